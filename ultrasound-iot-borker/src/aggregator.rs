@@ -0,0 +1,601 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+use uuid::Uuid;
+
+use crate::message::{DeviceMessage, ScanMessage};
+
+/// How far behind the newest sequence number a late message is still placed;
+/// older gaps stay counted as missing.
+const SEQUENCE_WINDOW: u64 = 1024;
+
+/// When a session's batch is complete. Whatever the policy, a batch is also
+/// emitted on a session-end marker, when its device starts another session, and
+/// after the idle timeout.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum AggregationPolicy {
+    /// A fixed number of samples.
+    Count { samples: usize },
+    /// All samples within a window starting at the first sample of the batch.
+    TimeWindow { window_secs: u64 },
+    /// Once the received payloads add up to `max_bytes`.
+    Bytes { max_bytes: usize },
+    /// Only on the device's session-end marker.
+    SessionEnd,
+}
+
+impl Default for AggregationPolicy {
+    fn default() -> Self {
+        AggregationPolicy::Count { samples: 5 }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SessionKey {
+    pub device: Uuid,
+    pub session: String,
+}
+
+#[derive(Debug)]
+struct Batch {
+    policy: AggregationPolicy,
+    /// The scan message under construction, without its values.
+    scan: ScanMessage,
+    /// Samples of each message, ordered by sequence number and then by arrival,
+    /// so that late messages take their place and unsequenced ones keep order.
+    messages: BTreeMap<(Option<u64>, usize), Vec<f32>>,
+    samples: usize,
+    bytes: usize,
+    started: Instant,
+    last_seen: Instant,
+}
+
+impl Batch {
+    fn new(
+        key: SessionKey,
+        policy: AggregationPolicy,
+        message: &DeviceMessage,
+        now: Instant,
+    ) -> Self {
+        Self {
+            policy,
+            scan: ScanMessage {
+                device: key.device,
+                session: key.session,
+                sample_type: message.sample_type.clone(),
+                unit: message.unit.clone(),
+                first_sequence: message.sequence,
+                first_timestamp_ms: message.timestamp_ms,
+                ..Default::default()
+            },
+            messages: BTreeMap::new(),
+            samples: 0,
+            bytes: 0,
+            started: now,
+            last_seen: now,
+        }
+    }
+
+    /// Whether samples of `message` can join the batch.
+    fn accepts(&self, message: &DeviceMessage) -> bool {
+        self.scan.sample_type == message.sample_type && self.scan.unit == message.unit
+    }
+
+    fn add(&mut self, message: &DeviceMessage, sequence: Sequence, bytes: usize, now: Instant) {
+        let values: Vec<f32> = message.values().collect();
+        self.samples += values.len();
+        let arrival = self.messages.len();
+        self.messages.insert((message.sequence, arrival), values);
+        let scan = &mut self.scan;
+        scan.schema_version = scan.schema_version.max(message.version);
+        // a late message may be older than the batch's first one
+        scan.first_sequence = earliest(scan.first_sequence, message.sequence);
+        scan.last_sequence = scan.last_sequence.max(message.sequence);
+        scan.first_timestamp_ms = earliest(scan.first_timestamp_ms, message.timestamp_ms);
+        scan.last_timestamp_ms = scan.last_timestamp_ms.max(message.timestamp_ms);
+        match sequence {
+            Sequence::Next { missing } => scan.missing += missing,
+            // only a gap counted in this batch can be taken back
+            Sequence::Late => scan.missing = scan.missing.saturating_sub(1),
+            Sequence::Duplicate => unreachable!("duplicates are dropped"),
+        }
+        self.bytes += bytes;
+        self.last_seen = now;
+    }
+
+    fn is_full(&self) -> bool {
+        match self.policy {
+            AggregationPolicy::Count { samples } => self.samples >= samples,
+            AggregationPolicy::Bytes { max_bytes } => self.bytes >= max_bytes,
+            AggregationPolicy::TimeWindow { .. } | AggregationPolicy::SessionEnd => false,
+        }
+    }
+
+    fn is_due(&self, now: Instant, idle_timeout: Duration) -> bool {
+        let window_closed = match self.policy {
+            AggregationPolicy::TimeWindow { window_secs } => {
+                now.duration_since(self.started) >= Duration::from_secs(window_secs)
+            }
+            _ => false,
+        };
+        window_closed || now.duration_since(self.last_seen) >= idle_timeout
+    }
+
+    /// The scan message with the samples in sequence order.
+    fn into_scan(self) -> ScanMessage {
+        ScanMessage {
+            values: self.messages.into_values().flatten().collect(),
+            ..self.scan
+        }
+    }
+}
+
+fn earliest(a: Option<u64>, b: Option<u64>) -> Option<u64> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        _ => a.or(b),
+    }
+}
+
+/// Where a message falls in its session's sequence.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Sequence {
+    /// The newest message, after `missing` skipped numbers.
+    Next {
+        missing: u64,
+    },
+    /// Fills an earlier gap.
+    Late,
+    Duplicate,
+}
+
+/// The current session of a device.
+#[derive(Debug)]
+struct SessionState {
+    session: String,
+    /// Highest sequence number received in the session.
+    last_sequence: Option<u64>,
+    /// Skipped sequence numbers within `SEQUENCE_WINDOW` of the highest.
+    gaps: BTreeSet<u64>,
+    /// Duplicates dropped since the session's last batch was emitted.
+    duplicates: u64,
+}
+
+impl SessionState {
+    fn new(session: String) -> Self {
+        Self {
+            session,
+            last_sequence: None,
+            gaps: BTreeSet::new(),
+            duplicates: 0,
+        }
+    }
+
+    fn sequence(&mut self, sequence: u64) -> Sequence {
+        let Some(last) = self.last_sequence else {
+            self.last_sequence = Some(sequence);
+            return Sequence::Next { missing: 0 };
+        };
+        if sequence <= last {
+            return if self.gaps.remove(&sequence) {
+                Sequence::Late
+            } else {
+                self.duplicates += 1;
+                Sequence::Duplicate
+            };
+        }
+        let oldest = sequence.saturating_sub(SEQUENCE_WINDOW);
+        self.gaps.extend((last + 1).max(oldest)..sequence);
+        self.gaps = self.gaps.split_off(&oldest);
+        self.last_sequence = Some(sequence);
+        Sequence::Next {
+            missing: sequence - last - 1,
+        }
+    }
+}
+
+/// Collects device samples into batches per (device, session), each completed
+/// according to the policy it was started with. Sequence numbers are checked
+/// per session: skipped numbers are reported as missing until they arrive late,
+/// repeated messages are dropped and counted.
+#[derive(Debug)]
+pub struct Aggregator {
+    idle_timeout: Duration,
+    batches: HashMap<SessionKey, Batch>,
+    /// Most recent session of each device.
+    sessions: HashMap<Uuid, SessionState>,
+}
+
+impl Aggregator {
+    pub fn new(idle_timeout: Duration) -> Self {
+        Self {
+            idle_timeout,
+            batches: HashMap::new(),
+            sessions: HashMap::new(),
+        }
+    }
+
+    /// Add a message of `bytes` payload bytes received at `now` and return the
+    /// batches it completed. `policy` applies when the message starts a batch.
+    pub fn push(
+        &mut self,
+        key: SessionKey,
+        policy: AggregationPolicy,
+        message: &DeviceMessage,
+        bytes: usize,
+        now: Instant,
+    ) -> Vec<ScanMessage> {
+        let mut ready = Vec::new();
+
+        // a new session of the device ends the previous one
+        if self
+            .sessions
+            .get(&key.device)
+            .is_none_or(|state| state.session != key.session)
+        {
+            if let Some(previous) = self.sessions.get(&key.device) {
+                let previous = SessionKey {
+                    device: key.device,
+                    session: previous.session.clone(),
+                };
+                ready.extend(self.take(previous));
+            }
+            self.sessions
+                .insert(key.device, SessionState::new(key.session.clone()));
+        }
+
+        let state = self.sessions.get_mut(&key.device).expect("inserted above");
+        let sequence = match message.sequence {
+            Some(sequence) => state.sequence(sequence),
+            None => Sequence::Next { missing: 0 },
+        };
+        if sequence == Sequence::Duplicate {
+            return ready;
+        }
+
+        // a batch that is already due goes out before the sample starts the
+        // next one, as does one of another sample type
+        if self
+            .batches
+            .get(&key)
+            .is_some_and(|batch| batch.is_due(now, self.idle_timeout) || !batch.accepts(message))
+        {
+            ready.extend(self.take(key.clone()));
+        }
+
+        let batch = self
+            .batches
+            .entry(key.clone())
+            .or_insert_with(|| Batch::new(key.clone(), policy, message, now));
+        batch.add(message, sequence, bytes, now);
+
+        if message.end {
+            ready.extend(self.take(key.clone()));
+            self.sessions.remove(&key.device);
+        } else if batch.is_full() {
+            ready.extend(self.take(key));
+        }
+        ready
+    }
+
+    /// Emit the batches whose time window closed or that were idle for longer
+    /// than the timeout. The sessions stay open, so that a session resuming
+    /// later is still checked for gaps.
+    pub fn flush_due(&mut self, now: Instant) -> Vec<ScanMessage> {
+        let due: Vec<SessionKey> = self
+            .batches
+            .iter()
+            .filter(|(_, batch)| batch.is_due(now, self.idle_timeout))
+            .map(|(key, _)| key.clone())
+            .collect();
+        due.into_iter().filter_map(|key| self.take(key)).collect()
+    }
+
+    /// Emit the batch of a session the device ended explicitly.
+    pub fn end_session(&mut self, key: &SessionKey) -> Option<ScanMessage> {
+        let scan = self.take(key.clone());
+        self.forget(key);
+        scan
+    }
+
+    /// Emit every partial batch, e.g. on shutdown.
+    pub fn flush_all(&mut self) -> Vec<ScanMessage> {
+        let keys: Vec<SessionKey> = self.batches.keys().cloned().collect();
+        let ready = keys.into_iter().filter_map(|key| self.take(key)).collect();
+        self.sessions.clear();
+        ready
+    }
+
+    /// Number of samples waiting in unfinished batches.
+    pub fn pending(&self) -> usize {
+        self.batches.values().map(|batch| batch.samples).sum()
+    }
+
+    fn forget(&mut self, key: &SessionKey) {
+        if self
+            .sessions
+            .get(&key.device)
+            .is_some_and(|state| state.session == key.session)
+        {
+            self.sessions.remove(&key.device);
+        }
+    }
+
+    fn take(&mut self, key: SessionKey) -> Option<ScanMessage> {
+        let mut scan = self.batches.remove(&key)?.into_scan();
+        if let Some(state) = self.sessions.get_mut(&key.device)
+            && state.session == key.session
+        {
+            scan.duplicates = std::mem::take(&mut state.duplicates);
+        }
+        Some(scan)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(30);
+    const COUNT: AggregationPolicy = AggregationPolicy::Count { samples: 3 };
+
+    fn key(device: u128, session: &str) -> SessionKey {
+        SessionKey {
+            device: Uuid::from_u128(device),
+            session: session.to_owned(),
+        }
+    }
+
+    fn sample(value: f32) -> DeviceMessage {
+        DeviceMessage {
+            value: Some(value),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn full_batches_are_emitted_per_session() {
+        let mut aggregator = Aggregator::new(TIMEOUT);
+        let now = Instant::now();
+
+        assert!(
+            aggregator
+                .push(key(1, "s1"), COUNT, &sample(1.0), 16, now)
+                .is_empty()
+        );
+        assert!(
+            aggregator
+                .push(key(2, "s9"), COUNT, &sample(10.0), 16, now)
+                .is_empty()
+        );
+        assert!(
+            aggregator
+                .push(key(1, "s1"), COUNT, &sample(2.0), 16, now)
+                .is_empty()
+        );
+        let ready = aggregator.push(key(1, "s1"), COUNT, &sample(3.0), 16, now);
+
+        assert_eq!(ready.len(), 1);
+        assert_eq!(ready[0].device, Uuid::from_u128(1));
+        assert_eq!(ready[0].session, "s1");
+        assert_eq!(ready[0].values, vec![1.0, 2.0, 3.0]);
+        assert_eq!(aggregator.pending(), 1);
+    }
+
+    #[test]
+    fn session_change_flushes_partial_batch() {
+        let mut aggregator = Aggregator::new(TIMEOUT);
+        let now = Instant::now();
+
+        aggregator.push(key(1, "s1"), COUNT, &sample(1.0), 16, now);
+        aggregator.push(key(1, "s1"), COUNT, &sample(2.0), 16, now);
+        let ready = aggregator.push(key(1, "s2"), COUNT, &sample(3.0), 16, now);
+
+        // the samples of the two sessions are never mixed
+        assert_eq!(ready.len(), 1);
+        assert_eq!(ready[0].session, "s1");
+        assert_eq!(ready[0].values, vec![1.0, 2.0]);
+        let rest = aggregator.flush_all();
+        assert_eq!(rest.len(), 1);
+        assert_eq!(rest[0].session, "s2");
+        assert_eq!(rest[0].values, vec![3.0]);
+    }
+
+    #[test]
+    fn idle_sessions_are_flushed() {
+        let mut aggregator = Aggregator::new(TIMEOUT);
+        let start = Instant::now();
+
+        aggregator.push(key(1, "s1"), COUNT, &sample(1.0), 16, start);
+        let later = start + Duration::from_secs(20);
+        aggregator.push(key(2, "s2"), COUNT, &sample(2.0), 16, later);
+
+        assert!(
+            aggregator
+                .flush_due(start + Duration::from_secs(29))
+                .is_empty()
+        );
+        let ready = aggregator.flush_due(start + Duration::from_secs(30));
+        assert_eq!(ready.len(), 1);
+        assert_eq!(ready[0].device, Uuid::from_u128(1));
+        assert_eq!(aggregator.pending(), 1);
+
+        // a flushed session that resumes starts a new batch
+        let resumed = start + Duration::from_secs(31);
+        assert!(
+            aggregator
+                .push(key(1, "s1"), COUNT, &sample(3.0), 16, resumed)
+                .is_empty()
+        );
+        assert_eq!(aggregator.pending(), 2);
+    }
+
+    #[test]
+    fn sequence_survives_idle_flush() {
+        let mut aggregator = Aggregator::new(TIMEOUT);
+        let start = Instant::now();
+
+        aggregator.push(key(1, "s1"), COUNT, &sequenced(1, 0, vec![1.0]), 16, start);
+        assert_eq!(aggregator.flush_due(start + TIMEOUT).len(), 1);
+
+        let resumed = start + TIMEOUT + Duration::from_secs(1);
+        for (sequence, value) in [(4, 4.0), (1, 1.0), (2, 2.0)] {
+            let message = sequenced(sequence, 0, vec![value]);
+            aggregator.push(key(1, "s1"), COUNT, &message, 16, resumed);
+        }
+        let ready = aggregator.flush_all();
+        assert_eq!(ready[0].values, vec![2.0, 4.0]);
+        assert_eq!(
+            (ready[0].first_sequence, ready[0].last_sequence),
+            (Some(2), Some(4))
+        );
+        assert_eq!((ready[0].missing, ready[0].duplicates), (1, 1));
+    }
+
+    #[test]
+    fn time_window_closes_after_first_sample() {
+        let mut aggregator = Aggregator::new(TIMEOUT);
+        let policy = AggregationPolicy::TimeWindow { window_secs: 10 };
+        let start = Instant::now();
+
+        for s in 0..5 {
+            let now = start + Duration::from_secs(2 * s);
+            assert!(
+                aggregator
+                    .push(key(1, "s1"), policy, &sample(s as f32), 16, now)
+                    .is_empty()
+            );
+        }
+        assert!(
+            aggregator
+                .flush_due(start + Duration::from_secs(9))
+                .is_empty()
+        );
+
+        let ready = aggregator.flush_due(start + Duration::from_secs(10));
+        assert_eq!(ready.len(), 1);
+        assert_eq!(ready[0].values.len(), 5);
+
+        // a sample arriving after the window closed starts the next batch
+        aggregator.push(key(1, "s1"), policy, &sample(0.0), 16, start);
+        let late = start + Duration::from_secs(12);
+        let ready = aggregator.push(key(1, "s1"), policy, &sample(1.0), 16, late);
+        assert_eq!(ready[0].values, vec![0.0]);
+        assert_eq!(aggregator.pending(), 1);
+    }
+
+    #[test]
+    fn byte_budget_and_session_end() {
+        let mut aggregator = Aggregator::new(TIMEOUT);
+        let bytes = AggregationPolicy::Bytes { max_bytes: 40 };
+        let now = Instant::now();
+
+        assert!(
+            aggregator
+                .push(key(1, "s1"), bytes, &sample(1.0), 16, now)
+                .is_empty()
+        );
+        assert!(
+            aggregator
+                .push(key(1, "s1"), bytes, &sample(2.0), 16, now)
+                .is_empty()
+        );
+        let ready = aggregator.push(key(1, "s1"), bytes, &sample(3.0), 16, now);
+        assert_eq!(ready[0].values.len(), 3);
+
+        let policy = AggregationPolicy::SessionEnd;
+        for s in 0..100 {
+            assert!(
+                aggregator
+                    .push(key(2, "s2"), policy, &sample(s as f32), 16, now)
+                    .is_empty()
+            );
+        }
+        let marker = DeviceMessage {
+            end: true,
+            ..sample(100.0)
+        };
+        let ready = aggregator.push(key(2, "s2"), policy, &marker, 16, now);
+        assert_eq!(ready.len(), 1);
+        assert_eq!(ready[0].values.len(), 101);
+        assert_eq!(aggregator.pending(), 0);
+
+        // an explicit end on the control topic works for any policy
+        aggregator.push(key(3, "s3"), policy, &sample(1.0), 16, now);
+        let ended = aggregator.end_session(&key(3, "s3")).unwrap();
+        assert_eq!(ended.values, vec![1.0]);
+        assert!(aggregator.end_session(&key(3, "s3")).is_none());
+    }
+
+    fn sequenced(sequence: u64, timestamp_ms: u64, samples: Vec<f32>) -> DeviceMessage {
+        DeviceMessage {
+            version: 2,
+            sequence: Some(sequence),
+            timestamp_ms: Some(timestamp_ms),
+            sample_type: Some("temperature".to_owned()),
+            unit: Some("celsius".to_owned()),
+            samples,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn gaps_and_duplicates_are_reported() {
+        let mut aggregator = Aggregator::new(TIMEOUT);
+        let policy = AggregationPolicy::Count { samples: 5 };
+        let now = Instant::now();
+
+        for (sequence, value) in [(1, 1.0), (2, 2.0), (2, 2.0), (5, 5.0), (3, 3.0), (3, 3.0)] {
+            let message = sequenced(sequence, 1000 * sequence, vec![value]);
+            assert!(
+                aggregator
+                    .push(key(1, "s1"), policy, &message, 16, now)
+                    .is_empty()
+            );
+        }
+        let ready = aggregator.push(
+            key(1, "s1"),
+            policy,
+            &sequenced(6, 6000, vec![6.0, 7.0]),
+            16,
+            now,
+        );
+
+        assert_eq!(ready.len(), 1);
+        let scan = &ready[0];
+        // 3 arrives late and takes its place, only 4 is lost
+        assert_eq!(scan.values, vec![1.0, 2.0, 3.0, 5.0, 6.0, 7.0]);
+        assert_eq!(
+            (scan.first_sequence, scan.last_sequence),
+            (Some(1), Some(6))
+        );
+        assert_eq!(
+            (scan.first_timestamp_ms, scan.last_timestamp_ms),
+            (Some(1000), Some(6000))
+        );
+        assert_eq!((scan.missing, scan.duplicates), (1, 2));
+        assert_eq!(scan.schema_version, 2);
+        assert_eq!(scan.unit.as_deref(), Some("celsius"));
+
+        // sequence numbers start over with the next session
+        aggregator.push(key(1, "s2"), policy, &sequenced(1, 0, vec![1.0]), 16, now);
+        assert_eq!(aggregator.pending(), 1);
+    }
+
+    #[test]
+    fn sample_types_are_not_mixed() {
+        let mut aggregator = Aggregator::new(TIMEOUT);
+        let now = Instant::now();
+
+        aggregator.push(key(1, "s1"), COUNT, &sample(1.0), 16, now);
+        let ready = aggregator.push(key(1, "s1"), COUNT, &sequenced(1, 0, vec![2.0]), 16, now);
+
+        assert_eq!(ready.len(), 1);
+        assert_eq!(ready[0].values, vec![1.0]);
+        assert_eq!(ready[0].schema_version, 1);
+        assert_eq!(ready[0].sample_type, None);
+        assert_eq!(aggregator.pending(), 1);
+    }
+}
@@ -0,0 +1,302 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+use crate::outbox::DeliveryError;
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum CommandKind {
+    /// Start sampling, in a new session unless `session` is given.
+    StartSession {
+        session: Option<String>,
+    },
+    StopSession,
+    SetSamplingInterval {
+        interval_ms: u32,
+    },
+    Reboot,
+}
+
+/// A command queued by the server for a device, published to it as JSON on
+/// `.../device/{device}/command`.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Command {
+    pub id: Uuid,
+    pub device: Uuid,
+    #[serde(flatten)]
+    pub kind: CommandKind,
+}
+
+pub fn command_topic(device: Uuid) -> String {
+    format!("rust_6_project/device/{}/command", device)
+}
+
+/// Published by a device on `.../command/ack` once it executed a command, or
+/// with `ok: false` and the reason if it could not.
+#[derive(Debug, Deserialize)]
+pub struct CommandAck {
+    pub id: Uuid,
+    pub ok: bool,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum CommandStatus {
+    /// Published to the device, `attempts` times so far.
+    Sent {
+        attempts: u32,
+    },
+    Acknowledged,
+    Rejected {
+        reason: String,
+    },
+    /// Never acknowledged, even after all attempts.
+    Expired,
+}
+
+/// Reported to the server on every status change of a command, at
+/// `{url}/status`.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct CommandStatusUpdate {
+    pub id: Uuid,
+    pub device: Uuid,
+    #[serde(flatten)]
+    pub status: CommandStatus,
+}
+
+#[derive(Debug)]
+struct InFlight {
+    command: Command,
+    attempts: u32,
+    sent_at: Instant,
+}
+
+/// Commands published but not yet acknowledged. A command is published again
+/// when its acknowledgement is overdue, and given up after `max_attempts`.
+#[derive(Debug)]
+pub struct CommandTracker {
+    ack_timeout: Duration,
+    max_attempts: u32,
+    in_flight: HashMap<Uuid, InFlight>,
+}
+
+impl CommandTracker {
+    pub fn new(ack_timeout: Duration, max_attempts: u32) -> Self {
+        Self {
+            ack_timeout,
+            max_attempts,
+            in_flight: HashMap::new(),
+        }
+    }
+
+    /// Track a command the caller just published.
+    pub fn sent(&mut self, command: Command, now: Instant) -> CommandStatusUpdate {
+        let update = CommandStatusUpdate {
+            id: command.id,
+            device: command.device,
+            status: CommandStatus::Sent { attempts: 1 },
+        };
+        self.in_flight.insert(
+            command.id,
+            InFlight {
+                command,
+                attempts: 1,
+                sent_at: now,
+            },
+        );
+        update
+    }
+
+    /// Settle a command acknowledged by `device`. Acks of unknown commands,
+    /// e.g. repeated ones, are ignored.
+    pub fn ack(&mut self, device: Uuid, ack: CommandAck) -> Option<CommandStatusUpdate> {
+        if self.in_flight.get(&ack.id)?.command.device != device {
+            return None;
+        }
+        self.in_flight.remove(&ack.id);
+        let status = if ack.ok {
+            CommandStatus::Acknowledged
+        } else {
+            CommandStatus::Rejected {
+                reason: ack.error.unwrap_or_default(),
+            }
+        };
+        Some(CommandStatusUpdate {
+            id: ack.id,
+            device,
+            status,
+        })
+    }
+
+    /// The overdue commands to publish again, and the status updates of those
+    /// and of the commands given up.
+    pub fn retry_due(&mut self, now: Instant) -> (Vec<Command>, Vec<CommandStatusUpdate>) {
+        let mut retries = Vec::new();
+        let mut updates = Vec::new();
+        self.in_flight.retain(|id, in_flight| {
+            if now.duration_since(in_flight.sent_at) < self.ack_timeout {
+                return true;
+            }
+            let device = in_flight.command.device;
+            if in_flight.attempts >= self.max_attempts {
+                updates.push(CommandStatusUpdate {
+                    id: *id,
+                    device,
+                    status: CommandStatus::Expired,
+                });
+                return false;
+            }
+            in_flight.attempts += 1;
+            in_flight.sent_at = now;
+            retries.push(in_flight.command.clone());
+            updates.push(CommandStatusUpdate {
+                id: *id,
+                device,
+                status: CommandStatus::Sent {
+                    attempts: in_flight.attempts,
+                },
+            });
+            true
+        });
+        (retries, updates)
+    }
+
+    pub fn len(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.in_flight.is_empty()
+    }
+}
+
+/// Fetch the commands the server queued since the last call; the server hands
+/// each command out once.
+pub async fn fetch_pending(
+    client: &reqwest::Client,
+    url: &str,
+) -> Result<Vec<Command>, DeliveryError> {
+    let response = client
+        .get(format!("{}/pending", url))
+        .send()
+        .await
+        .map_err(DeliveryError::Http)?;
+    if !response.status().is_success() {
+        return Err(DeliveryError::Status(response.status()));
+    }
+    response.json().await.map_err(DeliveryError::Http)
+}
+
+/// Poll the server at `url` for commands every `interval`, forever, and pass
+/// them on to `commands`.
+pub async fn poll(
+    client: reqwest::Client,
+    url: String,
+    interval: Duration,
+    commands: mpsc::Sender<Command>,
+) {
+    let mut tick = tokio::time::interval(interval);
+    loop {
+        tick.tick().await;
+        match fetch_pending(&client, &url).await {
+            Ok(pending) => {
+                for command in pending {
+                    debug!("Command from server: {:?}", command);
+                    if commands.send(command).await.is_err() {
+                        return;
+                    }
+                }
+            }
+            Err(e) => warn!("Failed to fetch commands: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    fn command(id: u128, kind: CommandKind) -> Command {
+        Command {
+            id: Uuid::from_u128(id),
+            device: Uuid::from_u128(100),
+            kind,
+        }
+    }
+
+    #[test]
+    fn commands_use_a_flat_wire_format() {
+        let command = command(1, CommandKind::SetSamplingInterval { interval_ms: 500 });
+        let json = serde_json::to_value(&command).unwrap();
+        assert_eq!(json["type"], "set_sampling_interval");
+        assert_eq!(json["interval_ms"], 500);
+        assert_eq!(serde_json::from_value::<Command>(json).unwrap(), command);
+
+        let update = CommandStatusUpdate {
+            id: command.id,
+            device: command.device,
+            status: CommandStatus::Sent { attempts: 2 },
+        };
+        let json = serde_json::to_value(&update).unwrap();
+        assert_eq!(
+            (&json["state"], &json["attempts"]),
+            (&"sent".into(), &2.into())
+        );
+    }
+
+    #[test]
+    fn acks_settle_commands() {
+        let mut tracker = CommandTracker::new(TIMEOUT, 3);
+        let now = Instant::now();
+        let device = Uuid::from_u128(100);
+        tracker.sent(command(1, CommandKind::Reboot), now);
+        tracker.sent(command(2, CommandKind::StopSession), now);
+
+        let ack = |id, ok| CommandAck {
+            id: Uuid::from_u128(id),
+            ok,
+            error: (!ok).then(|| "busy".to_owned()),
+        };
+        // only the addressed device can acknowledge
+        assert!(tracker.ack(Uuid::from_u128(7), ack(1, true)).is_none());
+        assert_eq!(
+            tracker.ack(device, ack(1, true)).unwrap().status,
+            CommandStatus::Acknowledged
+        );
+        assert!(tracker.ack(device, ack(1, true)).is_none());
+        assert_eq!(
+            tracker.ack(device, ack(2, false)).unwrap().status,
+            CommandStatus::Rejected {
+                reason: "busy".to_owned()
+            }
+        );
+        assert!(tracker.is_empty());
+    }
+
+    #[test]
+    fn unacknowledged_commands_are_retried_then_expired() {
+        let mut tracker = CommandTracker::new(TIMEOUT, 2);
+        let start = Instant::now();
+        tracker.sent(command(1, CommandKind::Reboot), start);
+
+        let (retries, updates) = tracker.retry_due(start + Duration::from_secs(9));
+        assert!(retries.is_empty() && updates.is_empty());
+
+        let (retries, updates) = tracker.retry_due(start + TIMEOUT);
+        assert_eq!(retries, vec![command(1, CommandKind::Reboot)]);
+        assert_eq!(updates[0].status, CommandStatus::Sent { attempts: 2 });
+
+        let (retries, updates) = tracker.retry_due(start + 2 * TIMEOUT);
+        assert!(retries.is_empty());
+        assert_eq!(updates[0].status, CommandStatus::Expired);
+        assert!(tracker.is_empty());
+    }
+}
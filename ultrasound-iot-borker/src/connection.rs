@@ -0,0 +1,247 @@
+use std::fmt::Display;
+use std::time::{Duration, Instant};
+
+use rumqttc::v5::mqttbytes::QoS;
+use rumqttc::v5::mqttbytes::v5::{Filter, Publish};
+use rumqttc::v5::{AsyncClient, ConnectionError, Event, EventLoop, Incoming, MqttOptions};
+use tracing::{debug, info, warn};
+
+use crate::backoff::Backoff;
+
+/// Connection history of the MQTT client.
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionStats {
+    /// Successful connections, the first one included.
+    pub connects: u32,
+    pub disconnects: u32,
+    /// Failed connection attempts since the last successful one.
+    pub failed_attempts: u32,
+    /// Total time spent disconnected after the first connection.
+    pub downtime: Duration,
+    pub last_error: Option<String>,
+    disconnected_at: Option<Instant>,
+    connected: bool,
+}
+
+impl ConnectionStats {
+    fn connected(&mut self, now: Instant) {
+        if let Some(since) = self.disconnected_at.take() {
+            self.downtime += now.duration_since(since);
+        }
+        self.connects += 1;
+        self.failed_attempts = 0;
+        self.connected = true;
+    }
+
+    fn failed(&mut self, error: &ConnectionError, now: Instant) {
+        if self.connected {
+            self.disconnects += 1;
+            self.disconnected_at = Some(now);
+            self.connected = false;
+        } else {
+            self.failed_attempts += 1;
+        }
+        self.last_error = Some(error.to_string());
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connected
+    }
+}
+
+impl Display for ConnectionStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} connects, {} disconnects, {:.1?} down",
+            self.connects, self.disconnects, self.downtime
+        )
+    }
+}
+
+/// MQTT v5 client that treats disconnects as transient: it reconnects with
+/// backoff and restores its subscriptions whenever the broker did not keep the
+/// session.
+pub struct Connection {
+    client: AsyncClient,
+    eventloop: EventLoop,
+    subscriptions: Vec<(String, QoS)>,
+    backoff: Backoff,
+    /// Earliest time of the next connection attempt after a failure.
+    retry_at: Option<Instant>,
+    stats: ConnectionStats,
+}
+
+impl Connection {
+    pub fn new(options: MqttOptions, subscriptions: Vec<(String, QoS)>, backoff: Backoff) -> Self {
+        let (client, eventloop) = AsyncClient::new(options, 10);
+        Self {
+            client,
+            eventloop,
+            subscriptions,
+            backoff,
+            retry_at: None,
+            stats: ConnectionStats::default(),
+        }
+    }
+
+    pub fn client(&self) -> &AsyncClient {
+        &self.client
+    }
+
+    pub fn stats(&self) -> &ConnectionStats {
+        &self.stats
+    }
+
+    /// Drive the connection until the next incoming publish.
+    /// Cancel-safe, so it can be used in `tokio::select!`.
+    pub async fn next_publish(&mut self) -> Publish {
+        loop {
+            if let Some(retry_at) = self.retry_at {
+                tokio::time::sleep_until(retry_at.into()).await;
+                self.retry_at = None;
+            }
+
+            match self.eventloop.poll().await {
+                Ok(Event::Incoming(Incoming::Publish(publish))) => return publish,
+                Ok(Event::Incoming(Incoming::ConnAck(connack))) => {
+                    let reconnect = self.stats.connects > 0;
+                    self.stats.connected(Instant::now());
+                    self.backoff.reset();
+                    if reconnect {
+                        info!("Reconnected to MQTT broker ({})", self.stats);
+                    }
+                    if !connack.session_present {
+                        self.subscribe();
+                    }
+                }
+                Ok(event) => debug!("Received = {:?}", event),
+                Err(e) => {
+                    self.stats.failed(&e, Instant::now());
+                    let delay = self.backoff.next_delay();
+                    warn!(
+                        "MQTT connection error: {} ({}); retrying in {:?}",
+                        e, self.stats, delay
+                    );
+                    self.retry_at = Some(Instant::now() + delay);
+                }
+            }
+        }
+    }
+
+    fn subscribe(&self) {
+        for (topic, qos) in &self.subscriptions {
+            // the client's own publishes, e.g. commands, are not delivered back
+            let filter = Filter {
+                nolocal: true,
+                ..Filter::new(topic.clone(), *qos)
+            };
+            if let Err(e) = self.client.try_subscribe_many([filter]) {
+                warn!("Failed to subscribe to {}: {}", topic, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// Read one MQTT packet, returning its type nibble and body.
+    async fn read_packet(stream: &mut TcpStream) -> (u8, Vec<u8>) {
+        let header = stream.read_u8().await.unwrap();
+        let (mut len, mut shift) = (0usize, 0);
+        loop {
+            let byte = stream.read_u8().await.unwrap();
+            len |= ((byte & 0x7f) as usize) << shift;
+            shift += 7;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+        let mut body = vec![0; len];
+        stream.read_exact(&mut body).await.unwrap();
+        (header >> 4, body)
+    }
+
+    /// Accept a client and answer its CONNECT; returns the clean-session flag.
+    async fn accept(listener: &TcpListener, session_present: bool) -> (TcpStream, bool) {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let (kind, body) = read_packet(&mut stream).await;
+        assert_eq!(kind, 1, "expected CONNECT");
+        // protocol name (6 bytes) and level, then the connect flags
+        let clean_session = body[7] & 0x02 != 0;
+        stream
+            .write_all(&[0x20, 0x03, session_present as u8, 0x00, 0x00])
+            .await
+            .unwrap();
+        (stream, clean_session)
+    }
+
+    async fn expect_subscribe(stream: &mut TcpStream) {
+        let (kind, body) = read_packet(stream).await;
+        assert_eq!(kind, 8, "expected SUBSCRIBE");
+        stream
+            .write_all(&[0x90, 0x04, body[0], body[1], 0x00, 0x01])
+            .await
+            .unwrap();
+    }
+
+    async fn publish(stream: &mut TcpStream, topic: &str) {
+        let mut packet = vec![0x30, (2 + topic.len() + 2) as u8];
+        packet.extend_from_slice(&(topic.len() as u16).to_be_bytes());
+        packet.extend_from_slice(topic.as_bytes());
+        // no properties
+        packet.push(0x00);
+        packet.push(b'1');
+        stream.write_all(&packet).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn reconnects_and_resubscribes() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let broker = tokio::spawn(async move {
+            let (mut stream, clean_session) = accept(&listener, false).await;
+            assert!(!clean_session);
+            expect_subscribe(&mut stream).await;
+            publish(&mut stream, "data/a").await;
+            drop(stream);
+
+            // the broker lost the session, so the client subscribes again
+            let (mut stream, _) = accept(&listener, false).await;
+            expect_subscribe(&mut stream).await;
+            publish(&mut stream, "data/b").await;
+            stream
+        });
+
+        let mut options = MqttOptions::new("test_hub", "127.0.0.1", port);
+        options.set_clean_start(false);
+        let backoff = Backoff::new(Duration::from_millis(10), Duration::from_millis(100));
+        let mut connection = Connection::new(
+            options,
+            vec![("data/+".to_owned(), QoS::AtLeastOnce)],
+            backoff,
+        );
+
+        let first = tokio::time::timeout(Duration::from_secs(5), connection.next_publish())
+            .await
+            .unwrap();
+        let second = tokio::time::timeout(Duration::from_secs(5), connection.next_publish())
+            .await
+            .unwrap();
+        let _stream = broker.await.unwrap();
+
+        assert_eq!(
+            (&first.topic[..], &second.topic[..]),
+            (&b"data/a"[..], &b"data/b"[..])
+        );
+        let stats = connection.stats();
+        assert_eq!((stats.connects, stats.disconnects), (2, 1));
+        assert!(stats.is_connected());
+        assert!(stats.last_error.is_some());
+    }
+}
@@ -0,0 +1,124 @@
+use std::thread::JoinHandle;
+
+use crate::settings::EmbeddedBroker;
+
+/// Host an MQTT v5 broker in-process on `settings.bind`:`settings.port`, so that
+/// devices and the aggregator need no external broker. The broker runs on its
+/// own thread for the lifetime of the process.
+#[cfg(feature = "embedded-broker")]
+pub fn start(settings: &EmbeddedBroker) -> Result<JoinHandle<()>, Box<dyn std::error::Error>> {
+    use config::{Config, File, FileFormat};
+    use tracing::{error, info};
+
+    // same layout as rumqttd's own configuration file
+    let toml = format!(
+        r#"
+        id = 0
+
+        [router]
+        max_connections = {max_connections}
+        max_outgoing_packet_count = 200
+        max_segment_size = 104857600
+        max_segment_count = 10
+
+        [v5.1]
+        name = "v5-1"
+        listen = "{bind}:{port}"
+        next_connection_delay_ms = 1
+
+        [v5.1.connections]
+        connection_timeout_ms = 60000
+        max_payload_size = {max_payload_size}
+        max_inflight_count = 100
+        dynamic_filters = true
+        "#,
+        max_connections = settings.max_connections,
+        bind = settings.bind,
+        port = settings.port,
+        max_payload_size = settings.max_payload_size,
+    );
+    let config: rumqttd::Config = Config::builder()
+        .add_source(File::from_str(&toml, FileFormat::Toml))
+        .build()?
+        .try_deserialize()?;
+
+    let mut broker = rumqttd::Broker::new(config);
+    info!(
+        "Starting embedded MQTT broker on {}:{}",
+        settings.bind, settings.port
+    );
+    let handle = std::thread::Builder::new()
+        .name("mqtt-broker".to_owned())
+        .spawn(move || {
+            if let Err(e) = broker.start() {
+                error!("Embedded MQTT broker stopped: {:?}", e);
+            }
+        })?;
+    Ok(handle)
+}
+
+#[cfg(not(feature = "embedded-broker"))]
+pub fn start(_settings: &EmbeddedBroker) -> Result<JoinHandle<()>, Box<dyn std::error::Error>> {
+    Err("the embedded broker needs the `embedded-broker` feature".into())
+}
+
+#[cfg(all(test, feature = "embedded-broker"))]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::time::Duration;
+
+    use rumqttc::v5::mqttbytes::QoS;
+    use rumqttc::v5::{AsyncClient, MqttOptions};
+
+    use crate::backoff::Backoff;
+    use crate::connection::Connection;
+
+    #[tokio::test]
+    async fn aggregator_receives_device_messages() {
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let settings = EmbeddedBroker {
+            port,
+            bind: "127.0.0.1".to_owned(),
+            ..Default::default()
+        };
+        start(&settings).unwrap();
+
+        let topic = "rust_6_project/device/+/session/+";
+        let mut connection = Connection::new(
+            MqttOptions::new("test_hub", "127.0.0.1", port),
+            vec![(topic.to_owned(), QoS::AtLeastOnce)],
+            Backoff::new(Duration::from_millis(50), Duration::from_millis(500)),
+        );
+
+        // the device connects once the broker accepted the hub's subscription
+        let (device, mut eventloop) =
+            AsyncClient::new(MqttOptions::new("test_device", "127.0.0.1", port), 10);
+        tokio::spawn(async move { while eventloop.poll().await.is_ok() {} });
+        let publisher = tokio::spawn(async move {
+            loop {
+                let _ = device
+                    .publish(
+                        "rust_6_project/device/d1/session/s1",
+                        QoS::AtLeastOnce,
+                        false,
+                        r#"{ "value": 1.0 }"#,
+                    )
+                    .await;
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        });
+
+        let publish = tokio::time::timeout(Duration::from_secs(10), connection.next_publish())
+            .await
+            .unwrap();
+        publisher.abort();
+
+        assert_eq!(&publish.topic[..], b"rust_6_project/device/d1/session/s1");
+        assert_eq!(connection.stats().connects, 1);
+    }
+}
@@ -0,0 +1,106 @@
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+/// Payload encoding of device messages and of the scan messages posted to the
+/// server.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Encoding {
+    #[default]
+    Json,
+    Cbor,
+    #[serde(alias = "msgpack")]
+    MessagePack,
+}
+
+impl Encoding {
+    /// Encoding named by the last level of a data topic, e.g. `.../cbor`.
+    pub fn from_suffix(suffix: &str) -> Option<Self> {
+        match suffix {
+            "json" => Some(Encoding::Json),
+            "cbor" => Some(Encoding::Cbor),
+            "msgpack" => Some(Encoding::MessagePack),
+            _ => None,
+        }
+    }
+
+    /// Encoding of an MQTT v5 content type or HTTP media type.
+    pub fn from_content_type(content_type: &str) -> Option<Self> {
+        let media_type = content_type.split(';').next().unwrap_or_default().trim();
+        match media_type.to_ascii_lowercase().as_str() {
+            "application/json" => Some(Encoding::Json),
+            "application/cbor" => Some(Encoding::Cbor),
+            "application/msgpack" | "application/x-msgpack" | "application/vnd.msgpack" => {
+                Some(Encoding::MessagePack)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Encoding::Json => "application/json",
+            Encoding::Cbor => "application/cbor",
+            Encoding::MessagePack => "application/msgpack",
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(self, payload: &[u8]) -> Result<T, String> {
+        match self {
+            Encoding::Json => serde_json::from_slice(payload).map_err(|e| e.to_string()),
+            Encoding::Cbor => ciborium::from_reader(payload).map_err(|e| e.to_string()),
+            Encoding::MessagePack => rmp_serde::from_slice(payload).map_err(|e| e.to_string()),
+        }
+    }
+
+    pub fn encode<T: Serialize>(self, value: &T) -> Result<Vec<u8>, String> {
+        match self {
+            Encoding::Json => serde_json::to_vec(value).map_err(|e| e.to_string()),
+            Encoding::Cbor => {
+                let mut buffer = Vec::new();
+                ciborium::into_writer(value, &mut buffer).map_err(|e| e.to_string())?;
+                Ok(buffer)
+            }
+            // maps rather than arrays, so that fields are matched by name
+            Encoding::MessagePack => rmp_serde::to_vec_named(value).map_err(|e| e.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::ScanMessage;
+    use uuid::Uuid;
+
+    #[test]
+    fn scan_messages_round_trip() {
+        let scan = ScanMessage {
+            device: Uuid::from_u128(1),
+            session: "s1".to_owned(),
+            values: vec![20.5, 20.75],
+            schema_version: 2,
+            unit: Some("celsius".to_owned()),
+            ..Default::default()
+        };
+        for encoding in [Encoding::Json, Encoding::Cbor, Encoding::MessagePack] {
+            let bytes = encoding.encode(&scan).unwrap();
+            assert_eq!(encoding.decode::<ScanMessage>(&bytes).unwrap(), scan);
+        }
+    }
+
+    #[test]
+    fn encodings_are_named_by_suffix_and_content_type() {
+        assert_eq!(Encoding::from_suffix("cbor"), Some(Encoding::Cbor));
+        assert_eq!(Encoding::from_suffix("control"), None);
+        assert_eq!(
+            Encoding::from_content_type("application/JSON; charset=utf-8"),
+            Some(Encoding::Json)
+        );
+        assert_eq!(
+            Encoding::from_content_type("application/x-msgpack"),
+            Some(Encoding::MessagePack)
+        );
+        assert_eq!(Encoding::from_content_type("text/plain"), None);
+    }
+}
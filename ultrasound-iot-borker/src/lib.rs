@@ -1,2 +1,13 @@
+pub mod aggregator;
+pub mod backoff;
+pub mod command;
+pub mod connection;
+pub mod embedded;
+pub mod encoding;
 pub mod message;
+pub mod outbox;
+pub mod presence;
+pub mod report;
+pub mod router;
 pub mod settings;
+pub mod topic;
@@ -1,16 +1,79 @@
-use std::collections::HashMap;
-use std::time::Duration;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 
-use reqwest::header::CONTENT_TYPE;
-use tracing::debug;
-use uuid::Uuid;
+use tokio::sync::{Notify, mpsc};
+use tracing::{debug, error, info, warn};
 
-use rumqttc::{AsyncClient, Event, Incoming, MqttOptions, QoS};
+use rumqttc::v5::MqttOptions;
+use rumqttc::v5::mqttbytes::QoS;
+use rumqttc::v5::mqttbytes::v5::ConnectProperties;
 
+use ultrasound_iot_borker::aggregator::Aggregator;
+use ultrasound_iot_borker::command::{self, Command, CommandTracker, command_topic};
+use ultrasound_iot_borker::connection::Connection;
+use ultrasound_iot_borker::embedded;
 use ultrasound_iot_borker::message::*;
+use ultrasound_iot_borker::outbox::{Outbox, deliver};
+use ultrasound_iot_borker::presence::{DeviceHealth, DeviceRegistry};
+use ultrasound_iot_borker::report;
+use ultrasound_iot_borker::router::{DEVICE_TOPICS, Routed, Router};
 use ultrasound_iot_borker::settings::Settings;
 
-const SAMPLE_SIZE: usize = 5;
+/// How often idle sessions and closed time windows are flushed.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Persist completed batches to the outbox and wake the delivery task. A batch
+/// that cannot be written, e.g. on a full disk, is lost but does not stop the
+/// broker.
+fn enqueue(outbox: &Mutex<Outbox>, notify: &Notify, scan_messages: Vec<ScanMessage>) {
+    if scan_messages.is_empty() {
+        return;
+    }
+    let mut outbox = outbox.lock().unwrap();
+    for scan_message in scan_messages {
+        if scan_message.missing > 0 || scan_message.duplicates > 0 {
+            warn!(
+                "Session {} of {}: {} messages missing, {} duplicates dropped",
+                scan_message.session,
+                scan_message.device,
+                scan_message.missing,
+                scan_message.duplicates
+            );
+        }
+        debug!("Queued {:?}", scan_message);
+        let session = scan_message.session.clone();
+        if let Err(e) = outbox.push(scan_message) {
+            error!("Failed to queue a batch of session {}: {}", session, e);
+        }
+    }
+    notify.notify_one();
+}
+
+/// Publish a command to its device; the tracker retries it if the publish is lost.
+fn publish_command(connection: &Connection, command: &Command) {
+    let payload = serde_json::to_vec(command).expect("Failed to serialize command");
+    if let Err(e) = connection.client().try_publish(
+        command_topic(command.device),
+        QoS::AtLeastOnce,
+        false,
+        payload,
+    ) {
+        warn!("Failed to publish command {}: {}", command.id, e);
+    }
+}
+
+/// Pass a report on to its reporter task, if the server takes such reports.
+fn send_report<T>(reports: Option<&mpsc::UnboundedSender<T>>, report: T) {
+    if let Some(reports) = reports {
+        let _ = reports.send(report);
+    }
+}
+
+/// Log a device going online or offline and pass it on to the server.
+fn presence_changed(health: DeviceHealth, reports: Option<&mpsc::UnboundedSender<DeviceHealth>>) {
+    info!("Device {} is {:?}", health.device, health.state);
+    send_report(reports, health);
+}
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -21,77 +84,190 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let settings = Settings::new().expect("Failed to initialize settings");
     debug!("{:?}", settings);
 
-    let mut mqttoptions = MqttOptions::new("6_2_hub", settings.broker.host, settings.broker.port);
-    mqttoptions.set_keep_alive(Duration::from_secs(5));
-
-    let (client, mut connection) = AsyncClient::new(mqttoptions, 10);
-    client
-        .subscribe("rust_6_project/device/+/session/+", QoS::AtMostOnce)
-        .await
-        .unwrap();
+    let broker = &settings.broker;
+    let (host, port) = match &broker.embedded {
+        Some(embedded) => {
+            embedded::start(embedded)?;
+            ("127.0.0.1", embedded.port)
+        }
+        None => (broker.host.as_str(), broker.port),
+    };
+    let mut mqttoptions = MqttOptions::new(&broker.client_id, host, port);
+    mqttoptions.set_keep_alive(Duration::from_secs(broker.keep_alive_secs));
+    mqttoptions.set_clean_start(broker.clean_session);
+    if !broker.clean_session {
+        let mut properties = ConnectProperties::new();
+        properties.session_expiry_interval = Some(broker.session_expiry_secs);
+        mqttoptions.set_connect_properties(properties);
+    }
 
-    let client = reqwest::Client::new();
-    let url = settings.server.url.clone();
+    let router = Router::default();
+    let subscriptions = vec![(DEVICE_TOPICS.to_owned(), QoS::AtLeastOnce)];
+    let mut connection = Connection::new(mqttoptions, subscriptions, broker.reconnect_backoff());
 
-    let mut device_progress: HashMap<Uuid, Vec<f32>> = HashMap::new();
+    let outbox = Arc::new(Mutex::new(Outbox::open(&settings.delivery.outbox_path)?));
+    let notify = Arc::new(Notify::new());
+    let delivery = tokio::spawn({
+        let (outbox, notify) = (outbox.clone(), notify.clone());
+        let (url, encoding) = (settings.server.url.clone(), settings.server.encoding);
+        let backoff = settings.delivery.backoff();
+        async move {
+            let client = reqwest::Client::new();
+            if let Err(e) = deliver(outbox, notify, client, url, encoding, backoff).await {
+                error!("Outbox delivery stopped: {}", e);
+            }
+        }
+    });
 
-    while let Ok(notification) = connection.poll().await {
-        debug!("Received = {:?}", notification);
+    let (command_tx, mut command_rx) = mpsc::channel(16);
+    let mut status_tx = None;
+    let mut tasks = Vec::new();
+    if let Some(commands) = &settings.commands {
+        let (tx, status_rx) = mpsc::unbounded_channel();
+        status_tx = Some(tx);
+        let client = reqwest::Client::new();
+        tasks.push(tokio::spawn(command::poll(
+            client.clone(),
+            commands.url.clone(),
+            commands.poll_interval(),
+            command_tx.clone(),
+        )));
+        tasks.push(tokio::spawn(report::report(
+            client,
+            format!("{}/status", commands.url),
+            settings.delivery.backoff(),
+            status_rx,
+        )));
+    }
+    let command_settings = settings.commands.unwrap_or_default();
+    let mut commands = CommandTracker::new(
+        command_settings.ack_timeout(),
+        command_settings.max_attempts,
+    );
 
-        let publish = match notification {
-            Event::Incoming(Incoming::Publish(publish)) => publish,
-            _ => continue,
-        };
+    let mut presence_tx = None;
+    if let Some(url) = &settings.presence.url {
+        let (tx, presence_rx) = mpsc::unbounded_channel();
+        presence_tx = Some(tx);
+        tasks.push(tokio::spawn(report::report(
+            reqwest::Client::new(),
+            url.clone(),
+            settings.delivery.backoff(),
+            presence_rx,
+        )));
+    }
+    let mut registry = DeviceRegistry::new(settings.presence.offline_after());
 
-        // Extract device and session
-        let topic_parts: Vec<&str> = publish.topic.split('/').collect();
-        debug!("topic_parts = {:?}", topic_parts);
-        if topic_parts.len() < 5 {
-            continue;
-        }
+    let mut aggregator = Aggregator::new(settings.aggregation.idle_timeout());
+    let mut flush_check = tokio::time::interval(FLUSH_INTERVAL);
 
-        let device = match Uuid::parse_str(topic_parts[2]) {
-            Ok(device) => device,
-            Err(_) => {
+    loop {
+        let publish = tokio::select! {
+            publish = connection.next_publish() => publish,
+            Some(command) = command_rx.recv() => {
+                publish_command(&connection, &command);
+                send_report(status_tx.as_ref(), commands.sent(command, Instant::now()));
                 continue;
             }
+            _ = flush_check.tick() => {
+                let now = Instant::now();
+                enqueue(&outbox, &notify, aggregator.flush_due(now));
+                let (retries, updates) = commands.retry_due(now);
+                for command in &retries {
+                    publish_command(&connection, command);
+                }
+                for update in updates {
+                    send_report(status_tx.as_ref(), update);
+                }
+                for health in registry.expire(SystemTime::now()) {
+                    presence_changed(health, presence_tx.as_ref());
+                }
+                continue;
+            }
+            _ = tokio::signal::ctrl_c() => break,
         };
+        debug!("Received = {:?}", publish);
 
-        let session = topic_parts[4].to_owned();
-
-        let msg = match serde_json::from_slice::<DeviceMessage>(&publish.payload) {
-            Ok(msg) => msg,
-            Err(_) => {
+        let topic = String::from_utf8_lossy(&publish.topic);
+        let content_type = publish
+            .properties
+            .as_ref()
+            .and_then(|properties| properties.content_type.as_deref());
+        let routed = match router.route(&topic, &publish.payload, content_type) {
+            Ok(routed) => routed,
+            Err(rejection) => {
+                warn!("Rejected {}: {}", rejection.topic, rejection.reason);
+                let dead_letter = serde_json::to_vec(&rejection.dead_letter(&publish.payload))?;
+                if let Err(e) = connection.client().try_publish(
+                    &broker.dead_letter_topic,
+                    QoS::AtLeastOnce,
+                    false,
+                    dead_letter,
+                ) {
+                    warn!("Failed to publish dead letter: {}", e);
+                }
                 continue;
             }
         };
 
-        device_progress
-            .entry(device)
-            .or_insert(Vec::with_capacity(SAMPLE_SIZE))
-            .push(msg.value);
+        let now = SystemTime::now();
+        let presence = match &routed {
+            Routed::Data { key, .. } | Routed::SessionControl { key, .. } => {
+                registry.seen(key.device, now)
+            }
+            Routed::Status { device, status } => registry.status(*device, status, now),
+            Routed::Heartbeat { device, heartbeat } => registry.heartbeat(*device, heartbeat, now),
+            Routed::CommandAck { device, .. } => registry.seen(*device, now),
+        };
+        if let Some(health) = presence {
+            presence_changed(health, presence_tx.as_ref());
+        }
 
-        debug!("{:?}", device_progress);
-        if device_progress.get(&device).unwrap().len() < SAMPLE_SIZE {
-            continue;
+        match routed {
+            Routed::Data { key, message } => {
+                let policy = settings.aggregation.policy_for(&topic);
+                let ready =
+                    aggregator.push(key, policy, &message, publish.payload.len(), Instant::now());
+                enqueue(&outbox, &notify, ready);
+                debug!("{} samples pending", aggregator.pending());
+            }
+            Routed::SessionControl { key, control } => {
+                info!(
+                    "Session {:?} of {}: {:?}",
+                    key.session, key.device, control.action
+                );
+                if control.action == SessionAction::End {
+                    enqueue(
+                        &outbox,
+                        &notify,
+                        aggregator.end_session(&key).into_iter().collect(),
+                    );
+                }
+            }
+            Routed::Status { .. } => {}
+            Routed::Heartbeat { device, heartbeat } => {
+                debug!("Heartbeat of {}: {:?}", device, heartbeat)
+            }
+            Routed::CommandAck { device, ack } => {
+                if let Some(update) = commands.ack(device, ack) {
+                    info!("Command {} of {}: {:?}", update.id, device, update.status);
+                    send_report(status_tx.as_ref(), update);
+                }
+            }
         }
-        let values = device_progress.remove(&device).unwrap();
+    }
 
-        let scan_message = ScanMessage {
-            device,
-            session,
-            values,
-        };
-        debug!("{:?}", scan_message);
-
-        let json_scan_message =
-            serde_json::to_string(&scan_message).expect("Failed to serialize scan message");
-        let _ = client
-            .post(&url)
-            .header(CONTENT_TYPE, "application/json")
-            .body(json_scan_message)
-            .send()
-            .await?;
+    info!(
+        "Shutting down ({}, {} devices online)",
+        connection.stats(),
+        registry.online()
+    );
+
+    // undelivered batches stay in the outbox for the next start
+    enqueue(&outbox, &notify, aggregator.flush_all());
+    delivery.abort();
+    for task in tasks {
+        task.abort();
     }
 
     Ok(())
@@ -1,14 +1,128 @@
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-#[derive(Debug, Deserialize)]
+/// Newest `DeviceMessage` schema version understood by the broker.
+pub const SCHEMA_VERSION: u32 = 2;
+
+/// A device sample message. Version 1 is the original `{ "value": ... }`;
+/// version 2 adds sequencing, timing and units, and may carry several samples.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
 pub struct DeviceMessage {
-    pub value: f32,
+    pub version: u32,
+    /// Increases by one with every message of the device.
+    pub sequence: Option<u64>,
+    /// Device clock in milliseconds when the first sample was taken.
+    pub timestamp_ms: Option<u64>,
+    /// What is sampled, e.g. `temperature`.
+    pub sample_type: Option<String>,
+    pub unit: Option<String>,
+    pub value: Option<f32>,
+    pub samples: Vec<f32>,
+    /// Marks the last message of the session.
+    pub end: bool,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+impl Default for DeviceMessage {
+    fn default() -> Self {
+        Self {
+            version: 1,
+            sequence: None,
+            timestamp_ms: None,
+            sample_type: None,
+            unit: None,
+            value: None,
+            samples: Vec::new(),
+            end: false,
+        }
+    }
+}
+
+impl DeviceMessage {
+    /// `value` followed by `samples`.
+    pub fn values(&self) -> impl Iterator<Item = f32> + '_ {
+        self.value.into_iter().chain(self.samples.iter().copied())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionAction {
+    Start,
+    End,
+}
+
+/// Published by a device on `.../session/{session}/control`.
+#[derive(Debug, Deserialize)]
+pub struct SessionControl {
+    pub action: SessionAction,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeviceState {
+    Online,
+    Offline,
+}
+
+/// Published by a device on `.../status` when it connects, with
+/// `"state": "offline"` as its last will, and optionally its health.
+#[derive(Debug, Deserialize)]
+pub struct DeviceStatus {
+    pub state: DeviceState,
+    #[serde(flatten)]
+    pub health: Heartbeat,
+}
+
+/// Published periodically by a device on `.../heartbeat`.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Heartbeat {
+    /// Battery charge in percent.
+    pub battery: Option<f32>,
+    /// Received signal strength in dBm.
+    pub rssi: Option<i32>,
+    pub firmware: Option<String>,
+    pub uptime_secs: Option<u64>,
+}
+
+/// A batch of samples of one session, as posted to the server.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
 pub struct ScanMessage {
     pub device: Uuid,
     pub session: String,
     pub values: Vec<f32>,
+    /// Highest schema version among the batch's device messages.
+    #[serde(default)]
+    pub schema_version: u32,
+    #[serde(default)]
+    pub sample_type: Option<String>,
+    #[serde(default)]
+    pub unit: Option<String>,
+    /// Sequence numbers of the first and last message in the batch.
+    #[serde(default)]
+    pub first_sequence: Option<u64>,
+    #[serde(default)]
+    pub last_sequence: Option<u64>,
+    /// Device timestamps of the first and last message in the batch.
+    #[serde(default)]
+    pub first_timestamp_ms: Option<u64>,
+    #[serde(default)]
+    pub last_timestamp_ms: Option<u64>,
+    /// Messages skipped by the sequence numbers, i.e. lost on the way.
+    #[serde(default)]
+    pub missing: u64,
+    /// Repeated messages that were dropped; late ones are kept.
+    #[serde(default)]
+    pub duplicates: u64,
+}
+
+/// A rejected MQTT message, republished to the dead-letter topic.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct DeadLetter {
+    pub topic: String,
+    pub reason: String,
+    /// The original payload, as text if it is UTF-8 and base64 otherwise.
+    pub payload: String,
+    #[serde(default)]
+    pub base64: bool,
 }
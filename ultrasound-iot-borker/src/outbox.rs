@@ -0,0 +1,427 @@
+use std::collections::VecDeque;
+use std::fmt::Display;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use reqwest::StatusCode;
+use reqwest::header::CONTENT_TYPE;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+use tracing::{debug, info, warn};
+
+use crate::backoff::Backoff;
+use crate::encoding::Encoding;
+use crate::message::ScanMessage;
+
+/// One line of the outbox log.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Record {
+    Enqueue { id: u64, message: ScanMessage },
+    Ack { id: u64 },
+}
+
+/// A message the server refused, appended to the outbox's `.rejected` file.
+#[derive(Debug, Serialize, Deserialize)]
+struct Rejected {
+    reason: String,
+    message: ScanMessage,
+}
+
+/// Scan messages waiting for the server, persisted to an append-only log of
+/// JSON lines so that they survive a restart.
+#[derive(Debug)]
+pub struct Outbox {
+    path: PathBuf,
+    file: File,
+    pending: VecDeque<(u64, ScanMessage)>,
+    next_id: u64,
+}
+
+impl Outbox {
+    /// Open the log at `path`, replaying the messages that were never
+    /// acknowledged, and compact it to just those.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_owned();
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let mut pending = VecDeque::new();
+        let mut next_id = 0;
+        if path.exists() {
+            for line in BufReader::new(File::open(&path)?).lines() {
+                // a line cut short by a crash is skipped
+                match serde_json::from_str::<Record>(&line?) {
+                    Ok(Record::Enqueue { id, message }) => {
+                        next_id = next_id.max(id + 1);
+                        pending.push_back((id, message));
+                    }
+                    Ok(Record::Ack { id }) => pending.retain(|(pending, _)| *pending != id),
+                    Err(e) => warn!("Skipping unreadable outbox record: {}", e),
+                }
+            }
+        }
+        if !pending.is_empty() {
+            info!("Replaying {} undelivered scan messages", pending.len());
+        }
+
+        // rewrite the log with only the pending messages
+        let compacted = path.with_extension("tmp");
+        {
+            let mut file = File::create(&compacted)?;
+            for (id, message) in &pending {
+                let record = Record::Enqueue {
+                    id: *id,
+                    message: message.clone(),
+                };
+                writeln!(file, "{}", serde_json::to_string(&record)?)?;
+            }
+            file.sync_all()?;
+        }
+        fs::rename(&compacted, &path)?;
+
+        let file = OpenOptions::new().append(true).open(&path)?;
+        Ok(Self {
+            path,
+            file,
+            pending,
+            next_id,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// JSON lines file of the messages given up with `reject_front`.
+    pub fn rejected_path(&self) -> PathBuf {
+        self.path.with_extension("rejected")
+    }
+
+    /// Persist a message; it is only queued once it is on disk.
+    pub fn push(&mut self, message: ScanMessage) -> io::Result<()> {
+        let id = self.next_id;
+        let record = Record::Enqueue {
+            id,
+            message: message.clone(),
+        };
+        self.append(&record)?;
+        self.next_id += 1;
+        self.pending.push_back((id, message));
+        Ok(())
+    }
+
+    /// Oldest message not yet acknowledged by the server.
+    pub fn front(&self) -> Option<&ScanMessage> {
+        self.pending.front().map(|(_, message)| message)
+    }
+
+    /// Mark the oldest message as delivered.
+    pub fn ack_front(&mut self) -> io::Result<()> {
+        let Some(&(id, _)) = self.pending.front() else {
+            return Ok(());
+        };
+        if self.pending.len() == 1 {
+            // nothing left to replay, start the log over
+            self.file.set_len(0)?;
+            self.file.sync_data()?;
+        } else {
+            self.append(&Record::Ack { id })?;
+        }
+        self.pending.pop_front();
+        Ok(())
+    }
+
+    /// Move the oldest message to the rejected file, so that a message the
+    /// server will never accept does not hold up the ones behind it.
+    pub fn reject_front(&mut self, reason: &str) -> io::Result<()> {
+        let Some((_, message)) = self.pending.front() else {
+            return Ok(());
+        };
+        let rejected = Rejected {
+            reason: reason.to_owned(),
+            message: message.clone(),
+        };
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.rejected_path())?;
+        writeln!(file, "{}", serde_json::to_string(&rejected)?)?;
+        file.sync_data()?;
+        self.ack_front()
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    fn append(&mut self, record: &Record) -> io::Result<()> {
+        writeln!(self.file, "{}", serde_json::to_string(record)?)?;
+        self.file.sync_data()
+    }
+}
+
+#[derive(Debug)]
+pub enum DeliveryError {
+    Http(reqwest::Error),
+    /// The server answered, but not with a 2xx status.
+    Status(StatusCode),
+}
+
+impl Display for DeliveryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeliveryError::Http(e) => write!(f, "HTTP error: {}", e),
+            DeliveryError::Status(status) => write!(f, "Server responded with {}", status),
+        }
+    }
+}
+
+impl std::error::Error for DeliveryError {}
+
+impl DeliveryError {
+    /// The server refused the request itself (4xx), so retrying it is futile.
+    pub fn is_permanent(&self) -> bool {
+        matches!(self, DeliveryError::Status(status) if status.is_client_error())
+    }
+}
+
+pub async fn post_scan(
+    client: &reqwest::Client,
+    url: &str,
+    encoding: Encoding,
+    scan_message: &ScanMessage,
+) -> Result<(), DeliveryError> {
+    debug!("{:?}", scan_message);
+
+    let body = encoding
+        .encode(scan_message)
+        .expect("Failed to serialize scan message");
+    let response = client
+        .post(url)
+        .header(CONTENT_TYPE, encoding.content_type())
+        .body(body)
+        .send()
+        .await
+        .map_err(DeliveryError::Http)?;
+
+    if response.status().is_success() {
+        Ok(())
+    } else {
+        Err(DeliveryError::Status(response.status()))
+    }
+}
+
+/// Send the outbox to the server in `encoding`, in order, forever. A message is removed only
+/// once the server acknowledged it with a 2xx status, or moved to the rejected file
+/// when it answers 4xx; other failures are retried with `backoff`. `notify` wakes the task when a message is pushed to an empty outbox.
+pub async fn deliver(
+    outbox: Arc<Mutex<Outbox>>,
+    notify: Arc<Notify>,
+    client: reqwest::Client,
+    url: String,
+    encoding: Encoding,
+    mut backoff: Backoff,
+) -> io::Result<()> {
+    loop {
+        let next = outbox.lock().unwrap().front().cloned();
+        let Some(message) = next else {
+            notify.notified().await;
+            continue;
+        };
+
+        match post_scan(&client, &url, encoding, &message).await {
+            Ok(()) => {
+                outbox.lock().unwrap().ack_front()?;
+                backoff.reset();
+            }
+            Err(e) if e.is_permanent() => {
+                warn!(
+                    "Server rejected scan message of session {} ({}), moving it aside",
+                    message.session, e
+                );
+                outbox.lock().unwrap().reject_front(&e.to_string())?;
+                backoff.reset();
+            }
+            Err(e) => {
+                let delay = backoff.next_delay();
+                warn!("Delivery failed ({}), retrying in {:?}", e, delay);
+                tokio::time::sleep(delay).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use uuid::Uuid;
+    use wiremock::matchers::{body_partial_json, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn scan(n: u128) -> ScanMessage {
+        ScanMessage {
+            device: Uuid::from_u128(n),
+            session: format!("s{n}"),
+            values: vec![n as f32],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn unacknowledged_messages_are_replayed() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("outbox.log");
+
+        let mut outbox = Outbox::open(&log).unwrap();
+        for n in 0..3 {
+            outbox.push(scan(n)).unwrap();
+        }
+        outbox.ack_front().unwrap();
+        drop(outbox);
+
+        let mut outbox = Outbox::open(&log).unwrap();
+        assert_eq!(outbox.len(), 2);
+        assert_eq!(outbox.front().unwrap().session, "s1");
+        outbox.push(scan(3)).unwrap();
+        for _ in 0..3 {
+            outbox.ack_front().unwrap();
+        }
+        assert!(outbox.is_empty());
+        drop(outbox);
+
+        assert!(Outbox::open(&log).unwrap().is_empty());
+        assert_eq!(fs::metadata(&log).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn truncated_record_is_skipped() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("outbox.log");
+
+        let mut outbox = Outbox::open(&log).unwrap();
+        outbox.push(scan(1)).unwrap();
+        drop(outbox);
+        let mut file = OpenOptions::new().append(true).open(&log).unwrap();
+        write!(file, "{{\"op\":\"enqueue\",\"id\":1,\"mess").unwrap();
+
+        let outbox = Outbox::open(&log).unwrap();
+        assert_eq!(outbox.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn delivery_retries_until_server_acknowledges() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/scan"))
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(2)
+            .with_priority(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/scan"))
+            .respond_with(ResponseTemplate::new(200))
+            .with_priority(2)
+            .mount(&server)
+            .await;
+
+        // messages queued before a restart
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("outbox.log");
+        let mut outbox = Outbox::open(&log).unwrap();
+        outbox.push(scan(1)).unwrap();
+        outbox.push(scan(2)).unwrap();
+        drop(outbox);
+
+        let outbox = Arc::new(Mutex::new(Outbox::open(&log).unwrap()));
+        let task = tokio::spawn(deliver(
+            outbox.clone(),
+            Arc::new(Notify::new()),
+            reqwest::Client::new(),
+            format!("{}/scan", server.uri()),
+            Encoding::Json,
+            Backoff::new(Duration::from_millis(10), Duration::from_millis(50)),
+        ));
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !outbox.lock().unwrap().is_empty() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("outbox was not delivered");
+        task.abort();
+
+        // two rejected attempts of the first message, then both in order
+        let requests = server.received_requests().await.unwrap();
+        let sessions: Vec<String> = requests
+            .iter()
+            .map(|r| {
+                serde_json::from_slice::<ScanMessage>(&r.body)
+                    .unwrap()
+                    .session
+            })
+            .collect();
+        assert_eq!(sessions, vec!["s1", "s1", "s1", "s2"]);
+    }
+
+    #[tokio::test]
+    async fn rejected_message_does_not_block_the_next() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(body_partial_json(serde_json::json!({ "session": "s1" })))
+            .respond_with(ResponseTemplate::new(400))
+            .with_priority(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(200))
+            .with_priority(2)
+            .mount(&server)
+            .await;
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut outbox = Outbox::open(dir.path().join("outbox.log")).unwrap();
+        outbox.push(scan(1)).unwrap();
+        outbox.push(scan(2)).unwrap();
+        let rejected_path = outbox.rejected_path();
+
+        let outbox = Arc::new(Mutex::new(outbox));
+        let task = tokio::spawn(deliver(
+            outbox.clone(),
+            Arc::new(Notify::new()),
+            reqwest::Client::new(),
+            format!("{}/scan", server.uri()),
+            Encoding::Json,
+            Backoff::new(Duration::from_millis(10), Duration::from_millis(50)),
+        ));
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !outbox.lock().unwrap().is_empty() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("outbox was not delivered");
+        task.abort();
+
+        // the refused message is posted once and kept aside
+        assert_eq!(server.received_requests().await.unwrap().len(), 2);
+        let rejected: Vec<Rejected> = fs::read_to_string(&rejected_path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(rejected.len(), 1);
+        assert_eq!(rejected[0].message, scan(1));
+    }
+}
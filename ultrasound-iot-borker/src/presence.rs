@@ -0,0 +1,204 @@
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::message::{DeviceState, DeviceStatus, Heartbeat};
+
+/// Presence and latest health of a device, reported to the server whenever
+/// the device goes online or offline.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct DeviceHealth {
+    pub device: Uuid,
+    pub state: DeviceState,
+    /// Wall clock time of the last message, in milliseconds since the Unix epoch.
+    pub last_seen_ms: u64,
+    pub battery: Option<f32>,
+    pub rssi: Option<i32>,
+    pub firmware: Option<String>,
+    pub uptime_secs: Option<u64>,
+}
+
+impl DeviceHealth {
+    fn update(&mut self, health: &Heartbeat) {
+        self.battery = health.battery.or(self.battery);
+        self.rssi = health.rssi.or(self.rssi);
+        self.firmware = health.firmware.clone().or(self.firmware.take());
+        self.uptime_secs = health.uptime_secs.or(self.uptime_secs);
+    }
+}
+
+fn millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// Which devices are online. A device is online while it publishes anything,
+/// and offline after its last will or `offline_after` without a message.
+#[derive(Debug)]
+pub struct DeviceRegistry {
+    offline_after: Duration,
+    devices: HashMap<Uuid, DeviceHealth>,
+}
+
+impl DeviceRegistry {
+    pub fn new(offline_after: Duration) -> Self {
+        Self {
+            offline_after,
+            devices: HashMap::new(),
+        }
+    }
+
+    pub fn get(&self, device: &Uuid) -> Option<&DeviceHealth> {
+        self.devices.get(device)
+    }
+
+    pub fn online(&self) -> usize {
+        self.devices
+            .values()
+            .filter(|health| health.state == DeviceState::Online)
+            .count()
+    }
+
+    /// Record any message of `device`; returns its health if it came online.
+    pub fn seen(&mut self, device: Uuid, now: SystemTime) -> Option<DeviceHealth> {
+        self.set_state(device, DeviceState::Online, Some(now), None)
+    }
+
+    pub fn heartbeat(
+        &mut self,
+        device: Uuid,
+        heartbeat: &Heartbeat,
+        now: SystemTime,
+    ) -> Option<DeviceHealth> {
+        self.set_state(device, DeviceState::Online, Some(now), Some(heartbeat))
+    }
+
+    /// Apply a status message. An offline status, typically the last will
+    /// published by the broker, does not count as the device being seen.
+    pub fn status(
+        &mut self,
+        device: Uuid,
+        status: &DeviceStatus,
+        now: SystemTime,
+    ) -> Option<DeviceHealth> {
+        let seen = (status.state == DeviceState::Online).then_some(now);
+        self.set_state(device, status.state, seen, Some(&status.health))
+    }
+
+    /// Take the devices silent for longer than `offline_after` offline.
+    pub fn expire(&mut self, now: SystemTime) -> Vec<DeviceHealth> {
+        let deadline = millis(now).saturating_sub(self.offline_after.as_millis() as u64);
+        self.devices
+            .values_mut()
+            .filter(|health| health.state == DeviceState::Online && health.last_seen_ms <= deadline)
+            .map(|health| {
+                health.state = DeviceState::Offline;
+                health.clone()
+            })
+            .collect()
+    }
+
+    fn set_state(
+        &mut self,
+        device: Uuid,
+        state: DeviceState,
+        seen: Option<SystemTime>,
+        health: Option<&Heartbeat>,
+    ) -> Option<DeviceHealth> {
+        let mut changed = false;
+        let entry = self.devices.entry(device).or_insert_with(|| {
+            changed = true;
+            DeviceHealth {
+                device,
+                state,
+                last_seen_ms: 0,
+                battery: None,
+                rssi: None,
+                firmware: None,
+                uptime_secs: None,
+            }
+        });
+        changed |= entry.state != state;
+        entry.state = state;
+        if let Some(seen) = seen {
+            entry.last_seen_ms = millis(seen);
+        }
+        if let Some(health) = health {
+            entry.update(health);
+        }
+        changed.then(|| entry.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OFFLINE_AFTER: Duration = Duration::from_secs(30);
+
+    fn status(state: DeviceState) -> DeviceStatus {
+        DeviceStatus {
+            state,
+            health: Heartbeat {
+                firmware: Some("0.1.0".to_owned()),
+                ..Default::default()
+            },
+        }
+    }
+
+    #[test]
+    fn only_state_changes_are_reported() {
+        let mut registry = DeviceRegistry::new(OFFLINE_AFTER);
+        let device = Uuid::from_u128(1);
+        let start = UNIX_EPOCH + Duration::from_secs(1_000);
+
+        let online = registry
+            .status(device, &status(DeviceState::Online), start)
+            .unwrap();
+        assert_eq!(online.state, DeviceState::Online);
+        assert_eq!(online.last_seen_ms, 1_000_000);
+
+        let heartbeat = Heartbeat {
+            battery: Some(80.0),
+            rssi: Some(-60),
+            uptime_secs: Some(120),
+            ..Default::default()
+        };
+        let later = start + Duration::from_secs(5);
+        assert!(registry.heartbeat(device, &heartbeat, later).is_none());
+        assert!(registry.seen(device, later).is_none());
+
+        // the last will keeps the health and the time the device was last seen
+        let offline = registry
+            .status(device, &status(DeviceState::Offline), later + OFFLINE_AFTER)
+            .unwrap();
+        assert_eq!(offline.state, DeviceState::Offline);
+        assert_eq!(offline.last_seen_ms, 1_005_000);
+        assert_eq!(
+            (offline.battery, offline.firmware.as_deref()),
+            (Some(80.0), Some("0.1.0"))
+        );
+        assert_eq!(registry.online(), 0);
+    }
+
+    #[test]
+    fn silent_devices_go_offline() {
+        let mut registry = DeviceRegistry::new(OFFLINE_AFTER);
+        let start = UNIX_EPOCH + Duration::from_secs(1_000);
+        registry.seen(Uuid::from_u128(1), start);
+        registry.seen(Uuid::from_u128(2), start + Duration::from_secs(10));
+
+        assert!(registry.expire(start + Duration::from_secs(29)).is_empty());
+        let expired = registry.expire(start + OFFLINE_AFTER);
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].device, Uuid::from_u128(1));
+        assert_eq!(registry.online(), 1);
+
+        // a message brings it back
+        let back = registry.seen(Uuid::from_u128(1), start + Duration::from_secs(31));
+        assert_eq!(back.unwrap().state, DeviceState::Online);
+    }
+}
@@ -0,0 +1,102 @@
+use reqwest::header::CONTENT_TYPE;
+use serde::Serialize;
+use tokio::sync::mpsc;
+use tracing::{error, warn};
+
+use crate::backoff::Backoff;
+use crate::outbox::DeliveryError;
+
+pub async fn post_json<T: Serialize>(
+    client: &reqwest::Client,
+    url: &str,
+    body: &T,
+) -> Result<(), DeliveryError> {
+    let response = client
+        .post(url)
+        .header(CONTENT_TYPE, "application/json")
+        .body(serde_json::to_vec(body).expect("Failed to serialize report"))
+        .send()
+        .await
+        .map_err(DeliveryError::Http)?;
+    if response.status().is_success() {
+        Ok(())
+    } else {
+        Err(DeliveryError::Status(response.status()))
+    }
+}
+
+/// Post reports to the server at `url` in order, retrying each with `backoff`
+/// while the server is unreachable or fails (5xx). A report the server refuses
+/// (4xx) is dropped. Reports are not persisted, unlike scan messages.
+pub async fn report<T: Serialize>(
+    client: reqwest::Client,
+    url: String,
+    mut backoff: Backoff,
+    mut reports: mpsc::UnboundedReceiver<T>,
+) {
+    while let Some(report) = reports.recv().await {
+        loop {
+            match post_json(&client, &url, &report).await {
+                Ok(()) => break,
+                Err(e) if e.is_permanent() => {
+                    error!("Report to {} refused ({}), dropping it", url, e);
+                    break;
+                }
+                Err(e) => {
+                    let delay = backoff.next_delay();
+                    warn!("Report to {} failed ({}), retrying in {:?}", url, e, delay);
+                    tokio::time::sleep(delay).await;
+                }
+            }
+        }
+        backoff.reset();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use wiremock::matchers::{body_partial_json, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    #[tokio::test]
+    async fn refused_report_does_not_block_the_next() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(body_partial_json(serde_json::json!({ "id": 1 })))
+            .respond_with(ResponseTemplate::new(404))
+            .with_priority(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/status"))
+            .respond_with(ResponseTemplate::new(200))
+            .with_priority(2)
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let (reports, rx) = mpsc::unbounded_channel();
+        let task = tokio::spawn(report(
+            reqwest::Client::new(),
+            format!("{}/status", server.uri()),
+            Backoff::new(Duration::from_millis(10), Duration::from_millis(50)),
+            rx,
+        ));
+        reports.send(serde_json::json!({ "id": 1 })).unwrap();
+        reports.send(serde_json::json!({ "id": 2 })).unwrap();
+        drop(reports);
+
+        tokio::time::timeout(Duration::from_secs(5), task)
+            .await
+            .expect("reports were not delivered")
+            .unwrap();
+        let requests = server.received_requests().await.unwrap();
+        let ids: Vec<serde_json::Value> = requests
+            .iter()
+            .map(|r| serde_json::from_slice::<serde_json::Value>(&r.body).unwrap()["id"].clone())
+            .collect();
+        assert_eq!(ids, vec![1, 2]);
+    }
+}
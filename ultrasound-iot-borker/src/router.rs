@@ -0,0 +1,401 @@
+use std::fmt::Display;
+
+use base64::prelude::*;
+use serde::de::DeserializeOwned;
+use uuid::Uuid;
+
+use crate::aggregator::SessionKey;
+use crate::command::CommandAck;
+use crate::encoding::Encoding;
+use crate::message::{
+    DeadLetter, DeviceMessage, DeviceStatus, Heartbeat, SCHEMA_VERSION, SessionControl,
+};
+use crate::topic::TopicPattern;
+
+/// Filter covering every device topic, so that malformed topics are seen and
+/// dead-lettered instead of never being received.
+pub const DEVICE_TOPICS: &str = "rust_6_project/device/#";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TopicKind {
+    Data,
+    SessionControl,
+    Status,
+    Heartbeat,
+    CommandAck,
+}
+
+/// A validated message of a known topic.
+#[derive(Debug)]
+pub enum Routed {
+    Data {
+        key: SessionKey,
+        message: DeviceMessage,
+    },
+    SessionControl {
+        key: SessionKey,
+        control: SessionControl,
+    },
+    Status {
+        device: Uuid,
+        status: DeviceStatus,
+    },
+    Heartbeat {
+        device: Uuid,
+        heartbeat: Heartbeat,
+    },
+    CommandAck {
+        device: Uuid,
+        ack: CommandAck,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RejectReason {
+    UnknownTopic,
+    InvalidDevice(String),
+    InvalidPayload(String),
+    UnsupportedEncoding(String),
+    UnsupportedVersion(u32),
+    OutOfRange(&'static str),
+}
+
+impl Display for RejectReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RejectReason::UnknownTopic => write!(f, "Unknown topic"),
+            RejectReason::InvalidDevice(device) => write!(f, "Invalid device id {:?}", device),
+            RejectReason::InvalidPayload(e) => write!(f, "Invalid payload: {}", e),
+            RejectReason::UnsupportedEncoding(encoding) => {
+                write!(f, "Unsupported encoding {:?}", encoding)
+            }
+            RejectReason::UnsupportedVersion(version) => {
+                write!(f, "Unsupported schema version {}", version)
+            }
+            RejectReason::OutOfRange(field) => write!(f, "`{}` is out of range", field),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Rejection {
+    pub topic: String,
+    pub reason: RejectReason,
+}
+
+impl Rejection {
+    /// The dead letter of `payload`; binary payloads, e.g. CBOR, are kept
+    /// intact as base64.
+    pub fn dead_letter(&self, payload: &[u8]) -> DeadLetter {
+        let (payload, base64) = match std::str::from_utf8(payload) {
+            Ok(text) => (text.to_owned(), false),
+            Err(_) => (BASE64_STANDARD.encode(payload), true),
+        };
+        DeadLetter {
+            topic: self.topic.clone(),
+            reason: self.reason.to_string(),
+            payload,
+            base64,
+        }
+    }
+}
+
+/// Matches device topics against the declared patterns, the first match
+/// winning, and decodes and validates their payloads. Payloads are JSON unless
+/// a data topic ends in an encoding (`.../session/{session}/cbor`) or the
+/// message has an MQTT v5 content type.
+#[derive(Debug)]
+pub struct Router {
+    routes: Vec<(TopicPattern, TopicKind)>,
+}
+
+impl Default for Router {
+    fn default() -> Self {
+        let routes = [
+            (
+                "rust_6_project/device/{device}/session/{session}",
+                TopicKind::Data,
+            ),
+            (
+                "rust_6_project/device/{device}/session/{session}/control",
+                TopicKind::SessionControl,
+            ),
+            (
+                "rust_6_project/device/{device}/session/{session}/{encoding}",
+                TopicKind::Data,
+            ),
+            ("rust_6_project/device/{device}/status", TopicKind::Status),
+            (
+                "rust_6_project/device/{device}/heartbeat",
+                TopicKind::Heartbeat,
+            ),
+            (
+                "rust_6_project/device/{device}/command/ack",
+                TopicKind::CommandAck,
+            ),
+        ];
+        Self {
+            routes: routes
+                .into_iter()
+                .map(|(pattern, kind)| (TopicPattern::new(pattern), kind))
+                .collect(),
+        }
+    }
+}
+
+impl Router {
+    pub fn route(
+        &self,
+        topic: &str,
+        payload: &[u8],
+        content_type: Option<&str>,
+    ) -> Result<Routed, Rejection> {
+        let reject = |reason| Rejection {
+            topic: topic.to_owned(),
+            reason,
+        };
+
+        let (kind, captures) = self
+            .routes
+            .iter()
+            .find_map(|(pattern, kind)| pattern.captures(topic).map(|c| (*kind, c)))
+            .ok_or_else(|| reject(RejectReason::UnknownTopic))?;
+
+        let device = captures["device"];
+        let device = Uuid::parse_str(device)
+            .map_err(|_| reject(RejectReason::InvalidDevice(device.to_owned())))?;
+        let encoding = match (captures.get("encoding"), content_type) {
+            (Some(suffix), _) => Encoding::from_suffix(suffix)
+                .ok_or_else(|| reject(RejectReason::UnsupportedEncoding(suffix.to_string())))?,
+            (None, Some(content_type)) => {
+                Encoding::from_content_type(content_type).ok_or_else(|| {
+                    reject(RejectReason::UnsupportedEncoding(content_type.to_owned()))
+                })?
+            }
+            (None, None) => Encoding::Json,
+        };
+        let key = || SessionKey {
+            device,
+            session: captures["session"].to_owned(),
+        };
+
+        let routed = match kind {
+            TopicKind::Data => {
+                let message: DeviceMessage = parse(encoding, payload).map_err(reject)?;
+                validate(&message).map_err(reject)?;
+                Routed::Data {
+                    key: key(),
+                    message,
+                }
+            }
+            TopicKind::SessionControl => Routed::SessionControl {
+                key: key(),
+                control: parse(encoding, payload).map_err(reject)?,
+            },
+            TopicKind::Status => {
+                let status: DeviceStatus = parse(encoding, payload).map_err(reject)?;
+                validate_health(&status.health).map_err(reject)?;
+                Routed::Status { device, status }
+            }
+            TopicKind::Heartbeat => {
+                let heartbeat: Heartbeat = parse(encoding, payload).map_err(reject)?;
+                validate_health(&heartbeat).map_err(reject)?;
+                Routed::Heartbeat { device, heartbeat }
+            }
+            TopicKind::CommandAck => Routed::CommandAck {
+                device,
+                ack: parse(encoding, payload).map_err(reject)?,
+            },
+        };
+        Ok(routed)
+    }
+}
+
+fn parse<T: DeserializeOwned>(encoding: Encoding, payload: &[u8]) -> Result<T, RejectReason> {
+    encoding
+        .decode(payload)
+        .map_err(RejectReason::InvalidPayload)
+}
+
+fn validate(message: &DeviceMessage) -> Result<(), RejectReason> {
+    if !(1..=SCHEMA_VERSION).contains(&message.version) {
+        return Err(RejectReason::UnsupportedVersion(message.version));
+    }
+    // only a session-end marker may come without samples
+    if message.values().next().is_none() && !message.end {
+        return Err(RejectReason::InvalidPayload("no samples".to_owned()));
+    }
+    if !message.values().all(f32::is_finite) {
+        return Err(RejectReason::OutOfRange("value"));
+    }
+    Ok(())
+}
+
+fn validate_health(health: &Heartbeat) -> Result<(), RejectReason> {
+    if health
+        .battery
+        .is_some_and(|battery| !(0.0..=100.0).contains(&battery))
+    {
+        return Err(RejectReason::OutOfRange("battery"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{DeviceState, SessionAction};
+
+    const DEVICE: &str = "00000000-0000-0000-0000-000000000001";
+
+    fn topic(suffix: &str) -> String {
+        format!("rust_6_project/device/{DEVICE}/{suffix}")
+    }
+
+    #[test]
+    fn topics_are_routed_by_kind() {
+        let router = Router::default();
+
+        match router.route(&topic("session/s1"), br#"{ "value": 1.5 }"#, None) {
+            Ok(Routed::Data { key, message }) => {
+                assert_eq!(key.device, Uuid::from_u128(1));
+                assert_eq!(key.session, "s1");
+                assert_eq!(message.version, 1);
+                assert_eq!(message.values().collect::<Vec<_>>(), vec![1.5]);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        let versioned = br#"{
+            "version": 2, "sequence": 7, "timestamp_ms": 1200,
+            "sample_type": "temperature", "unit": "celsius", "samples": [20.5, 20.7]
+        }"#;
+        match router.route(&topic("session/s1"), versioned, None) {
+            Ok(Routed::Data { message, .. }) => {
+                assert_eq!((message.version, message.sequence), (2, Some(7)));
+                assert_eq!(message.timestamp_ms, Some(1200));
+                assert_eq!(message.unit.as_deref(), Some("celsius"));
+                assert_eq!(message.values().collect::<Vec<_>>(), vec![20.5, 20.7]);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        match router.route(
+            &topic("session/s1/control"),
+            br#"{ "action": "end" }"#,
+            None,
+        ) {
+            Ok(Routed::SessionControl { key, control }) => {
+                assert_eq!(key.session, "s1");
+                assert_eq!(control.action, SessionAction::End);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        match router.route(&topic("status"), br#"{ "state": "offline" }"#, None) {
+            Ok(Routed::Status { status, .. }) => assert_eq!(status.state, DeviceState::Offline),
+            other => panic!("unexpected {:?}", other),
+        }
+        match router.route(
+            &topic("heartbeat"),
+            br#"{ "battery": 80, "rssi": -60 }"#,
+            None,
+        ) {
+            Ok(Routed::Heartbeat { heartbeat, .. }) => assert_eq!(heartbeat.rssi, Some(-60)),
+            other => panic!("unexpected {:?}", other),
+        }
+        let ack = br#"{ "id": "00000000-0000-0000-0000-000000000002", "ok": true }"#;
+        match router.route(&topic("command/ack"), ack, None) {
+            Ok(Routed::CommandAck { device, ack }) => {
+                assert_eq!(device, Uuid::from_u128(1));
+                assert_eq!(ack.id, Uuid::from_u128(2));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn invalid_messages_are_rejected_with_a_reason() {
+        let router = Router::default();
+        let reason =
+            |topic: &str, payload: &[u8]| router.route(topic, payload, None).unwrap_err().reason;
+
+        assert_eq!(
+            reason(&topic("firmware"), b"{}"),
+            RejectReason::UnknownTopic
+        );
+        assert_eq!(
+            reason("rust_6_project/device/d1/session/s1", br#"{ "value": 1 }"#),
+            RejectReason::InvalidDevice("d1".to_owned())
+        );
+        assert!(matches!(
+            reason(&topic("session/s1"), br#"{ "val": 1 }"#),
+            RejectReason::InvalidPayload(_)
+        ));
+        assert_eq!(
+            reason(&topic("session/s1"), br#"{ "version": 3, "value": 1 }"#),
+            RejectReason::UnsupportedVersion(3)
+        );
+        assert!(matches!(
+            reason(&topic("session/s1/control"), br#"{ "action": "pause" }"#),
+            RejectReason::InvalidPayload(_)
+        ));
+        assert_eq!(
+            reason(&topic("heartbeat"), br#"{ "battery": 140 }"#),
+            RejectReason::OutOfRange("battery")
+        );
+        assert_eq!(
+            reason(&topic("status"), br#"{ "state": "online", "battery": -1 }"#),
+            RejectReason::OutOfRange("battery")
+        );
+
+        assert_eq!(
+            reason(&topic("session/s1/protobuf"), b""),
+            RejectReason::UnsupportedEncoding("protobuf".to_owned())
+        );
+
+        let rejection = router
+            .route(&topic("session/s1"), b"\xff1", None)
+            .unwrap_err();
+        let dead_letter = rejection.dead_letter(b"\xff1");
+        assert_eq!(dead_letter.topic, topic("session/s1"));
+        assert_eq!(dead_letter.payload, BASE64_STANDARD.encode(b"\xff1"));
+        assert!(dead_letter.base64);
+        let dead_letter = rejection.dead_letter(b"{ \"value\": 1");
+        assert_eq!(
+            (dead_letter.payload.as_str(), dead_letter.base64),
+            ("{ \"value\": 1", false)
+        );
+        assert!(dead_letter.reason.starts_with("Invalid payload"));
+    }
+
+    #[test]
+    fn binary_payloads_are_decoded() {
+        let router = Router::default();
+        let message = serde_json::json!({ "version": 2, "sequence": 3, "samples": [1.5, 2.5] });
+        let samples = |routed: Result<Routed, Rejection>| match routed {
+            Ok(Routed::Data { key, message }) => {
+                assert_eq!(key.session, "s1");
+                message.values().collect::<Vec<_>>()
+            }
+            other => panic!("unexpected {:?}", other),
+        };
+
+        let cbor = Encoding::Cbor.encode(&message).unwrap();
+        let msgpack = Encoding::MessagePack.encode(&message).unwrap();
+        for routed in [
+            router.route(&topic("session/s1/cbor"), &cbor, None),
+            router.route(&topic("session/s1/msgpack"), &msgpack, None),
+            router.route(&topic("session/s1"), &cbor, Some("application/cbor")),
+            // the topic suffix takes precedence over the content type
+            router.route(&topic("session/s1/cbor"), &cbor, Some("application/json")),
+        ] {
+            assert_eq!(samples(routed), vec![1.5, 2.5]);
+        }
+
+        assert!(matches!(
+            router
+                .route(&topic("session/s1"), &cbor, Some("text/plain"))
+                .unwrap_err()
+                .reason,
+            RejectReason::UnsupportedEncoding(_)
+        ));
+    }
+}
@@ -1,21 +1,246 @@
+use std::time::Duration;
+
 use config::{Config, ConfigError, File};
 use serde::Deserialize;
 
+use crate::aggregator::AggregationPolicy;
+use crate::backoff::Backoff;
+use crate::encoding::Encoding;
+use crate::topic::topic_matches;
+
 #[derive(Debug, Deserialize)]
 pub struct Broker {
     pub host: String,
     pub port: u16,
+    /// Stable client id, so that the broker can resume the session.
+    #[serde(default = "Broker::default_client_id")]
+    pub client_id: String,
+    /// Start a new session on every connection instead of resuming the
+    /// previous one (and its queued QoS 1 messages).
+    #[serde(default)]
+    pub clean_session: bool,
+    /// How long the broker keeps a resumable session after a disconnect.
+    #[serde(default = "Broker::default_session_expiry_secs")]
+    pub session_expiry_secs: u32,
+    #[serde(default = "Broker::default_keep_alive_secs")]
+    pub keep_alive_secs: u64,
+    /// First reconnect delay, doubled after each failed attempt.
+    #[serde(default = "Broker::default_reconnect_initial_ms")]
+    pub reconnect_initial_ms: u64,
+    #[serde(default = "Broker::default_reconnect_max_secs")]
+    pub reconnect_max_secs: u64,
+    /// Rejected messages are republished here with the reason.
+    #[serde(default = "Broker::default_dead_letter_topic")]
+    pub dead_letter_topic: String,
+    /// Host the broker in-process instead of connecting to `host`:`port`.
+    #[serde(default)]
+    pub embedded: Option<EmbeddedBroker>,
+}
+
+/// In-process MQTT broker, available with the `embedded-broker` feature.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct EmbeddedBroker {
+    pub bind: String,
+    pub port: u16,
+    pub max_connections: usize,
+    pub max_payload_size: usize,
+}
+
+impl Default for EmbeddedBroker {
+    fn default() -> Self {
+        Self {
+            bind: "0.0.0.0".to_owned(),
+            port: 1883,
+            max_connections: 100,
+            max_payload_size: 20480,
+        }
+    }
+}
+
+impl Broker {
+    fn default_client_id() -> String {
+        "6_2_hub".to_owned()
+    }
+
+    fn default_dead_letter_topic() -> String {
+        "rust_6_project/dead_letter".to_owned()
+    }
+
+    fn default_session_expiry_secs() -> u32 {
+        3600
+    }
+
+    fn default_keep_alive_secs() -> u64 {
+        5
+    }
+
+    fn default_reconnect_initial_ms() -> u64 {
+        500
+    }
+
+    fn default_reconnect_max_secs() -> u64 {
+        30
+    }
+
+    pub fn reconnect_backoff(&self) -> Backoff {
+        Backoff::new(
+            Duration::from_millis(self.reconnect_initial_ms),
+            Duration::from_secs(self.reconnect_max_secs),
+        )
+    }
 }
 
 #[derive(Debug, Deserialize)]
 pub struct Server {
     pub url: String,
+    /// Encoding of the scan messages posted to `url`.
+    #[serde(default)]
+    pub encoding: Encoding,
+}
+
+/// Aggregation policy of the topics matching an MQTT topic filter.
+#[derive(Debug, Deserialize)]
+pub struct TopicPolicy {
+    pub topic: String,
+    pub policy: AggregationPolicy,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct Aggregation {
+    /// Partial batches are sent after this long without samples.
+    pub idle_timeout_secs: u64,
+    /// Policy of topics not matched by `topics`.
+    pub policy: AggregationPolicy,
+    /// Per-topic policies; the first matching filter wins.
+    pub topics: Vec<TopicPolicy>,
+}
+
+impl Default for Aggregation {
+    fn default() -> Self {
+        Self {
+            idle_timeout_secs: 30,
+            policy: AggregationPolicy::default(),
+            topics: Vec::new(),
+        }
+    }
+}
+
+impl Aggregation {
+    pub fn idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.idle_timeout_secs)
+    }
+
+    pub fn policy_for(&self, topic: &str) -> AggregationPolicy {
+        self.topics
+            .iter()
+            .find(|entry| topic_matches(&entry.topic, topic))
+            .map_or(self.policy, |entry| entry.policy)
+    }
+}
+
+/// Delivery of scan messages to the server.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct Delivery {
+    /// Append-only log of messages not yet acknowledged by the server.
+    pub outbox_path: String,
+    /// First retry delay after a failed post, doubled on each further failure.
+    pub initial_backoff_ms: u64,
+    pub max_backoff_secs: u64,
+}
+
+impl Default for Delivery {
+    fn default() -> Self {
+        Self {
+            outbox_path: "ultrasound-iot-borker/data/outbox.log".to_owned(),
+            initial_backoff_ms: 500,
+            max_backoff_secs: 60,
+        }
+    }
+}
+
+impl Delivery {
+    pub fn backoff(&self) -> Backoff {
+        Backoff::new(
+            Duration::from_millis(self.initial_backoff_ms),
+            Duration::from_secs(self.max_backoff_secs),
+        )
+    }
+}
+
+/// Downlink of commands from the server to the devices.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct Commands {
+    /// Base URL of the server's command endpoints.
+    pub url: String,
+    pub poll_interval_secs: u64,
+    /// A command is published again if the device did not acknowledge it
+    /// within this time.
+    pub ack_timeout_secs: u64,
+    pub max_attempts: u32,
+}
+
+impl Default for Commands {
+    fn default() -> Self {
+        Self {
+            url: "http://localhost:8080/command".to_owned(),
+            poll_interval_secs: 2,
+            ack_timeout_secs: 10,
+            max_attempts: 3,
+        }
+    }
+}
+
+impl Commands {
+    pub fn poll_interval(&self) -> Duration {
+        Duration::from_secs(self.poll_interval_secs)
+    }
+
+    pub fn ack_timeout(&self) -> Duration {
+        Duration::from_secs(self.ack_timeout_secs)
+    }
+}
+
+/// Tracking of which devices are online.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct Presence {
+    /// A device without messages for this long is considered offline.
+    pub offline_after_secs: u64,
+    /// Server endpoint receiving the devices' state changes, if any.
+    pub url: Option<String>,
+}
+
+impl Default for Presence {
+    fn default() -> Self {
+        Self {
+            offline_after_secs: 30,
+            url: None,
+        }
+    }
+}
+
+impl Presence {
+    pub fn offline_after(&self) -> Duration {
+        Duration::from_secs(self.offline_after_secs)
+    }
 }
 
 #[derive(Debug, Deserialize)]
 pub struct Settings {
     pub broker: Broker,
     pub server: Server,
+    #[serde(default)]
+    pub aggregation: Aggregation,
+    #[serde(default)]
+    pub delivery: Delivery,
+    /// Commands are only relayed when this section is present.
+    pub commands: Option<Commands>,
+    #[serde(default)]
+    pub presence: Presence,
 }
 
 impl Settings {
@@ -32,3 +257,53 @@ impl Settings {
         s.try_deserialize()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::FileFormat;
+
+    #[test]
+    fn policies_are_selected_per_topic() {
+        let toml = r#"
+            [broker]
+            host = "localhost"
+            port = 1883
+
+            [server]
+            url = "http://localhost:8080/scan"
+
+            [aggregation]
+            policy = { kind = "count", samples = 8 }
+
+            [[aggregation.topics]]
+            topic = "rust_6_project/device/slow/session/+"
+            policy = { kind = "time_window", window_secs = 10 }
+
+            [[aggregation.topics]]
+            topic = "rust_6_project/device/+/session/+"
+            policy = { kind = "session_end" }
+        "#;
+        let settings: Settings = Config::builder()
+            .add_source(File::from_str(toml, FileFormat::Toml))
+            .build()
+            .unwrap()
+            .try_deserialize()
+            .unwrap();
+        let aggregation = settings.aggregation;
+
+        assert_eq!(aggregation.idle_timeout(), Duration::from_secs(30));
+        assert_eq!(
+            aggregation.policy_for("rust_6_project/device/slow/session/s1"),
+            AggregationPolicy::TimeWindow { window_secs: 10 }
+        );
+        assert_eq!(
+            aggregation.policy_for("rust_6_project/device/d1/session/s1"),
+            AggregationPolicy::SessionEnd
+        );
+        assert_eq!(
+            aggregation.policy_for("other/topic"),
+            AggregationPolicy::Count { samples: 8 }
+        );
+    }
+}
@@ -0,0 +1,123 @@
+use std::collections::HashMap;
+
+/// Whether `topic` matches the MQTT topic filter `filter`, where `+` matches
+/// one level and a trailing `#` any number of levels.
+pub fn topic_matches(filter: &str, topic: &str) -> bool {
+    let mut levels = topic.split('/');
+    for pattern in filter.split('/') {
+        match (pattern, levels.next()) {
+            ("#", _) => return true,
+            ("+", Some(_)) => {}
+            (pattern, Some(level)) if pattern == level => {}
+            _ => return false,
+        }
+    }
+    levels.next().is_none()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Level {
+    Literal(String),
+    /// `{name}`, matches and captures one level.
+    Param(String),
+}
+
+/// Topic with named single-level parameters, e.g.
+/// `rust_6_project/device/{device}/session/{session}`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TopicPattern {
+    levels: Vec<Level>,
+}
+
+impl TopicPattern {
+    pub fn new(pattern: &str) -> Self {
+        let levels = pattern
+            .split('/')
+            .map(
+                |level| match level.strip_prefix('{').and_then(|l| l.strip_suffix('}')) {
+                    Some(name) => Level::Param(name.to_owned()),
+                    None => Level::Literal(level.to_owned()),
+                },
+            )
+            .collect();
+        Self { levels }
+    }
+
+    /// The equivalent MQTT subscription filter.
+    pub fn filter(&self) -> String {
+        self.levels
+            .iter()
+            .map(|level| match level {
+                Level::Literal(literal) => literal.as_str(),
+                Level::Param(_) => "+",
+            })
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+
+    /// The parameter values if `topic` matches.
+    pub fn captures<'t>(&self, topic: &'t str) -> Option<HashMap<&str, &'t str>> {
+        let levels: Vec<&str> = topic.split('/').collect();
+        if levels.len() != self.levels.len() {
+            return None;
+        }
+        let mut captures = HashMap::new();
+        for (pattern, level) in self.levels.iter().zip(levels) {
+            match pattern {
+                Level::Literal(literal) if literal == level => {}
+                Level::Param(name) if !level.is_empty() => {
+                    captures.insert(name.as_str(), level);
+                }
+                _ => return None,
+            }
+        }
+        Some(captures)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wildcards() {
+        let topic = "rust_6_project/device/d1/session/s1";
+        assert!(topic_matches("rust_6_project/device/+/session/+", topic));
+        assert!(topic_matches("rust_6_project/device/d1/#", topic));
+        assert!(topic_matches("#", topic));
+        assert!(!topic_matches("rust_6_project/device/d2/session/+", topic));
+        assert!(!topic_matches("rust_6_project/device/+", topic));
+        assert!(!topic_matches(
+            "rust_6_project/device/+/session/+/end",
+            topic
+        ));
+    }
+
+    #[test]
+    fn pattern_captures_parameters() {
+        let pattern = TopicPattern::new("rust_6_project/device/{device}/session/{session}");
+        assert_eq!(pattern.filter(), "rust_6_project/device/+/session/+");
+
+        let captures = pattern
+            .captures("rust_6_project/device/d1/session/s1")
+            .unwrap();
+        assert_eq!(captures["device"], "d1");
+        assert_eq!(captures["session"], "s1");
+
+        assert!(
+            pattern
+                .captures("rust_6_project/device/d1/session")
+                .is_none()
+        );
+        assert!(
+            pattern
+                .captures("rust_6_project/device//session/s1")
+                .is_none()
+        );
+        assert!(
+            pattern
+                .captures("rust_6_project/device/d1/status/s1")
+                .is_none()
+        );
+    }
+}
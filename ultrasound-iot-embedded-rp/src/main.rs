@@ -10,6 +10,7 @@ use cyw43::JoinOptions;
 use cyw43_pio::{DEFAULT_CLOCK_DIVIDER, PioSpi};
 use defmt::*;
 use embassy_executor::Spawner;
+use embassy_futures::select::{Either, select};
 use embassy_net::tcp::TcpSocket;
 use embassy_net::{Config, StackResources};
 use embassy_net::{Config as EmbassyNetConfig, dns::DnsQueryType};
@@ -19,7 +20,7 @@ use embassy_rp::clocks::RoscRng;
 use embassy_rp::gpio::{Level, Output};
 use embassy_rp::peripherals::{DMA_CH0, PIO0};
 use embassy_rp::pio::Pio;
-use embassy_time::{Duration, Timer};
+use embassy_time::{Duration, Instant, Timer};
 use heapless::String;
 use rand::RngCore;
 use rust_mqtt::{
@@ -27,6 +28,7 @@ use rust_mqtt::{
     packet::v5::reason_codes::ReasonCode,
     utils::rng_generator::CountingRng,
 };
+use serde::Deserialize;
 use static_cell::StaticCell;
 use {defmt_rtt as _, panic_probe as _};
 
@@ -40,6 +42,56 @@ const WIFI_PASSWORD: &str = env!("WIFI_PASSWORD");
 
 const DEVICE_ID: &str = env!("DEVICE_ID");
 
+/// Sampling periods between two heartbeats, also while sampling is stopped.
+const HEARTBEAT_EVERY: u32 = 10;
+
+/// A command relayed by the broker on `.../command`.
+#[derive(Deserialize)]
+struct CommandMessage<'a> {
+    id: &'a str,
+    #[serde(rename = "type")]
+    kind: &'a str,
+    session: Option<&'a str>,
+    interval_ms: Option<u32>,
+}
+
+/// What the sampling loop does for a command.
+enum Action {
+    StartSession(Option<String<32>>),
+    StopSession,
+    SetInterval(u32),
+    Reboot,
+}
+
+/// Parse a command into its id and action, or the reason it was rejected.
+fn parse_command(payload: &[u8]) -> Option<(String<36>, Result<Action, &'static str>)> {
+    let (command, _) = serde_json_core::from_slice::<CommandMessage>(payload).ok()?;
+    let id = String::try_from(command.id).ok()?;
+    let action = match command.kind {
+        "start_session" => match command.session.map(String::try_from) {
+            Some(Err(_)) => Err("session id too long"),
+            session => Ok(Action::StartSession(session.and_then(Result::ok))),
+        },
+        "stop_session" => Ok(Action::StopSession),
+        "set_sampling_interval" => match command.interval_ms {
+            Some(interval_ms) if interval_ms >= 100 => Ok(Action::SetInterval(interval_ms)),
+            _ => Err("invalid interval"),
+        },
+        "reboot" => Ok(Action::Reboot),
+        _ => Err("unsupported command"),
+    };
+    Some((id, action))
+}
+
+fn session_topic(session: &str) -> String<128> {
+    let mut topic = String::new();
+    let _ = core::fmt::write(
+        &mut topic,
+        format_args!("rust_6_project/device/{}/session/{}", DEVICE_ID, session),
+    );
+    topic
+}
+
 #[embassy_executor::task]
 async fn cyw43_task(
     runner: cyw43::Runner<'static, Output<'static>, PioSpi<'static, PIO0, 0, DMA_CH0>>,
@@ -167,13 +219,37 @@ async fn main(spawner: Spawner) {
     }
     info!("connected!");
 
+    let mut status_topic: String<128> = String::new();
+    let _ = core::fmt::write(
+        &mut status_topic,
+        format_args!("rust_6_project/device/{}/status", DEVICE_ID),
+    );
+    let mut heartbeat_topic: String<128> = String::new();
+    let _ = core::fmt::write(
+        &mut heartbeat_topic,
+        format_args!("rust_6_project/device/{}/heartbeat", DEVICE_ID),
+    );
+    let mut command_topic: String<128> = String::new();
+    let _ = core::fmt::write(
+        &mut command_topic,
+        format_args!("rust_6_project/device/{}/command", DEVICE_ID),
+    );
+    let mut ack_topic: String<128> = String::new();
+    let _ = core::fmt::write(
+        &mut ack_topic,
+        format_args!("rust_6_project/device/{}/command/ack", DEVICE_ID),
+    );
+
     let mut config = ClientConfig::new(
         rust_mqtt::client::client_config::MqttVersion::MQTTv5,
         CountingRng(20000),
     );
     config.add_max_subscribe_qos(rust_mqtt::packet::v5::publish_packet::QualityOfService::QoS1);
     config.add_client_id(DEVICE_ID);
-    config.max_packet_size = 100;
+    // published by the broker when the connection is lost
+    config.add_will(&status_topic, b"{ \"state\": \"offline\" }", false);
+    // commands carry two UUIDs
+    config.max_packet_size = 256;
     let mut recv_buffer = [0; 256];
     let mut write_buffer = [0; 256];
 
@@ -200,56 +276,224 @@ async fn main(spawner: Spawner) {
         },
     }
 
-    let session_id = generate_id_hex(&mut rng);
-    let mut topic: String<128> = String::new();
+    let mut status = String::<96>::new();
     let _ = core::fmt::write(
-        &mut topic,
+        &mut status,
         format_args!(
-            "rust_6_project/device/{}/session/{}",
-            DEVICE_ID, &session_id
+            "{{ \"state\": \"online\", \"firmware\": \"{}\" }}",
+            env!("CARGO_PKG_VERSION")
         ),
     );
+    if let Err(mqtt_error) = client
+        .send_message(
+            &status_topic,
+            status.as_bytes(),
+            rust_mqtt::packet::v5::publish_packet::QualityOfService::QoS1,
+            false,
+        )
+        .await
+    {
+        error!("MQTT Error: {:?}", mqtt_error);
+        return;
+    }
 
-    let delay = Duration::from_secs(1);
+    if let Err(mqtt_error) = client.subscribe_to_topic(&command_topic).await {
+        error!("MQTT Error: {:?}", mqtt_error);
+        return;
+    }
+
+    let mut topic = session_topic(&generate_id_hex(&mut rng));
+
+    // half of the sampling interval
+    let mut delay = Duration::from_secs(1);
+    let mut sampling = true;
+    let mut sequence: u32 = 0;
+    let mut periods: u32 = 0;
+    // the broker republishes a command until its ack arrives, so a repeated
+    // id is acknowledged again without running the command twice
+    let mut last_command: Option<(String<36>, String<128>)> = None;
     loop {
         info!("led on!");
         control.gpio_set(0, true).await;
         Timer::after(delay).await;
 
-        let temp = adc.read(&mut ts).await.unwrap();
-        let celsius = convert_to_celsius(temp);
-
-        let mut celsius_string = String::<64>::new();
-        let _ = core::fmt::write(
-            &mut celsius_string,
-            format_args!("{{ \"value\": {} }}", celsius),
-        );
-
-        match client
-            .send_message(
-                &topic,
-                celsius_string.as_bytes(),
-                rust_mqtt::packet::v5::publish_packet::QualityOfService::QoS1,
-                true,
-            )
-            .await
+        if sampling {
+            let temp = adc.read(&mut ts).await.unwrap();
+            let celsius = convert_to_celsius(temp);
+
+            let mut celsius_string = String::<192>::new();
+            let _ = core::fmt::write(
+                &mut celsius_string,
+                format_args!(
+                    "{{ \"version\": 2, \"sequence\": {}, \"timestamp_ms\": {}, \"sample_type\": \"temperature\", \"unit\": \"celsius\", \"value\": {} }}",
+                    sequence,
+                    Instant::now().as_millis(),
+                    celsius
+                ),
+            );
+            sequence += 1;
+
+            match client
+                .send_message(
+                    &topic,
+                    celsius_string.as_bytes(),
+                    rust_mqtt::packet::v5::publish_packet::QualityOfService::QoS1,
+                    true,
+                )
+                .await
+            {
+                Ok(()) => {}
+                Err(mqtt_error) => match mqtt_error {
+                    ReasonCode::NetworkError => {
+                        error!("MQTT Network Error");
+                        return;
+                    }
+                    _ => {
+                        error!("Other MQTT Error: {:?}", mqtt_error);
+                        return;
+                    }
+                },
+            }
+        }
+
+        periods += 1;
+        if periods % HEARTBEAT_EVERY == 0 {
+            let mut heartbeat = String::<96>::new();
+            let _ = core::fmt::write(
+                &mut heartbeat,
+                format_args!(
+                    "{{ \"firmware\": \"{}\", \"uptime_secs\": {} }}",
+                    env!("CARGO_PKG_VERSION"),
+                    Instant::now().as_secs()
+                ),
+            );
+            if let Err(mqtt_error) = client
+                .send_message(
+                    &heartbeat_topic,
+                    heartbeat.as_bytes(),
+                    rust_mqtt::packet::v5::publish_packet::QualityOfService::QoS0,
+                    false,
+                )
+                .await
+            {
+                error!("MQTT Error: {:?}", mqtt_error);
+                return;
+            }
+        }
+
+        info!("led off!");
+        control.gpio_set(0, false).await;
+
+        // wait for commands until the next sample is due
+        let deadline = Instant::now() + delay;
+        while let Either::First(received) =
+            select(client.receive_message(), Timer::at(deadline)).await
         {
-            Ok(()) => {}
-            Err(mqtt_error) => match mqtt_error {
-                ReasonCode::NetworkError => {
-                    error!("MQTT Network Error");
+            let parsed = match received {
+                Ok((_, payload)) => parse_command(payload),
+                Err(mqtt_error) => {
+                    error!("MQTT Error: {:?}", mqtt_error);
                     return;
                 }
-                _ => {
-                    error!("Other MQTT Error: {:?}", mqtt_error);
+            };
+            let Some((id, action)) = parsed else {
+                warn!("Ignoring malformed command");
+                continue;
+            };
+            if let Some((_, ack)) = last_command.as_ref().filter(|(last, _)| *last == id) {
+                warn!("Acknowledging repeated command again");
+                if let Err(mqtt_error) = client
+                    .send_message(
+                        &ack_topic,
+                        ack.as_bytes(),
+                        rust_mqtt::packet::v5::publish_packet::QualityOfService::QoS1,
+                        false,
+                    )
+                    .await
+                {
+                    error!("MQTT Error: {:?}", mqtt_error);
                     return;
                 }
-            },
-        }
+                continue;
+            }
 
-        info!("led off!");
-        control.gpio_set(0, false).await;
-        Timer::after(delay).await;
+            let mut reboot = false;
+            let mut end_session = false;
+            let result = action.map(|action| match action {
+                Action::StartSession(session) => {
+                    topic = match session {
+                        Some(session) => session_topic(&session),
+                        None => session_topic(&generate_id_hex(&mut rng)),
+                    };
+                    sampling = true;
+                }
+                Action::StopSession => {
+                    end_session = sampling;
+                    sampling = false;
+                }
+                Action::SetInterval(interval_ms) => {
+                    delay = Duration::from_millis(interval_ms as u64 / 2)
+                }
+                Action::Reboot => reboot = true,
+            });
+
+            // session-end marker, so that the broker sends the batch right away
+            if end_session {
+                let mut marker = String::<192>::new();
+                let _ = core::fmt::write(
+                    &mut marker,
+                    format_args!(
+                        "{{ \"version\": 2, \"sequence\": {}, \"timestamp_ms\": {}, \"sample_type\": \"temperature\", \"unit\": \"celsius\", \"end\": true }}",
+                        sequence,
+                        Instant::now().as_millis()
+                    ),
+                );
+                sequence += 1;
+                if let Err(mqtt_error) = client
+                    .send_message(
+                        &topic,
+                        marker.as_bytes(),
+                        rust_mqtt::packet::v5::publish_packet::QualityOfService::QoS1,
+                        false,
+                    )
+                    .await
+                {
+                    error!("MQTT Error: {:?}", mqtt_error);
+                    return;
+                }
+            }
+
+            let mut ack = String::<128>::new();
+            let _ = match result {
+                Ok(()) => core::fmt::write(
+                    &mut ack,
+                    format_args!("{{ \"id\": \"{}\", \"ok\": true }}", id),
+                ),
+                Err(reason) => core::fmt::write(
+                    &mut ack,
+                    format_args!(
+                        "{{ \"id\": \"{}\", \"ok\": false, \"error\": \"{}\" }}",
+                        id, reason
+                    ),
+                ),
+            };
+            if let Err(mqtt_error) = client
+                .send_message(
+                    &ack_topic,
+                    ack.as_bytes(),
+                    rust_mqtt::packet::v5::publish_packet::QualityOfService::QoS1,
+                    false,
+                )
+                .await
+            {
+                error!("MQTT Error: {:?}", mqtt_error);
+                return;
+            }
+            last_command = Some((id, ack));
+            if reboot {
+                cortex_m::peripheral::SCB::sys_reset();
+            }
+        }
     }
 }
 
@@ -0,0 +1,449 @@
+use image::{Rgba as RgbaPixel, RgbaImage};
+
+use crate::colormap::Rgba;
+use crate::constants::*;
+use crate::measurement::{Calibration, PixelPoint, distance_mm};
+use crate::processing::encode_png;
+use crate::{ImageError, UltrasoundImage};
+
+/// Spacing of the depth scale ticks; every second tick is labelled.
+const DEPTH_TICK_MM: f64 = 5.0;
+
+#[derive(Debug, Clone, PartialEq, uniffi::Enum)]
+pub enum Annotation {
+    /// Text with its top-left corner at `position`.
+    Text { position: PixelPoint, text: String },
+    /// Arrow pointing from `from` to `to`.
+    Arrow { from: PixelPoint, to: PixelPoint },
+    /// Caliper between two points, labelled with their distance in millimetres.
+    Caliper { start: PixelPoint, end: PixelPoint },
+}
+
+#[derive(Debug, Clone, PartialEq, uniffi::Record)]
+pub struct AnnotationOverlay {
+    pub session_id: Option<String>,
+    pub device_id: Option<String>,
+    /// Acquisition time, printed as given.
+    pub timestamp: Option<String>,
+    /// Depth scale with ticks along the right edge.
+    pub depth_scale: bool,
+    /// Marker at TRANSMIT_FOCAL_DEPTH on the left edge.
+    pub focal_marker: bool,
+    pub annotations: Vec<Annotation>,
+    pub color: Rgba,
+}
+
+impl Default for AnnotationOverlay {
+    fn default() -> Self {
+        Self {
+            session_id: None,
+            device_id: None,
+            timestamp: None,
+            depth_scale: true,
+            focal_marker: true,
+            annotations: Vec::new(),
+            color: Rgba {
+                r: 255,
+                g: 255,
+                b: 0,
+                a: 255,
+            },
+        }
+    }
+}
+
+/// 5x7 bitmap font, one byte per row with the leftmost pixel in bit 4.
+fn glyph(c: char) -> [u8; 7] {
+    match c.to_ascii_uppercase() {
+        '0' => [0x0E, 0x11, 0x13, 0x15, 0x19, 0x11, 0x0E],
+        '1' => [0x04, 0x0C, 0x04, 0x04, 0x04, 0x04, 0x0E],
+        '2' => [0x0E, 0x11, 0x01, 0x02, 0x04, 0x08, 0x1F],
+        '3' => [0x1F, 0x02, 0x04, 0x02, 0x01, 0x11, 0x0E],
+        '4' => [0x02, 0x06, 0x0A, 0x12, 0x1F, 0x02, 0x02],
+        '5' => [0x1F, 0x10, 0x1E, 0x01, 0x01, 0x11, 0x0E],
+        '6' => [0x06, 0x08, 0x10, 0x1E, 0x11, 0x11, 0x0E],
+        '7' => [0x1F, 0x01, 0x02, 0x04, 0x08, 0x08, 0x08],
+        '8' => [0x0E, 0x11, 0x11, 0x0E, 0x11, 0x11, 0x0E],
+        '9' => [0x0E, 0x11, 0x11, 0x0F, 0x01, 0x02, 0x0C],
+        'A' => [0x0E, 0x11, 0x11, 0x11, 0x1F, 0x11, 0x11],
+        'B' => [0x1E, 0x11, 0x11, 0x1E, 0x11, 0x11, 0x1E],
+        'C' => [0x0E, 0x11, 0x10, 0x10, 0x10, 0x11, 0x0E],
+        'D' => [0x1C, 0x12, 0x11, 0x11, 0x11, 0x12, 0x1C],
+        'E' => [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x1F],
+        'F' => [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x10],
+        'G' => [0x0E, 0x11, 0x10, 0x17, 0x11, 0x11, 0x0F],
+        'H' => [0x11, 0x11, 0x11, 0x1F, 0x11, 0x11, 0x11],
+        'I' => [0x0E, 0x04, 0x04, 0x04, 0x04, 0x04, 0x0E],
+        'J' => [0x07, 0x02, 0x02, 0x02, 0x02, 0x12, 0x0C],
+        'K' => [0x11, 0x12, 0x14, 0x18, 0x14, 0x12, 0x11],
+        'L' => [0x10, 0x10, 0x10, 0x10, 0x10, 0x10, 0x1F],
+        'M' => [0x11, 0x1B, 0x15, 0x15, 0x11, 0x11, 0x11],
+        'N' => [0x11, 0x11, 0x19, 0x15, 0x13, 0x11, 0x11],
+        'O' => [0x0E, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E],
+        'P' => [0x1E, 0x11, 0x11, 0x1E, 0x10, 0x10, 0x10],
+        'Q' => [0x0E, 0x11, 0x11, 0x11, 0x15, 0x12, 0x0D],
+        'R' => [0x1E, 0x11, 0x11, 0x1E, 0x14, 0x12, 0x11],
+        'S' => [0x0F, 0x10, 0x10, 0x0E, 0x01, 0x01, 0x1E],
+        'T' => [0x1F, 0x04, 0x04, 0x04, 0x04, 0x04, 0x04],
+        'U' => [0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E],
+        'V' => [0x11, 0x11, 0x11, 0x11, 0x11, 0x0A, 0x04],
+        'W' => [0x11, 0x11, 0x11, 0x15, 0x15, 0x15, 0x0A],
+        'X' => [0x11, 0x11, 0x0A, 0x04, 0x0A, 0x11, 0x11],
+        'Y' => [0x11, 0x11, 0x11, 0x0A, 0x04, 0x04, 0x04],
+        'Z' => [0x1F, 0x01, 0x02, 0x04, 0x08, 0x10, 0x1F],
+        ' ' => [0x00; 7],
+        '.' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x0C, 0x0C],
+        ',' => [0x00, 0x00, 0x00, 0x00, 0x0C, 0x04, 0x08],
+        ':' => [0x00, 0x0C, 0x0C, 0x00, 0x0C, 0x0C, 0x00],
+        '-' => [0x00, 0x00, 0x00, 0x1F, 0x00, 0x00, 0x00],
+        '+' => [0x00, 0x04, 0x04, 0x1F, 0x04, 0x04, 0x00],
+        '/' => [0x00, 0x01, 0x02, 0x04, 0x08, 0x10, 0x00],
+        '_' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x1F],
+        '=' => [0x00, 0x00, 0x1F, 0x00, 0x1F, 0x00, 0x00],
+        '(' => [0x02, 0x04, 0x08, 0x08, 0x08, 0x04, 0x02],
+        ')' => [0x08, 0x04, 0x02, 0x02, 0x02, 0x04, 0x08],
+        '#' => [0x0A, 0x0A, 0x1F, 0x0A, 0x1F, 0x0A, 0x0A],
+        '%' => [0x18, 0x19, 0x02, 0x04, 0x08, 0x13, 0x03],
+        _ => [0x0E, 0x11, 0x01, 0x02, 0x04, 0x00, 0x04], // '?'
+    }
+}
+
+/// Drawing surface with a single pen color and font scale.
+struct Canvas {
+    img: RgbaImage,
+    color: RgbaPixel<u8>,
+    scale: i64,
+}
+
+impl Canvas {
+    fn plot(&mut self, x: i64, y: i64) {
+        if x < 0 || y < 0 || x >= self.img.width() as i64 || y >= self.img.height() as i64 {
+            return;
+        }
+        let base = *self.img.get_pixel(x as u32, y as u32);
+        let alpha = self.color[3] as f64 / 255.0;
+        let mix = |c: usize| {
+            (self.color[c] as f64 * alpha + base[c] as f64 * (1.0 - alpha)).round() as u8
+        };
+        self.img
+            .put_pixel(x as u32, y as u32, RgbaPixel([mix(0), mix(1), mix(2), 255]));
+    }
+
+    /// Bresenham line between two pixel positions.
+    fn line(&mut self, (x0, y0): (i64, i64), (x1, y1): (i64, i64)) {
+        let (dx, dy) = ((x1 - x0).abs(), -(y1 - y0).abs());
+        let (sx, sy) = ((x1 - x0).signum(), (y1 - y0).signum());
+        let (mut x, mut y, mut err) = (x0, y0, dx + dy);
+        loop {
+            self.plot(x, y);
+            if x == x1 && y == y1 {
+                break;
+            }
+            let e2 = 2 * err;
+            if e2 >= dy {
+                err += dy;
+                x += sx;
+            }
+            if e2 <= dx {
+                err += dx;
+                y += sy;
+            }
+        }
+    }
+
+    fn text_width(&self, text: &str) -> i64 {
+        text.chars().count() as i64 * 6 * self.scale
+    }
+
+    fn text_height(&self) -> i64 {
+        7 * self.scale
+    }
+
+    fn text(&mut self, (x, y): (i64, i64), text: &str) {
+        for (i, c) in text.chars().enumerate() {
+            let left = x + i as i64 * 6 * self.scale;
+            for (row, bits) in glyph(c).iter().enumerate() {
+                for col in 0..5 {
+                    if bits & (0x10 >> col) == 0 {
+                        continue;
+                    }
+                    for dy in 0..self.scale {
+                        for dx in 0..self.scale {
+                            self.plot(
+                                left + col * self.scale + dx,
+                                y + row as i64 * self.scale + dy,
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn pixel(p: PixelPoint) -> (i64, i64) {
+    (p.x.round() as i64, p.y.round() as i64)
+}
+
+/// Row of the given depth (mm) in a calibrated image.
+fn depth_row(calibration: &Calibration, depth_mm: f64) -> f64 {
+    (depth_mm - calibration.z_origin_mm) / calibration.pixel_height_mm
+}
+
+fn draw_depth_scale(canvas: &mut Canvas, calibration: &Calibration) {
+    let (width, height) = (canvas.img.width() as i64, canvas.img.height() as i64);
+    let bottom_mm = calibration.z_origin_mm + (height - 1) as f64 * calibration.pixel_height_mm;
+    let first = (calibration.z_origin_mm / DEPTH_TICK_MM).ceil() as i64;
+    let last = (bottom_mm / DEPTH_TICK_MM).floor() as i64;
+
+    for tick in first..=last {
+        let depth_mm = tick as f64 * DEPTH_TICK_MM;
+        let row = depth_row(calibration, depth_mm).round() as i64;
+        let major = tick % 2 == 0;
+        let length = if major { 8 } else { 4 } * canvas.scale;
+        canvas.line((width - 1 - length, row), (width - 1, row));
+        if major {
+            let label = format!("{}", depth_mm.round() as i64);
+            let x = width - 1 - length - canvas.scale - canvas.text_width(&label);
+            // kept inside the image, or at its top when the image is shorter than the text
+            let y = (row - canvas.text_height() / 2)
+                .min(height - canvas.text_height())
+                .max(0);
+            canvas.text((x, y), &label);
+        }
+    }
+}
+
+fn draw_focal_marker(canvas: &mut Canvas, calibration: &Calibration) {
+    let row = depth_row(calibration, TRANSMIT_FOCAL_DEPTH * 1e3).round() as i64;
+    let size = 4 * canvas.scale;
+    // right-pointing triangle on the left edge
+    for i in 0..=size {
+        canvas.line((i, row - (size - i)), (i, row + (size - i)));
+    }
+}
+
+fn draw_arrow(canvas: &mut Canvas, from: (i64, i64), to: (i64, i64)) {
+    canvas.line(from, to);
+    let angle = ((from.1 - to.1) as f64).atan2((from.0 - to.0) as f64);
+    let head = 5.0 * canvas.scale as f64;
+    for side in [-0.5, 0.5] {
+        let a = angle + side;
+        let tip = (
+            to.0 + (head * a.cos()).round() as i64,
+            to.1 + (head * a.sin()).round() as i64,
+        );
+        canvas.line(to, tip);
+    }
+}
+
+fn draw_caliper(
+    canvas: &mut Canvas,
+    calibration: &Calibration,
+    start: PixelPoint,
+    end: PixelPoint,
+) {
+    let (a, b) = (pixel(start), pixel(end));
+    canvas.line(a, b);
+
+    // short perpendicular ticks at both ends
+    let (dx, dy) = (end.x - start.x, end.y - start.y);
+    let norm = dx.hypot(dy).max(f64::MIN_POSITIVE);
+    let half = 3.0 * canvas.scale as f64;
+    let (px, py) = (
+        (-dy / norm * half).round() as i64,
+        (dx / norm * half).round() as i64,
+    );
+    for (x, y) in [a, b] {
+        canvas.line((x - px, y - py), (x + px, y + py));
+    }
+
+    let label = format!("{:.1} MM", distance_mm(*calibration, start, end));
+    let mid = ((a.0 + b.0) / 2, (a.1 + b.1) / 2);
+    canvas.text((mid.0 + 2 * canvas.scale, mid.1 + 2 * canvas.scale), &label);
+}
+
+/// Burn the overlay into a copy of `image`, returning an RGBA image with the
+/// same calibration and display parameters. Depth scale, focal marker and
+/// calipers need the image to be calibrated.
+#[uniffi::export]
+pub fn annotate_image(
+    image: UltrasoundImage,
+    overlay: AnnotationOverlay,
+) -> Result<UltrasoundImage, ImageError> {
+    let needs_calibration = overlay.depth_scale
+        || overlay.focal_marker
+        || overlay
+            .annotations
+            .iter()
+            .any(|a| matches!(a, Annotation::Caliper { .. }));
+    let calibration = match image.calibration {
+        Some(calibration) => Some(calibration),
+        None if needs_calibration => {
+            return Err(ImageError::InvalidData(
+                "Depth scale, focal marker and calipers need a calibrated image".to_owned(),
+            ));
+        }
+        None => None,
+    };
+
+    let decoded =
+        image::load_from_memory(&image.data).map_err(|e| ImageError::InvalidData(e.to_string()))?;
+    let c = overlay.color;
+    let mut canvas = Canvas {
+        img: decoded.to_rgba8(),
+        color: RgbaPixel([c.r, c.g, c.b, c.a]),
+        scale: (decoded.height() as i64 / 300).max(1),
+    };
+
+    // session header in the top-left corner
+    let header = [
+        overlay.session_id.map(|id| format!("SESSION {id}")),
+        overlay.device_id.map(|id| format!("DEVICE {id}")),
+        overlay.timestamp,
+    ];
+    let line_height = canvas.text_height() + 2 * canvas.scale;
+    for (i, text) in header.iter().flatten().enumerate() {
+        let top = canvas.scale + i as i64 * line_height;
+        canvas.text((canvas.scale * 6, top), text);
+    }
+
+    if let Some(calibration) = &calibration {
+        if overlay.depth_scale {
+            draw_depth_scale(&mut canvas, calibration);
+        }
+        if overlay.focal_marker {
+            draw_focal_marker(&mut canvas, calibration);
+        }
+    }
+
+    for annotation in &overlay.annotations {
+        match annotation {
+            Annotation::Text { position, text } => canvas.text(pixel(*position), text),
+            Annotation::Arrow { from, to } => draw_arrow(&mut canvas, pixel(*from), pixel(*to)),
+            Annotation::Caliper { start, end } => {
+                if let Some(calibration) = &calibration {
+                    draw_caliper(&mut canvas, calibration, *start, *end);
+                }
+            }
+        }
+    }
+
+    encode_png(
+        image::DynamicImage::ImageRgba8(canvas.img),
+        calibration,
+        image.display,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::colormap::PixelFormat;
+    use crate::display::DisplayParameters;
+    use image::{DynamicImage, GrayImage};
+
+    fn raw_image(calibration: Option<Calibration>) -> UltrasoundImage {
+        let gray = GrayImage::new(200, 400);
+        encode_png(
+            DynamicImage::ImageLuma8(gray),
+            calibration,
+            DisplayParameters::fixed(Vec::new()),
+        )
+        .unwrap()
+    }
+
+    fn calibration() -> Calibration {
+        // 0.1 mm pixels from 0 to 39.9 mm depth
+        Calibration {
+            x_origin_mm: -10.0,
+            z_origin_mm: 0.0,
+            pixel_width_mm: 0.1,
+            pixel_height_mm: 0.1,
+        }
+    }
+
+    fn decode(image: &UltrasoundImage) -> RgbaImage {
+        image::load_from_memory(&image.data).unwrap().to_rgba8()
+    }
+
+    fn is_pen(p: &RgbaPixel<u8>) -> bool {
+        p.0 == [255, 255, 0, 255]
+    }
+
+    #[test]
+    fn depth_scale_and_focal_marker_follow_calibration() {
+        let raw = raw_image(Some(calibration()));
+        let raw_pixels = decode(&raw);
+        let annotated = annotate_image(raw, AnnotationOverlay::default()).unwrap();
+        let img = decode(&annotated);
+
+        assert_eq!(annotated.format, PixelFormat::Rgba8);
+        assert_eq!(annotated.calibration, Some(calibration()));
+        // ticks every 5 mm on the right edge, longer every 10 mm
+        for (row, length) in [(50, 4), (100, 8), (150, 4), (300, 8)] {
+            assert!(is_pen(img.get_pixel(199, row)));
+            assert!(is_pen(img.get_pixel(199 - length, row)));
+            assert!(!is_pen(img.get_pixel(199 - length - 1, row)));
+            assert!(!is_pen(img.get_pixel(199, row + 2)));
+        }
+        // focal marker tip at 20 mm
+        assert!(is_pen(img.get_pixel(4, 200)));
+        assert!(!is_pen(img.get_pixel(4, 201)));
+        // the raw image is left as it was
+        assert!(raw_pixels.pixels().all(|p| p.0 == [0, 0, 0, 255]));
+    }
+
+    #[test]
+    fn depth_scale_fits_images_shorter_than_text() {
+        let tiny = encode_png(
+            DynamicImage::ImageLuma8(GrayImage::new(20, 4)),
+            Some(Calibration {
+                pixel_height_mm: 1.0,
+                ..calibration()
+            }),
+            DisplayParameters::fixed(Vec::new()),
+        )
+        .unwrap();
+        let img = decode(&annotate_image(tiny, AnnotationOverlay::default()).unwrap());
+
+        assert_eq!(img.dimensions(), (20, 4));
+        // the 0 mm tick is still drawn
+        assert!(is_pen(img.get_pixel(19, 0)));
+    }
+
+    #[test]
+    fn text_and_calipers_are_drawn() {
+        let overlay = AnnotationOverlay {
+            session_id: Some("abc".to_owned()),
+            depth_scale: false,
+            focal_marker: false,
+            annotations: vec![
+                Annotation::Text {
+                    position: PixelPoint { x: 20.0, y: 100.0 },
+                    text: "I".to_owned(),
+                },
+                Annotation::Caliper {
+                    start: PixelPoint { x: 50.0, y: 200.0 },
+                    end: PixelPoint { x: 150.0, y: 200.0 },
+                },
+            ],
+            ..Default::default()
+        };
+        let img = decode(&annotate_image(raw_image(Some(calibration())), overlay).unwrap());
+
+        // top bar of 'I' spans columns 1..=3 of the glyph
+        assert!(is_pen(img.get_pixel(21, 100)) && is_pen(img.get_pixel(23, 100)));
+        assert!(!is_pen(img.get_pixel(20, 100)) && !is_pen(img.get_pixel(24, 100)));
+        // caliper line and end ticks
+        assert!(is_pen(img.get_pixel(100, 200)));
+        assert!(is_pen(img.get_pixel(50, 197)) && is_pen(img.get_pixel(150, 203)));
+        // header text was drawn near the top-left corner
+        assert!(
+            img.enumerate_pixels()
+                .any(|(x, y, p)| x < 100 && y < 10 && is_pen(p))
+        );
+
+        assert!(annotate_image(raw_image(None), AnnotationOverlay::default()).is_err());
+    }
+}
@@ -0,0 +1,410 @@
+use ndarray::{Array1, Array2, Array3, ArrayView2, Axis, s};
+use rayon::prelude::*;
+
+use crate::constants::*;
+use crate::iq2img::{beamform_df, steered_delay_indices};
+use crate::real::Real;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, uniffi::Enum)]
+pub enum BeamformerKind {
+    /// Conventional delay-and-sum.
+    #[default]
+    DelayAndSum,
+    /// Delay-and-sum weighted by the coherence factor.
+    CoherenceFactor,
+    /// Delay-and-sum weighted by the generalized coherence factor, treating
+    /// spatial frequencies up to `m0` bins as coherent.
+    GeneralizedCoherenceFactor { m0: u32 },
+    /// Capon/minimum-variance with subaperture averaging over `subaperture`
+    /// elements and `diagonal_loading` relative to the mean eigenvalue.
+    MinimumVariance {
+        subaperture: u32,
+        diagonal_loading: f64,
+    },
+}
+
+pub fn beamform<T: Real>(
+    data: &Array3<T>,
+    time: &Array1<f64>,
+    xd: &Array1<f64>,
+    kind: BeamformerKind,
+) -> Array2<T> {
+    match kind {
+        BeamformerKind::DelayAndSum => beamform_df(data, time, xd),
+        _ => beamform_steered(data, time, xd, kind, 0.0),
+    }
+}
+
+/// Beamform along scan lines steered by `angle` (radians) from the array normal.
+pub fn beamform_steered<T: Real>(
+    data: &Array3<T>,
+    time: &Array1<f64>,
+    xd: &Array1<f64>,
+    kind: BeamformerKind,
+    angle: f64,
+) -> Array2<T> {
+    let prop_dist_ind = steered_delay_indices(time, xd, angle);
+    match kind {
+        BeamformerKind::DelayAndSum => beamform_das(data, &prop_dist_ind),
+        BeamformerKind::CoherenceFactor => beamform_gcf(data, &prop_dist_ind, 0),
+        BeamformerKind::GeneralizedCoherenceFactor { m0 } => {
+            beamform_gcf(data, &prop_dist_ind, m0 as usize)
+        }
+        BeamformerKind::MinimumVariance {
+            subaperture,
+            diagonal_loading,
+        } => beamform_mv(data, &prop_dist_ind, subaperture as usize, diagonal_loading),
+    }
+}
+
+/// Half-length (in samples) of the temporal window used to stabilise the
+/// adaptive estimates; spans roughly one period of the transmit pulse.
+fn averaging_half_window() -> usize {
+    let sample_rate = SAMPLE_RATE * UPSAMP_FACT as f64;
+    (sample_rate / TRANSMIT_FREQ / 2.0).round() as usize
+}
+
+/// Delay every channel of transmit beam `n` onto the focal grid, giving a
+/// (channels, depth) matrix of aligned samples.
+pub fn focus_beam<T: Real>(data: &Array3<T>, n: usize, prop_dist_ind: &Array2<usize>) -> Array2<T> {
+    let (channels, depth) = prop_dist_ind.dim();
+    let mut aligned = Array2::<T>::zeros((channels, depth));
+    for m in 0..channels {
+        let waveform = data.slice(s![n, m, ..]);
+        for (t, &idx) in prop_dist_ind.slice(s![m, ..]).iter().enumerate() {
+            aligned[[m, t]] = waveform[idx];
+        }
+    }
+    aligned
+}
+
+fn window_sum<T: Real>(x: &Array1<T>, half_window: usize) -> Array1<T> {
+    let len = x.len();
+    let mut cumsum = Array1::<T>::zeros(len + 1);
+    for i in 0..len {
+        cumsum[i + 1] = cumsum[i] + x[i];
+    }
+    Array1::from_shape_fn(len, |t| {
+        let lo = t.saturating_sub(half_window);
+        let hi = (t + half_window + 1).min(len);
+        cumsum[hi] - cumsum[lo]
+    })
+}
+
+/// Generalized coherence factor of aligned channel data. With `m0 = 0` this
+/// is the conventional coherence factor |sum x|^2 / (N sum |x|^2).
+pub fn coherence_factor<T: Real>(
+    aligned: &ArrayView2<T>,
+    m0: usize,
+    half_window: usize,
+) -> Array1<T> {
+    let (channels, depth) = aligned.dim();
+    let m0 = m0.min(channels / 2);
+
+    // spatial DFT kernel for bins -m0..=m0
+    let kernel: Vec<Vec<(T, T)>> = (-(m0 as isize)..=(m0 as isize))
+        .map(|k| {
+            (0..channels)
+                .map(|m| {
+                    let phase =
+                        -2.0 * std::f64::consts::PI * (k * m as isize) as f64 / channels as f64;
+                    (T::cast(phase.cos()), T::cast(phase.sin()))
+                })
+                .collect()
+        })
+        .collect();
+
+    let mut coherent = Array1::<T>::zeros(depth);
+    let mut total = Array1::<T>::zeros(depth);
+    for t in 0..depth {
+        let column = aligned.column(t);
+        total[t] = T::cast(channels as f64) * column.mapv(|x| x * x).sum();
+        // energy of the aperture spectrum within +/- m0 spatial bins
+        for bin in &kernel {
+            let (mut re, mut im) = (T::zero(), T::zero());
+            for (&x, &(c, s)) in column.iter().zip(bin) {
+                re += x * c;
+                im += x * s;
+            }
+            coherent[t] += re * re + im * im;
+        }
+    }
+
+    let coherent = window_sum(&coherent, half_window);
+    let total = window_sum(&total, half_window);
+    Array1::from_shape_fn(depth, |t| {
+        if total[t] > T::zero() {
+            (coherent[t] / total[t]).max(T::zero()).min(T::one())
+        } else {
+            T::zero()
+        }
+    })
+}
+
+pub fn beamform_das<T: Real>(data: &Array3<T>, prop_dist_ind: &Array2<usize>) -> Array2<T> {
+    let n_beams = data.shape()[0];
+
+    let lines: Vec<Array1<T>> = (0..n_beams)
+        .into_par_iter()
+        .map(|n| focus_beam(data, n, prop_dist_ind).sum_axis(Axis(0)))
+        .collect();
+
+    stack_lines(lines, prop_dist_ind.shape()[1])
+}
+
+pub fn beamform_gcf<T: Real>(
+    data: &Array3<T>,
+    prop_dist_ind: &Array2<usize>,
+    m0: usize,
+) -> Array2<T> {
+    let half_window = averaging_half_window();
+    let n_beams = data.shape()[0];
+
+    let lines: Vec<Array1<T>> = (0..n_beams)
+        .into_par_iter()
+        .map(|n| {
+            let aligned = focus_beam(data, n, prop_dist_ind);
+            let das = aligned.sum_axis(Axis(0));
+            let cf = coherence_factor(&aligned.view(), m0, half_window);
+            das * cf
+        })
+        .collect();
+
+    stack_lines(lines, prop_dist_ind.shape()[1])
+}
+
+pub fn beamform_mv<T: Real>(
+    data: &Array3<T>,
+    prop_dist_ind: &Array2<usize>,
+    subaperture: usize,
+    diagonal_loading: f64,
+) -> Array2<T> {
+    let half_window = averaging_half_window();
+    let n_beams = data.shape()[0];
+
+    let lines: Vec<Array1<T>> = (0..n_beams)
+        .into_par_iter()
+        .map(|n| {
+            let aligned = focus_beam(data, n, prop_dist_ind);
+            minimum_variance(&aligned.view(), subaperture, diagonal_loading, half_window)
+        })
+        .collect();
+
+    stack_lines(lines, prop_dist_ind.shape()[1])
+}
+
+/// Minimum-variance output for aligned channel data, using forward
+/// subaperture averaging and temporal averaging over `2 * half_window + 1`
+/// samples to estimate the spatial covariance.
+pub fn minimum_variance<T: Real>(
+    aligned: &ArrayView2<T>,
+    subaperture: usize,
+    diagonal_loading: f64,
+    half_window: usize,
+) -> Array1<T> {
+    let (channels, depth) = aligned.dim();
+    let l = subaperture.clamp(1, channels);
+    let n_sub = channels - l + 1;
+
+    // covariance summed over the subapertures of a single sample
+    let outer = |cov: &mut [T], tau: usize, sign: T| {
+        let column = aligned.column(tau);
+        for p in 0..n_sub {
+            for i in 0..l {
+                for j in 0..=i {
+                    cov[i * l + j] += sign * column[p + i] * column[p + j];
+                }
+            }
+        }
+    };
+
+    let mut line = Array1::<T>::zeros(depth);
+    let mut window = vec![T::zero(); l * l];
+    let mut cov = vec![T::zero(); l * l];
+    let mut weights = vec![T::zero(); l];
+    for tau in 0..half_window.min(depth) {
+        outer(&mut window, tau, T::one());
+    }
+    for t in 0..depth {
+        // slide the temporal window to [t - half_window, t + half_window]
+        if t + half_window < depth {
+            outer(&mut window, t + half_window, T::one());
+        }
+        if t > half_window {
+            outer(&mut window, t - half_window - 1, -T::one());
+        }
+
+        cov.copy_from_slice(&window);
+        let trace: T = (0..l).map(|i| cov[i * l + i]).sum();
+        let loading = T::cast(diagonal_loading) * trace / T::cast(l as f64);
+        for i in 0..l {
+            cov[i * l + i] += loading;
+        }
+
+        // w = R^-1 a / (a^T R^-1 a) with a steering vector of ones
+        weights.iter_mut().for_each(|w| *w = T::one());
+        let solved = trace > T::zero() && cholesky_solve(&mut cov, l, &mut weights);
+        let mut norm: T = weights.iter().copied().sum();
+        if !solved || !norm.is_finite() || norm <= T::zero() {
+            // degenerate covariance, fall back to uniform weights
+            weights.iter_mut().for_each(|w| *w = T::one());
+            norm = T::cast(l as f64);
+        }
+
+        let column = aligned.column(t);
+        let mut y = T::zero();
+        for p in 0..n_sub {
+            for i in 0..l {
+                y += weights[i] * column[p + i];
+            }
+        }
+        // scale to the delay-and-sum amplitude of the full aperture
+        line[t] = y / norm / T::cast(n_sub as f64) * T::cast(channels as f64);
+    }
+    line
+}
+
+/// Solve `A x = b` in place for a symmetric positive definite `A` whose
+/// lower triangle is stored row-major in `a`. Returns false if `A` is not
+/// positive definite.
+fn cholesky_solve<T: Real>(a: &mut [T], n: usize, b: &mut [T]) -> bool {
+    for j in 0..n {
+        let mut d = a[j * n + j];
+        for k in 0..j {
+            d -= a[j * n + k] * a[j * n + k];
+        }
+        if d <= T::zero() {
+            return false;
+        }
+        let d = d.sqrt();
+        a[j * n + j] = d;
+        for i in j + 1..n {
+            let mut v = a[i * n + j];
+            for k in 0..j {
+                v -= a[i * n + k] * a[j * n + k];
+            }
+            a[i * n + j] = v / d;
+        }
+    }
+    // forward substitution L y = b
+    for i in 0..n {
+        let mut v = b[i];
+        for k in 0..i {
+            v -= a[i * n + k] * b[k];
+        }
+        b[i] = v / a[i * n + i];
+    }
+    // back substitution L^T x = y
+    for i in (0..n).rev() {
+        let mut v = b[i];
+        for k in i + 1..n {
+            v -= a[k * n + i] * b[k];
+        }
+        b[i] = v / a[i * n + i];
+    }
+    true
+}
+
+fn stack_lines<T: Real>(lines: Vec<Array1<T>>, depth: usize) -> Array2<T> {
+    let mut image = Array2::<T>::zeros((lines.len(), depth));
+    for (n, line) in lines.iter().enumerate() {
+        image.slice_mut(s![n, ..]).assign(line);
+    }
+    image
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::iq2img::envelope;
+    use ndarray_stats::QuantileExt;
+
+    const N_BEAMS: usize = 17;
+    const TARGET_DEPTH: f64 = 20e-3;
+
+    /// Channel data of a single point scatterer below the centre beam.
+    fn point_target() -> (Array3<f64>, Array1<f64>, Array1<f64>) {
+        // delays are indexed from t = 0, so the record has to start there
+        let sample_rate = SAMPLE_RATE * UPSAMP_FACT as f64;
+        let n_samples = (2.0 * 23e-3 / SPEED_SOUND * sample_rate) as usize;
+        let time = Array1::from_shape_fn(n_samples, |i| i as f64 / sample_rate);
+
+        let channels = N_PROBE_CHANNELS as usize;
+        let xd = Array1::from_shape_fn(channels, |m| {
+            (m as f64 - (channels - 1) as f64 / 2.0) * ARRAY_PITCH
+        });
+
+        let sigma = 1.0 / TRANSMIT_FREQ;
+        let data = Array3::from_shape_fn((N_BEAMS, channels, n_samples), |(n, m, i)| {
+            // lateral offset of the target from the centre of the active aperture
+            let dx = (N_BEAMS / 2) as f64 * ARRAY_PITCH - n as f64 * ARRAY_PITCH;
+            let tx = (dx.powi(2) + TARGET_DEPTH.powi(2)).sqrt();
+            let rx = ((xd[m] - dx).powi(2) + TARGET_DEPTH.powi(2)).sqrt();
+            let tau = time[i] - (tx + rx) / SPEED_SOUND;
+            (-(tau / sigma).powi(2)).exp()
+                * (2.0 * std::f64::consts::PI * TRANSMIT_FREQ * tau).cos()
+        });
+        (data, time, xd)
+    }
+
+    /// Lateral profile (peak envelope per beam) normalised to its maximum.
+    fn lateral_profile(image: &Array2<f64>) -> Array1<f64> {
+        let profile = Array1::from_shape_fn(image.shape()[0], |n| {
+            let line = image.slice(s![n, ..]).into_owned();
+            envelope(&line).fold(0.0, |a: f64, &b| a.max(b))
+        });
+        let max = profile.fold(0.0, |a: f64, &b| a.max(b));
+        profile / max
+    }
+
+    /// -6 dB width of the lateral profile in beams.
+    fn resolution(profile: &Array1<f64>) -> usize {
+        profile.iter().filter(|&&v| v >= 0.5).count()
+    }
+
+    /// Mean sidelobe level (dB) away from the target.
+    fn clutter_db(profile: &Array1<f64>) -> f64 {
+        let centre = N_BEAMS / 2;
+        let off: Vec<f64> = profile
+            .iter()
+            .enumerate()
+            .filter(|(n, _)| n.abs_diff(centre) > 4)
+            .map(|(_, &v)| v * v)
+            .collect();
+        10.0 * (off.iter().sum::<f64>() / off.len() as f64).log10()
+    }
+
+    #[test]
+    fn adaptive_beamformers_improve_point_target() {
+        let (data, time, xd) = point_target();
+
+        let das = lateral_profile(&beamform(&data, &time, &xd, BeamformerKind::DelayAndSum));
+        let cf = lateral_profile(&beamform(
+            &data,
+            &time,
+            &xd,
+            BeamformerKind::CoherenceFactor,
+        ));
+        let gcf = lateral_profile(&beamform(
+            &data,
+            &time,
+            &xd,
+            BeamformerKind::GeneralizedCoherenceFactor { m0: 1 },
+        ));
+        let mv = lateral_profile(&beamform(
+            &data,
+            &time,
+            &xd,
+            BeamformerKind::MinimumVariance {
+                subaperture: N_PROBE_CHANNELS / 2,
+                diagonal_loading: 0.01,
+            },
+        ));
+
+        assert_eq!(das.argmax().unwrap(), N_BEAMS / 2);
+        assert!(clutter_db(&cf) < clutter_db(&das) - 6.0);
+        assert!(clutter_db(&gcf) < clutter_db(&das));
+        assert!(clutter_db(&gcf) > clutter_db(&cf));
+        assert!(resolution(&mv) < resolution(&das));
+    }
+}
@@ -0,0 +1,183 @@
+use image::{DynamicImage, GrayImage, Rgba as RgbaPixel, RgbaImage};
+
+use crate::ImageError;
+
+#[derive(Debug, Clone, Copy, PartialEq, uniffi::Record)]
+pub struct Rgba {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+    pub a: u8,
+}
+
+#[derive(Debug, Clone, PartialEq, Default, uniffi::Enum)]
+pub enum Colormap {
+    /// Plain 8-bit grayscale, encoded without a color channel.
+    #[default]
+    Gray,
+    /// Warm sepia/bronze tint of the grayscale ramp.
+    Sepia,
+    /// Black through red and yellow to white.
+    Hot,
+    /// Perceptually uniform blue-green-yellow map.
+    Viridis,
+    /// Perceptually uniform black-purple-yellow map.
+    Inferno,
+    /// User-supplied lookup table of exactly 256 entries.
+    Custom { lut: Vec<Rgba> },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, uniffi::Enum)]
+pub enum PixelFormat {
+    Gray8,
+    Rgba8,
+}
+
+// Polynomial fits of the matplotlib maps, coefficients c0..c6 per channel.
+const VIRIDIS: [[f64; 3]; 7] = [
+    [0.2777273272234177, 0.005407344544966578, 0.3340998053353061],
+    [0.1050930431085774, 1.404613529898575, 1.384590162594685],
+    [-0.3308618287255563, 0.214847559468213, 0.09509516302823659],
+    [-4.634230498983486, -5.799100973351585, -19.33244095627987],
+    [6.228269936347081, 14.17993336680509, 56.69055260068105],
+    [4.776384997670288, -13.74514537774601, -65.35303263337234],
+    [-5.435455855934631, 4.645852612178535, 26.3124352495832],
+];
+
+const INFERNO: [[f64; 3]; 7] = [
+    [
+        0.0002189403691192265,
+        0.001651004631001012,
+        -0.01948089843709184,
+    ],
+    [0.1065134194856116, 0.5639564367884091, 3.932712388889277],
+    [11.60249308247187, -3.972853965665698, -15.9423941062914],
+    [-41.70399613139459, 17.43639888205313, 44.35414519872813],
+    [77.162935699427, -33.40235894210092, -81.80730925738993],
+    [-71.31942824499214, 32.62606426397723, 73.20951985803202],
+    [25.13112622477341, -12.24266895238567, -23.07032500287172],
+];
+
+fn polynomial(coeffs: &[[f64; 3]; 7], t: f64) -> [f64; 3] {
+    let mut rgb = [0.0; 3];
+    for (c, value) in rgb.iter_mut().enumerate() {
+        *value = coeffs.iter().rev().fold(0.0, |acc, k| acc * t + k[c]);
+    }
+    rgb
+}
+
+fn to_rgba(rgb: [f64; 3]) -> Rgba {
+    let channel = |v: f64| (v.clamp(0.0, 1.0) * 255.0).round() as u8;
+    Rgba {
+        r: channel(rgb[0]),
+        g: channel(rgb[1]),
+        b: channel(rgb[2]),
+        a: 255,
+    }
+}
+
+impl Colormap {
+    pub fn validate(&self) -> Result<(), ImageError> {
+        match self {
+            Colormap::Custom { lut } if lut.len() != 256 => Err(ImageError::InvalidData(format!(
+                "Custom lookup table must have 256 entries, got {}",
+                lut.len()
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Color of gray level `level`.
+    pub fn color(&self, level: u8) -> Rgba {
+        let t = level as f64 / 255.0;
+        match self {
+            Colormap::Gray => to_rgba([t, t, t]),
+            Colormap::Sepia => to_rgba([1.351 * t, 1.203 * t, 0.937 * t]),
+            Colormap::Hot => to_rgba([3.0 * t, 3.0 * t - 1.0, 3.0 * t - 2.0]),
+            Colormap::Viridis => to_rgba(polynomial(&VIRIDIS, t)),
+            Colormap::Inferno => to_rgba(polynomial(&INFERNO, t)),
+            Colormap::Custom { lut } => lut[level as usize],
+        }
+    }
+
+    pub fn lut(&self) -> [Rgba; 256] {
+        std::array::from_fn(|i| self.color(i as u8))
+    }
+
+    /// Color a grayscale image. `Gray` keeps the single-channel image.
+    pub fn apply(&self, img: GrayImage) -> DynamicImage {
+        if *self == Colormap::Gray {
+            return DynamicImage::ImageLuma8(img);
+        }
+        let lut = self.lut();
+        let rgba = RgbaImage::from_fn(img.width(), img.height(), |x, y| {
+            let c = lut[img.get_pixel(x, y)[0] as usize];
+            RgbaPixel([c.r, c.g, c.b, c.a])
+        });
+        DynamicImage::ImageRgba8(rgba)
+    }
+}
+
+/// Alpha-blend `overlay` on top of `base` (same size), e.g. a Doppler or strain map over B-mode.
+pub fn composite(base: &RgbaImage, overlay: &RgbaImage) -> RgbaImage {
+    RgbaImage::from_fn(base.width(), base.height(), |x, y| {
+        let b = base.get_pixel(x, y);
+        let o = overlay.get_pixel(x, y);
+        let alpha = o[3] as f64 / 255.0;
+        let mix = |c: usize| (o[c] as f64 * alpha + b[c] as f64 * (1.0 - alpha)).round() as u8;
+        RgbaPixel([mix(0), mix(1), mix(2), b[3].max(o[3])])
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(c: Rgba, rgb: [u8; 3]) -> bool {
+        [c.r, c.g, c.b]
+            .iter()
+            .zip(rgb.iter())
+            .all(|(a, b)| a.abs_diff(*b) <= 6)
+    }
+
+    #[test]
+    fn builtin_maps_match_reference_endpoints() {
+        assert!(close(Colormap::Gray.color(128), [128, 128, 128]));
+        assert!(close(Colormap::Hot.color(0), [0, 0, 0]));
+        assert!(close(Colormap::Hot.color(255), [255, 255, 255]));
+        // matplotlib viridis and inferno end points
+        assert!(close(Colormap::Viridis.color(0), [68, 1, 84]));
+        assert!(close(Colormap::Viridis.color(255), [253, 231, 37]));
+        assert!(close(Colormap::Inferno.color(0), [0, 0, 4]));
+        assert!(close(Colormap::Inferno.color(255), [252, 255, 164]));
+    }
+
+    #[test]
+    fn custom_lut_is_validated_and_applied() {
+        assert!(Colormap::Custom { lut: vec![] }.validate().is_err());
+
+        let lut: Vec<Rgba> = (0..=255)
+            .map(|i| Rgba {
+                r: 255 - i,
+                g: i,
+                b: 0,
+                a: 255,
+            })
+            .collect();
+        let colormap = Colormap::Custom { lut };
+        assert!(colormap.validate().is_ok());
+
+        let gray = GrayImage::from_raw(2, 1, vec![0, 255]).unwrap();
+        let rgba = colormap.apply(gray).to_rgba8();
+        assert_eq!(rgba.get_pixel(0, 0).0, [255, 0, 0, 255]);
+        assert_eq!(rgba.get_pixel(1, 0).0, [0, 255, 0, 255]);
+    }
+
+    #[test]
+    fn composite_blends_by_overlay_alpha() {
+        let base = RgbaImage::from_pixel(1, 1, RgbaPixel([0, 0, 200, 255]));
+        let overlay = RgbaImage::from_pixel(1, 1, RgbaPixel([200, 0, 0, 128]));
+        let out = composite(&base, &overlay);
+        assert_eq!(out.get_pixel(0, 0).0, [100, 0, 100, 255]);
+    }
+}
@@ -9,3 +9,7 @@ pub const ARRAY_PITCH: f64 = 2.0 * 1.8519e-4;
 pub const REC_LEN: u32 = 1585;
 pub const UPSAMP_FACT: u32 = 4;
 pub const DECIM_FACT: u32 = 8;
+pub const DYNAMIC_RANGE: f64 = 35.0;
+pub const TGC_GAIN: f64 = 8.686;
+pub const HARMONIC_FILTER_TAPS: usize = 801;
+pub const IQ_FILTER_TAPS: usize = 255;
@@ -0,0 +1,204 @@
+use ndarray::{Array1, Array2, Axis};
+
+use crate::constants::*;
+
+/// Minimum and maximum dynamic range (dB) the auto-optimizer may choose.
+const MIN_DYNAMIC_RANGE: f64 = 20.0;
+const MAX_DYNAMIC_RANGE: f64 = 80.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, uniffi::Record)]
+pub struct AutoOptimize {
+    /// Envelope percentile (0-100) mapped to black.
+    pub low_percentile: f64,
+    /// Envelope percentile (0-100) mapped to white.
+    pub high_percentile: f64,
+    /// Number of depth zones for automatic TGC, 0 to disable it.
+    pub tgc_zones: u32,
+}
+
+impl Default for AutoOptimize {
+    fn default() -> Self {
+        Self {
+            low_percentile: 5.0,
+            high_percentile: 99.0,
+            tgc_zones: 0,
+        }
+    }
+}
+
+/// Display parameters an image was rendered with.
+#[derive(Debug, Clone, PartialEq, uniffi::Record)]
+pub struct DisplayParameters {
+    /// Gain (dB) relative to normalizing the frame maximum to white.
+    pub gain_db: f64,
+    pub dynamic_range_db: f64,
+    /// TGC gains (dB) at the centres of equal depth zones from the top to the
+    /// bottom of the image, linearly interpolated in between. Empty if no TGC
+    /// was applied.
+    pub tgc_db: Vec<f64>,
+}
+
+impl DisplayParameters {
+    /// Parameters of the fixed `log_compress` mapping.
+    pub fn fixed(tgc_db: Vec<f64>) -> Self {
+        Self {
+            gain_db: 0.0,
+            dynamic_range_db: DYNAMIC_RANGE,
+            tgc_db,
+        }
+    }
+}
+
+/// TGC gains describing a linear ramp from 0 dB at the top of the image to
+/// `gain_db` at the bottom, as applied by `processing::apply_tgc`.
+pub fn ramp_tgc(gain_db: f64) -> Vec<f64> {
+    vec![gain_db / 4.0, 3.0 * gain_db / 4.0]
+}
+
+/// Percentile (0-100) of `values` with linear interpolation between ranks.
+pub fn percentile(values: &mut [f64], p: f64) -> f64 {
+    if values.is_empty() {
+        return 0.0;
+    }
+    values.sort_by(|a, b| a.total_cmp(b));
+    let rank = (p / 100.0).clamp(0.0, 1.0) * (values.len() - 1) as f64;
+    let (lo, hi) = (rank.floor() as usize, rank.ceil() as usize);
+    values[lo] + (values[hi] - values[lo]) * (rank - lo as f64)
+}
+
+fn to_db(values: impl Iterator<Item = f64>) -> Vec<f64> {
+    values
+        .filter(|&v| v > 0.0)
+        .map(|v| 20.0 * v.log10())
+        .collect()
+}
+
+/// Per-zone gains (dB) that bring the median level of every depth zone to
+/// the median level of the whole frame.
+pub fn depth_tgc(envelope: &Array2<f64>, depth_axis: Axis, zones: usize) -> Vec<f64> {
+    let depth = envelope.len_of(depth_axis);
+    let zones = zones.clamp(1, depth.max(1));
+    let target = percentile(&mut to_db(envelope.iter().copied()), 50.0);
+
+    (0..zones)
+        .map(|z| {
+            let (start, end) = (z * depth / zones, (z + 1) * depth / zones);
+            let mut zone_db =
+                to_db((start..end).flat_map(|i| envelope.index_axis(depth_axis, i).to_vec()));
+            if zone_db.is_empty() {
+                0.0
+            } else {
+                target - percentile(&mut zone_db, 50.0)
+            }
+        })
+        .collect()
+}
+
+/// Apply a TGC curve given at evenly spaced depths, interpolating per sample.
+pub fn apply_tgc_curve(envelope: &Array2<f64>, depth_axis: Axis, tgc_db: &[f64]) -> Array2<f64> {
+    if tgc_db.is_empty() {
+        return envelope.clone();
+    }
+    let depth = envelope.len_of(depth_axis);
+    let gains = Array1::from_shape_fn(depth, |i| {
+        if tgc_db.len() == 1 {
+            return tgc_db[0];
+        }
+        // zone gains sit at the zone centres, extrapolated linearly beyond the outer ones
+        let pos = (i as f64 + 0.5) / depth as f64 * tgc_db.len() as f64 - 0.5;
+        let k = (pos.max(0.0).floor() as usize).min(tgc_db.len() - 2);
+        tgc_db[k] + (tgc_db[k + 1] - tgc_db[k]) * (pos - k as f64)
+    });
+
+    let mut out = envelope.clone();
+    for (i, mut lane) in out.axis_iter_mut(depth_axis).enumerate() {
+        lane *= 10f64.powf(gains[i] / 20.0);
+    }
+    out
+}
+
+/// Choose gain and dynamic range from the envelope histogram (and optionally a
+/// depth-wise TGC), returning the compressed image in [0, 1] with the parameters used.
+pub fn auto_optimize(
+    envelope: &Array2<f64>,
+    depth_axis: Axis,
+    options: AutoOptimize,
+) -> (Array2<f64>, DisplayParameters) {
+    let tgc_db = if options.tgc_zones > 0 {
+        depth_tgc(envelope, depth_axis, options.tgc_zones as usize)
+    } else {
+        Vec::new()
+    };
+    let envelope = apply_tgc_curve(envelope, depth_axis, &tgc_db);
+
+    let mut env_db = to_db(envelope.iter().copied());
+    let max_db = env_db.iter().copied().fold(f64::MIN, f64::max);
+    let white = percentile(&mut env_db, options.high_percentile);
+    let black = percentile(&mut env_db, options.low_percentile);
+    let dr = (white - black).clamp(MIN_DYNAMIC_RANGE, MAX_DYNAMIC_RANGE);
+
+    let img = envelope.mapv(|x| {
+        let db = 20.0 * x.log10() - white;
+        (db.clamp(-dr, 0.0) + dr) / dr
+    });
+
+    let params = DisplayParameters {
+        gain_db: if env_db.is_empty() {
+            0.0
+        } else {
+            max_db - white
+        },
+        dynamic_range_db: dr,
+        tgc_db,
+    };
+    (img, params)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::iq2img::log_compress;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    fn speckle(lines: usize, depth: usize) -> Array2<f64> {
+        let mut rng = StdRng::seed_from_u64(32);
+        Array2::from_shape_fn((lines, depth), |_| 0.1 + rng.r#gen::<f64>())
+    }
+
+    fn median(img: &Array2<f64>) -> f64 {
+        percentile(&mut img.iter().copied().collect::<Vec<_>>(), 50.0)
+    }
+
+    #[test]
+    fn bright_reflector_does_not_darken_frame() {
+        let mut env = speckle(32, 512);
+        env[[16, 100]] = 1e4;
+
+        let fixed = log_compress(&env, DYNAMIC_RANGE);
+        let (auto, params) = auto_optimize(&env, Axis(1), AutoOptimize::default());
+
+        assert!(median(&fixed) < 0.1);
+        assert!(median(&auto) > 0.5 && median(&auto) < 0.95);
+        assert!(params.gain_db > 60.0);
+        assert!(params.dynamic_range_db >= MIN_DYNAMIC_RANGE);
+    }
+
+    #[test]
+    fn depth_tgc_equalizes_attenuation() {
+        // 0.5 dB per sample of round-trip attenuation
+        let env =
+            speckle(32, 128) * Array1::from_shape_fn(128, |i| 10f64.powf(-0.5 * i as f64 / 20.0));
+        let options = AutoOptimize {
+            tgc_zones: 8,
+            ..Default::default()
+        };
+        let (img, params) = auto_optimize(&env, Axis(1), options);
+
+        assert_eq!(params.tgc_db.len(), 8);
+        assert!(params.tgc_db.windows(2).all(|w| w[1] > w[0]));
+        let top = median(&img.slice(ndarray::s![.., ..16]).to_owned());
+        let bottom = median(&img.slice(ndarray::s![.., 112..]).to_owned());
+        assert!((top - bottom).abs() < 0.1);
+    }
+}
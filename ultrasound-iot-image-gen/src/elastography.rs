@@ -0,0 +1,427 @@
+use image::{Rgba as RgbaPixel, RgbaImage};
+use ndarray::{Array1, Array2, ArrayView1, Axis, s};
+use ndarray_linalg::c64;
+use rayon::prelude::*;
+
+use crate::ImageError;
+use crate::colormap::Colormap;
+use crate::display::percentile;
+use crate::iq2img::{analytic, scan_convert};
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, uniffi::Enum)]
+pub enum DisplacementEstimator {
+    /// Peak of the windowed normalized cross-correlation, refined by parabolic interpolation.
+    #[default]
+    NormalizedCrossCorrelation,
+    /// Phase of the windowed zero-lag correlation of the analytic signals.
+    Phase,
+}
+
+#[derive(Debug, Clone, PartialEq, uniffi::Record)]
+pub struct StrainOptions {
+    pub estimator: DisplacementEstimator,
+    /// Correlation window length in RF samples.
+    pub window: u32,
+    /// Distance between consecutive windows in RF samples.
+    pub step: u32,
+    /// Largest lag searched by cross-correlation around the previous window's estimate, in RF samples.
+    pub search: u32,
+    /// Number of displacement estimates in the least-squares strain kernel.
+    pub kernel: u32,
+    /// Overlay palette from low (hard) to high (soft) strain.
+    pub colormap: Colormap,
+    /// Overlay opacity in [0, 1].
+    pub opacity: f64,
+}
+
+impl Default for StrainOptions {
+    fn default() -> Self {
+        Self {
+            estimator: DisplacementEstimator::default(),
+            window: 256,
+            step: 64,
+            search: 32,
+            kernel: 9,
+            colormap: Colormap::Viridis,
+            opacity: 0.5,
+        }
+    }
+}
+
+impl StrainOptions {
+    pub fn validate(&self) -> Result<(), ImageError> {
+        if self.window < 2 || self.step == 0 || self.kernel < 2 {
+            return Err(ImageError::InvalidData(format!(
+                "Strain window ({}) and kernel ({}) must be at least 2 and step ({}) positive",
+                self.window, self.kernel, self.step
+            )));
+        }
+        if !(0.0..=1.0).contains(&self.opacity) {
+            return Err(ImageError::InvalidData(format!(
+                "Strain overlay opacity must be in [0, 1], got {}",
+                self.opacity
+            )));
+        }
+        self.colormap.validate()
+    }
+}
+
+/// Start samples of the correlation windows along a line of `depth` samples.
+pub fn window_starts(depth: usize, window: usize, step: usize) -> Vec<usize> {
+    if depth < window {
+        return Vec::new();
+    }
+    (0..=depth - window).step_by(step).collect()
+}
+
+fn ncc_displacement(
+    pre: ArrayView1<f64>,
+    post: ArrayView1<f64>,
+    start: usize,
+    window: usize,
+    center: isize,
+    search: isize,
+) -> f64 {
+    let a = pre.slice(s![start..start + window]);
+    let energy_a = a.dot(&a);
+
+    let ncc = |lag: isize| {
+        let begin = start as isize + lag;
+        if begin < 0 || begin as usize + window > post.len() {
+            return None;
+        }
+        let b = post.slice(s![begin as usize..begin as usize + window]);
+        let denom = (energy_a * b.dot(&b)).sqrt();
+        Some(if denom > 0.0 { a.dot(&b) / denom } else { 0.0 })
+    };
+
+    let mut best = (center, f64::MIN);
+    for lag in center - search..=center + search {
+        if let Some(rho) = ncc(lag)
+            && rho > best.1
+        {
+            best = (lag, rho);
+        }
+    }
+    let (lag, peak) = best;
+    if peak == f64::MIN {
+        return center as f64;
+    }
+
+    // sub-sample peak from a parabola through the neighbouring lags
+    match (ncc(lag - 1), ncc(lag + 1)) {
+        (Some(left), Some(right)) => {
+            let curvature = left - 2.0 * peak + right;
+            if curvature < 0.0 {
+                lag as f64 + 0.5 * (left - right) / curvature
+            } else {
+                lag as f64
+            }
+        }
+        _ => lag as f64,
+    }
+}
+
+fn phase_displacement(
+    pre: &Array1<c64>,
+    post: &Array1<c64>,
+    start: usize,
+    window: usize,
+    offset: isize,
+    omega: f64,
+) -> f64 {
+    let begin = (start as isize + offset).clamp(0, (post.len() - window) as isize);
+    let offset = begin - start as isize;
+    let correlation = pre
+        .slice(s![start..start + window])
+        .iter()
+        .zip(post.slice(s![begin as usize..begin as usize + window]))
+        .fold(c64::new(0.0, 0.0), |acc, (a, b)| acc + a * b.conj());
+    offset as f64 + correlation.arg() / omega
+}
+
+fn analytic_line(line: ArrayView1<f64>) -> Array1<c64> {
+    analytic(&line.to_owned(), line.len())
+}
+
+/// Axial displacement (RF samples, positive away from the probe) of every window
+/// of every line between two beamformed (lines, depth) RF frames. Each window is
+/// searched around the estimate of the window above it so that displacements
+/// larger than the search range or half a wavelength are tracked down the line.
+/// Returns the displacements and the window centres in samples.
+pub fn displacement(
+    pre: &Array2<f64>,
+    post: &Array2<f64>,
+    center_frequency: f64,
+    sample_rate: f64,
+    options: &StrainOptions,
+) -> (Array2<f64>, Array1<f64>) {
+    let window = options.window as usize;
+    let starts = window_starts(pre.len_of(Axis(1)), window, options.step as usize);
+    let centres = Array1::from_iter(starts.iter().map(|&s| s as f64 + (window - 1) as f64 / 2.0));
+    let omega = 2.0 * std::f64::consts::PI * center_frequency / sample_rate;
+
+    let lines: Vec<Array1<f64>> = (0..pre.len_of(Axis(0)))
+        .into_par_iter()
+        .map(|n| {
+            let (a, b) = (pre.row(n), post.row(n));
+            let analytic_pair = match options.estimator {
+                DisplacementEstimator::Phase => Some((analytic_line(a), analytic_line(b))),
+                DisplacementEstimator::NormalizedCrossCorrelation => None,
+            };
+
+            let mut previous = 0.0_f64;
+            Array1::from_iter(starts.iter().map(|&start| {
+                let center = previous.round() as isize;
+                previous = match &analytic_pair {
+                    Some((a, b)) => phase_displacement(a, b, start, window, center, omega),
+                    None => ncc_displacement(a, b, start, window, center, options.search as isize),
+                };
+                previous
+            }))
+        })
+        .collect();
+
+    let mut out = Array2::zeros((lines.len(), starts.len()));
+    for (n, line) in lines.into_iter().enumerate() {
+        out.row_mut(n).assign(&line);
+    }
+    (out, centres)
+}
+
+/// Axial strain as the least-squares slope of displacement against depth over
+/// `kernel` consecutive windows (truncated at the ends of the line).
+pub fn least_squares_strain(
+    displacement: &Array2<f64>,
+    centres: &Array1<f64>,
+    kernel: usize,
+) -> Array2<f64> {
+    let windows = centres.len();
+    let half = kernel / 2;
+    Array2::from_shape_fn(displacement.raw_dim(), |(n, k)| {
+        let (lo, hi) = (k.saturating_sub(half), (k + kernel - half).min(windows));
+        let z = centres.slice(s![lo..hi]);
+        let d = displacement.slice(s![n, lo..hi]);
+        let (z_mean, d_mean) = (z.mean().unwrap(), d.mean().unwrap());
+        let num: f64 = z
+            .iter()
+            .zip(d)
+            .map(|(z, d)| (z - z_mean) * (d - d_mean))
+            .sum();
+        let den: f64 = z.iter().map(|z| (z - z_mean).powi(2)).sum();
+        if den > 0.0 { num / den } else { 0.0 }
+    })
+}
+
+/// Interpolate per-window strain onto every depth sample. Returns the strain and
+/// a mask that is 1 between the first and last window centres and 0 elsewhere.
+pub fn strain_to_samples(
+    strain: &Array2<f64>,
+    centres: &Array1<f64>,
+    depth: usize,
+) -> (Array2<f64>, Array2<f64>) {
+    let lines = strain.len_of(Axis(0));
+    let mut values = Array2::zeros((lines, depth));
+    let mut mask = Array2::zeros((lines, depth));
+    if centres.is_empty() {
+        return (values, mask);
+    }
+
+    let (first, last) = (centres[0], centres[centres.len() - 1]);
+    for i in 0..depth {
+        let z = i as f64;
+        if z < first || z > last {
+            continue;
+        }
+        let k = centres
+            .iter()
+            .position(|&c| c >= z)
+            .unwrap_or(centres.len() - 1);
+        let (k0, k1) = (k.saturating_sub(1), k);
+        let frac = if k1 > k0 {
+            (z - centres[k0]) / (centres[k1] - centres[k0])
+        } else {
+            0.0
+        };
+        for n in 0..lines {
+            values[[n, i]] = strain[[n, k0]] + (strain[[n, k1]] - strain[[n, k0]]) * frac;
+            mask[[n, i]] = 1.0;
+        }
+    }
+    (values, mask)
+}
+
+/// Scan convert a (lines, depth) strain map and its mask like the B-mode image.
+/// `scan_convert` resamples through 8-bit gray levels, so the strain is carried
+/// through it as [0, 1] around its largest magnitude and mapped back after.
+pub fn scan_convert_strain(
+    strain: &Array2<f64>,
+    mask: &Array2<f64>,
+    x: &Array1<f64>,
+    z: &Array1<f64>,
+) -> (Array2<f64>, Array2<f64>) {
+    let scale = strain
+        .iter()
+        .fold(0.0, |max: f64, s| max.max(s.abs()))
+        .max(f64::MIN_POSITIVE);
+    let (strain_sc, _, _) = scan_convert(&strain.mapv(|s| 0.5 + 0.5 * s / scale), x, z);
+    let (mask_sc, _, _) = scan_convert(mask, x, z);
+    (
+        strain_sc.mapv(|v| (v / 255.0 - 0.5) * 2.0 * scale),
+        mask_sc / 255.0,
+    )
+}
+
+/// Color scan-converted (rows, columns) strain magnitude where `mask` is set,
+/// scaled so that the 98th percentile of the covered strain saturates the palette.
+pub fn strain_overlay(
+    strain: &Array2<f64>,
+    mask: &Array2<f64>,
+    options: &StrainOptions,
+) -> RgbaImage {
+    let mut covered: Vec<f64> = strain
+        .iter()
+        .zip(mask)
+        .filter(|&(_, &m)| m >= 0.5)
+        .map(|(s, _)| s.abs())
+        .collect();
+    let scale = percentile(&mut covered, 98.0).max(f64::MIN_POSITIVE);
+    let lut = options.colormap.lut();
+    let alpha = (options.opacity * 255.0).round() as u8;
+
+    let (rows, cols) = strain.dim();
+    RgbaImage::from_fn(cols as u32, rows as u32, |x, y| {
+        let (x, y) = (x as usize, y as usize);
+        if mask[[y, x]] < 0.5 {
+            return RgbaPixel([0, 0, 0, 0]);
+        }
+        let level = (strain[[y, x]].abs() / scale * 255.0)
+            .round()
+            .clamp(0.0, 255.0) as usize;
+        let c = lut[level];
+        RgbaPixel([c.r, c.g, c.b, alpha])
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    /// One RF line of random scatterers, compressed by `strain` above sample
+    /// `boundary` and `strain_deep` below it.
+    fn rf_pair(
+        depth: usize,
+        boundary: f64,
+        strain: f64,
+        strain_deep: f64,
+    ) -> (Array2<f64>, Array2<f64>) {
+        let fs = SAMPLE_RATE * UPSAMP_FACT as f64;
+        let period = fs / TRANSMIT_FREQ;
+        let mut rng = StdRng::seed_from_u64(34);
+        let scatterers: Vec<(f64, f64)> = (0..depth / 8)
+            .map(|_| (rng.r#gen::<f64>() * depth as f64, rng.r#gen::<f64>() - 0.5))
+            .collect();
+        let moved = |z: f64| {
+            if z < boundary {
+                z * (1.0 + strain)
+            } else {
+                boundary * (1.0 + strain) + (z - boundary) * (1.0 + strain_deep)
+            }
+        };
+        let line = |shift: &dyn Fn(f64) -> f64| {
+            Array2::from_shape_fn((1, depth), |(_, n)| {
+                scatterers
+                    .iter()
+                    .map(|&(z, a)| {
+                        let t = n as f64 - shift(z);
+                        a * (-(t / period).powi(2) / 2.0).exp()
+                            * (2.0 * std::f64::consts::PI * t / period).cos()
+                    })
+                    .sum()
+            })
+        };
+        (line(&|z| z), line(&moved))
+    }
+
+    fn median_strain(strain: &Array2<f64>, centres: &Array1<f64>, lo: f64, hi: f64) -> f64 {
+        let mut values: Vec<f64> = strain
+            .iter()
+            .zip(centres)
+            .filter(|&(_, &c)| c > lo && c < hi)
+            .map(|(s, _)| *s)
+            .collect();
+        percentile(&mut values, 50.0)
+    }
+
+    #[test]
+    fn estimators_recover_layered_strain() {
+        let depth = 4000;
+        let (pre, post) = rf_pair(depth, 2000.0, -0.005, -0.015);
+        let fs = SAMPLE_RATE * UPSAMP_FACT as f64;
+
+        for estimator in [
+            DisplacementEstimator::NormalizedCrossCorrelation,
+            DisplacementEstimator::Phase,
+        ] {
+            let options = StrainOptions {
+                estimator,
+                ..Default::default()
+            };
+            let (disp, centres) = displacement(&pre, &post, TRANSMIT_FREQ, fs, &options);
+            let strain = least_squares_strain(&disp, &centres, options.kernel as usize);
+
+            let shallow = median_strain(&strain, &centres, 300.0, 1700.0);
+            let deep = median_strain(&strain, &centres, 2300.0, 3700.0);
+            assert!(
+                (shallow + 0.005).abs() < 0.001,
+                "{estimator:?} shallow {shallow}"
+            );
+            assert!((deep + 0.015).abs() < 0.002, "{estimator:?} deep {deep}");
+        }
+    }
+
+    #[test]
+    fn overlay_separates_stiff_and_soft_regions() {
+        // stiff (-0.2 %) above soft (-1 %) tissue, on the beamformer's grids
+        let (lines, depth) = (16, 2400);
+        let fs = SAMPLE_RATE * UPSAMP_FACT as f64;
+        let x = Array1::range(0.0, lines as f64, 1.0) * ARRAY_PITCH;
+        let z = Array1::range(0.0, depth as f64, 1.0) / fs * SPEED_SOUND / 2.0;
+        let strain =
+            Array2::from_shape_fn(
+                (lines, depth),
+                |(_, i)| {
+                    if i < depth / 2 { -0.002 } else { -0.01 }
+                },
+            );
+        let mask = Array2::ones((lines, depth));
+
+        let (strain_sc, mask_sc) = scan_convert_strain(&strain, &mask, &x, &z);
+        let (strain_sc, mask_sc) = (strain_sc.t().to_owned(), mask_sc.t().to_owned());
+        let (rows, cols) = strain_sc.dim();
+        let (stiff, soft) = ([rows / 4, cols / 2], [3 * rows / 4, cols / 2]);
+        assert!((strain_sc[stiff] + 0.002).abs() < 2e-4);
+        assert!((strain_sc[soft] + 0.01).abs() < 2e-4);
+
+        let overlay = strain_overlay(&strain_sc, &mask_sc, &StrainOptions::default());
+        let pixel = |[r, c]: [usize; 2]| *overlay.get_pixel(c as u32, r as u32);
+        assert_eq!(pixel(stiff)[3], pixel(soft)[3]);
+        assert_ne!(pixel(stiff), pixel(soft));
+    }
+
+    #[test]
+    fn strain_is_interpolated_between_window_centres() {
+        let centres = Array1::from(vec![10.0, 20.0]);
+        let strain = Array2::from_shape_vec((1, 2), vec![0.0, 1.0]).unwrap();
+        let (values, mask) = strain_to_samples(&strain, &centres, 30);
+
+        assert_eq!(mask[[0, 5]], 0.0);
+        assert_eq!(mask[[0, 25]], 0.0);
+        assert_eq!(mask[[0, 15]], 1.0);
+        assert!((values[[0, 15]] - 0.5).abs() < 1e-12);
+        assert!(StrainOptions::default().validate().is_ok());
+    }
+}
@@ -1,25 +1,27 @@
 use std::path::Path;
 
 use image::{GrayImage, imageops::FilterType};
-use ndarray::{Array1, Array2, Array3, Zip, s};
-use ndarray_linalg::{Scalar, c64};
+use ndarray::{Array1, Array2, Array3, Axis, Zip, s};
 use ndarray_stats::QuantileExt;
+use num_complex::Complex;
 
 use rustfft::FftPlanner;
 use tracing::info;
 
 use crate::constants::*;
+use crate::real::Real;
+use crate::resample::Resampler;
 
-fn fft_priv(x: &Array1<c64>, n: usize, inverse: bool) -> Array1<c64> {
+fn fft_priv<T: Real>(x: &Array1<Complex<T>>, n: usize, inverse: bool) -> Array1<Complex<T>> {
     // Copy input into a mutable buffer; pad with zeros if necessary.
-    let mut buffer: Vec<c64> = {
+    let mut buffer: Vec<Complex<T>> = {
         let mut vec = x.to_vec();
-        vec.resize(n, c64::new(0.0, 0.0));
+        vec.resize(n, Complex::new(T::zero(), T::zero()));
         vec
     };
 
     // Create the FFT planner.
-    let mut planner = FftPlanner::<f64>::new();
+    let mut planner = FftPlanner::<T>::new();
     let fft = if inverse {
         planner.plan_fft_inverse(n)
     } else {
@@ -31,7 +33,7 @@ fn fft_priv(x: &Array1<c64>, n: usize, inverse: bool) -> Array1<c64> {
 
     // If performing an inverse FFT, normalize the result.
     if inverse {
-        let scale = 1.0 / n as f64;
+        let scale = T::one() / T::cast(n as f64);
         for v in buffer.iter_mut() {
             *v = *v * scale;
         }
@@ -39,27 +41,33 @@ fn fft_priv(x: &Array1<c64>, n: usize, inverse: bool) -> Array1<c64> {
     Array1::from(buffer)
 }
 
-pub fn fft(x: &Array1<c64>, n: usize) -> Array1<c64> {
+pub fn fft<T: Real>(x: &Array1<Complex<T>>, n: usize) -> Array1<Complex<T>> {
     fft_priv(x, n, false)
 }
 
-pub fn ifft(x: &Array1<c64>) -> Array1<c64> {
+pub fn ifft<T: Real>(x: &Array1<Complex<T>>) -> Array1<Complex<T>> {
     fft_priv(x, x.len(), true)
 }
 
-pub fn array_indexing_1d(x: &Array1<f64>, ind: &Array1<usize>) -> Array1<f64> {
+pub fn array_indexing_1d<T: Copy>(x: &Array1<T>, ind: &Array1<usize>) -> Array1<T> {
     Zip::from(ind).map_collect(|idx| x[*idx])
 }
 
-pub fn beamform_df(data: &Array3<f64>, time: &Array1<f64>, xd: &Array1<f64>) -> Array2<f64> {
+pub fn propagation_delay_indices(time: &Array1<f64>, xd: &Array1<f64>) -> Array2<usize> {
+    steered_delay_indices(time, xd, 0.0)
+}
+
+pub fn steered_delay_indices(time: &Array1<f64>, xd: &Array1<f64>, angle: f64) -> Array2<usize> {
     // acoustic propagation distance from transmission to reception for each
     // element. Note: transmission is consdiered to arise from the center
-    // of the array.
+    // of the array, and the scan line is steered by `angle` (radians) from
+    // the array normal.
     let zd = time * SPEED_SOUND / 2.0;
-    let zd2 = zd.mapv(|x| x.powi(2));
+    let zd2 = zd.mapv(|x| (x * angle.cos()).powi(2));
+    let xs = zd.mapv(|x| x * angle.sin());
     let mut prop_dist = Array2::<f64>::zeros((N_PROBE_CHANNELS as usize, zd.len()));
     for r in 0..N_PROBE_CHANNELS {
-        let dist = (xd[r as usize].powi(2) + &zd2).mapv(<f64>::sqrt) + &zd;
+        let dist = ((xd[r as usize] - &xs).mapv(|x| x.powi(2)) + &zd2).mapv(<f64>::sqrt) + &zd;
         let mut slice = prop_dist.slice_mut(s![r as usize, ..]);
         slice.assign(&dist);
     }
@@ -68,19 +76,25 @@ pub fn beamform_df(data: &Array3<f64>, time: &Array1<f64>, xd: &Array1<f64>) ->
     let prop_dist_ind = (prop_dist / SPEED_SOUND * sample_rate).mapv(|x| x.round() as usize);
 
     // replace out-of-bounds indices
-    let prop_dist_ind = prop_dist_ind.mapv(|x| x.min(time.len() - 1));
+    prop_dist_ind.mapv(|x| x.min(time.len() - 1))
+}
+
+pub fn beamform_df<T: Real>(data: &Array3<T>, time: &Array1<f64>, xd: &Array1<f64>) -> Array2<T> {
+    let zd = time * SPEED_SOUND / 2.0;
+    let prop_dist_ind = propagation_delay_indices(time, xd);
 
     // beamform
-    let mut image = Array2::<f64>::zeros((N_TRANSMIT_BEAMS as usize, zd.len()));
-    for n in 0..N_TRANSMIT_BEAMS {
-        let mut scan_line = Array1::<f64>::zeros(zd.len());
+    let n_beams = data.shape()[0];
+    let mut image = Array2::<T>::zeros((n_beams, zd.len()));
+    for n in 0..n_beams {
+        let mut scan_line = Array1::<T>::zeros(zd.len());
         for m in 0..N_PROBE_CHANNELS {
-            let waveform = data.slice(s![n as usize, m as usize, ..]).into_owned();
+            let waveform = data.slice(s![n, m as usize, ..]).into_owned();
             let inds = prop_dist_ind.slice(s![m as usize, ..]).into_owned();
             let waveform_indexed = array_indexing_1d(&waveform, &inds);
             scan_line += &waveform_indexed;
         }
-        let mut image_slice = image.slice_mut(s![n as usize, ..]);
+        let mut image_slice = image.slice_mut(s![n, ..]);
         image_slice.assign(&scan_line);
     }
     return image;
@@ -135,8 +149,8 @@ pub fn scan_convert(
     x: &Array1<f64>,
     z: &Array1<f64>,
 ) -> (Array2<f64>, Array1<f64>, Array1<f64>) {
-    // decimate in depth dimensions
-    let img_decim = img.slice(s![.., ..;DECIM_FACT]).into_owned();
+    // low-pass and decimate in depth dimensions
+    let img_decim = Resampler::new(1, DECIM_FACT).apply_axis(img, Axis(1));
     let z_sc = z.slice(s![..;DECIM_FACT]).into_owned();
 
     info!("Decimated imape shape = {:?}", img_decim.shape());
@@ -166,32 +180,289 @@ pub fn img_save(img: &Array2<f64>, img_save_path: &Path) {
     imgbuf.unwrap().save(img_save_path).unwrap();
 }
 
-pub fn analytic(waveform: &Array1<f64>, nfft: usize) -> Array1<c64> {
-    // Discrete-time analytic signal
-    // This mimics scipy.signal.hilbert
-
-    let waveform = waveform.mapv(|x| c64::new(x, 0.0)); // convert to complex
+/// Discrete-time analytic signal of length `nfft` (zero padded, at least the
+/// waveform length), mimicking scipy.signal.hilbert for odd and even lengths.
+pub fn analytic<T: Real>(waveform: &Array1<T>, nfft: usize) -> Array1<Complex<T>> {
+    let nfft = nfft.max(waveform.len());
+    let waveform = waveform.mapv(|x| Complex::new(x, T::zero())); // convert to complex
     let waveform_fft = fft(&waveform, nfft);
 
-    // currently only working if nfft is even
-    let mut h1 = Array1::<f64>::ones(nfft);
-    let h2 = Array1::<f64>::ones(((nfft / 2) - 1) as usize) * 2.0;
-    let mut slice = h1.slice_mut(s![1..(nfft / 2)]);
-    slice.assign(&h2);
-    let h0 = Array1::<f64>::zeros((nfft / 2 - 1) as usize);
-    let mut slice = h1.slice_mut(s![(nfft / 2) + 1..]);
-    slice.assign(&h0);
+    // keep DC (and Nyquist for even lengths), double positive and drop negative frequencies
+    let h = Array1::from_shape_fn(nfft, |k| {
+        if k == 0 || 2 * k == nfft {
+            T::one()
+        } else if 2 * k < nfft {
+            T::cast(2.0)
+        } else {
+            T::zero()
+        }
+    });
+
+    let analytic_fft = waveform_fft * h.mapv(|x| Complex::new(x, T::zero()));
+    ifft(&analytic_fft)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, uniffi::Enum)]
+pub enum EnvelopeDetection {
+    /// Magnitude of the FFT-based analytic signal, optionally zero padded to a
+    /// power of two length for faster transforms.
+    Hilbert { pad_to_power_of_two: bool },
+    /// Magnitude of the baseband IQ signal after demodulation at the centre
+    /// frequency and low-pass filtering.
+    IqMagnitude,
+}
+
+impl Default for EnvelopeDetection {
+    fn default() -> Self {
+        EnvelopeDetection::Hilbert {
+            pad_to_power_of_two: false,
+        }
+    }
+}
+
+pub fn envelope<T: Real>(waveform: &Array1<T>) -> Array1<T> {
+    hilbert_envelope(waveform, false)
+}
+
+pub fn hilbert_envelope<T: Real>(waveform: &Array1<T>, pad_to_power_of_two: bool) -> Array1<T> {
+    let nfft = if pad_to_power_of_two {
+        waveform.len().next_power_of_two()
+    } else {
+        waveform.len()
+    };
+    let env = analytic(waveform, nfft).mapv(|x| x.norm());
+    env.slice(s![..waveform.len()]).to_owned()
+}
+
+/// Mix an RF line down to baseband at `center_frequency` and low-pass it to half
+/// the transmit frequency, giving complex IQ samples at the RF sample rate.
+pub fn iq_demodulate<T: Real>(
+    waveform: &Array1<T>,
+    center_frequency: f64,
+    sample_rate: f64,
+) -> Array1<Complex<T>> {
+    let omega = 2.0 * std::f64::consts::PI * center_frequency / sample_rate;
+    let i = Array1::from_shape_fn(waveform.len(), |n| {
+        waveform[n] * T::cast((omega * n as f64).cos())
+    });
+    let q = Array1::from_shape_fn(waveform.len(), |n| {
+        -waveform[n] * T::cast((omega * n as f64).sin())
+    });
+
+    let coeffs = fir_bandpass(IQ_FILTER_TAPS, 0.0, TRANSMIT_FREQ / 2.0, sample_rate);
+    let coeffs = (&coeffs / coeffs.sum()).mapv(T::cast);
+    let (i, q) = (
+        filter_zero_phase(&i, &coeffs),
+        filter_zero_phase(&q, &coeffs),
+    );
+    Zip::from(&i)
+        .and(&q)
+        .map_collect(|&i, &q| Complex::new(i, q))
+}
+
+/// Envelope from the IQ magnitude; the factor 2 restores the amplitude lost to the mixing image.
+pub fn iq_envelope<T: Real>(
+    waveform: &Array1<T>,
+    center_frequency: f64,
+    sample_rate: f64,
+) -> Array1<T> {
+    iq_demodulate(waveform, center_frequency, sample_rate).mapv(|x| T::cast(2.0) * x.norm())
+}
+
+pub fn detect_line_envelope<T: Real>(
+    waveform: &Array1<T>,
+    detection: EnvelopeDetection,
+    center_frequency: f64,
+    sample_rate: f64,
+) -> Array1<T> {
+    match detection {
+        EnvelopeDetection::Hilbert {
+            pad_to_power_of_two,
+        } => hilbert_envelope(waveform, pad_to_power_of_two),
+        EnvelopeDetection::IqMagnitude => iq_envelope(waveform, center_frequency, sample_rate),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, uniffi::Enum)]
+pub enum ImagingMode {
+    /// Conventional B-mode at the transmit frequency.
+    #[default]
+    Fundamental,
+    /// Tissue harmonic B-mode, band-pass filtered around 2 * TRANSMIT_FREQ.
+    Harmonic,
+}
+
+impl ImagingMode {
+    /// Centre frequency of the echoes imaged in this mode.
+    pub fn center_frequency(&self) -> f64 {
+        match self {
+            ImagingMode::Fundamental => TRANSMIT_FREQ,
+            ImagingMode::Harmonic => 2.0 * TRANSMIT_FREQ,
+        }
+    }
+}
+
+/// Hamming-windowed sinc band-pass FIR with `taps` coefficients passing `[f_lo, f_hi]`.
+pub fn fir_bandpass(taps: usize, f_lo: f64, f_hi: f64, sample_rate: f64) -> Array1<f64> {
+    let lc = f_lo / sample_rate;
+    let uc = f_hi / sample_rate;
+    let mid = (taps - 1) as f64 / 2.0;
+    let sinc = |fc: f64, n: f64| {
+        if n == 0.0 {
+            2.0 * fc
+        } else {
+            (2.0 * std::f64::consts::PI * fc * n).sin() / (std::f64::consts::PI * n)
+        }
+    };
+    Array1::from_shape_fn(taps, |i| {
+        let n = i as f64 - mid;
+        let window =
+            0.54 - 0.46 * (2.0 * std::f64::consts::PI * i as f64 / (taps - 1) as f64).cos();
+        (sinc(uc, n) - sinc(lc, n)) * window
+    })
+}
 
-    let analytic_fft = waveform_fft * h1.mapv(|x| c64::new(x, 0.0));
-    let analytic = ifft(&analytic_fft);
+/// Filter with a linear-phase FIR, compensating its group delay so the
+/// output stays aligned with the input. Convolution is done in the frequency domain.
+pub fn filter_zero_phase<T: Real>(waveform: &Array1<T>, coeffs: &Array1<T>) -> Array1<T> {
+    let n = waveform.len();
+    let nfft = (n + coeffs.len() - 1).next_power_of_two();
+    let x = fft(&waveform.mapv(|x| Complex::new(x, T::zero())), nfft);
+    let h = fft(&coeffs.mapv(|x| Complex::new(x, T::zero())), nfft);
+    let y = ifft(&(x * h));
+
+    let delay = (coeffs.len() - 1) / 2;
+    y.slice(s![delay..delay + n]).mapv(|x| x.re)
+}
+
+/// Band-pass every line of a (lines, depth) RF image around the second harmonic.
+pub fn harmonic_filter<T: Real>(rf: &Array2<T>) -> Array2<T> {
+    let sample_rate = SAMPLE_RATE * UPSAMP_FACT as f64;
+    let f2 = 2.0 * TRANSMIT_FREQ;
+    let coeffs = fir_bandpass(
+        HARMONIC_FILTER_TAPS,
+        f2 - TRANSMIT_FREQ / 2.0,
+        f2 + TRANSMIT_FREQ / 2.0,
+        sample_rate,
+    )
+    .mapv(T::cast);
+
+    let mut filtered = Array2::<T>::zeros(rf.raw_dim());
+    for n in 0..rf.shape()[0] {
+        let a_line = rf.slice(s![n, ..]).into_owned();
+        filtered
+            .slice_mut(s![n, ..])
+            .assign(&filter_zero_phase(&a_line, &coeffs));
+    }
+    filtered
+}
 
-    analytic
+/// Sum the echoes of a pulse and its inverted copy. Linear (fundamental)
+/// components cancel, leaving the even harmonics generated in tissue.
+pub fn pulse_inversion<T: Real>(positive: &Array3<T>, inverted: &Array3<T>) -> Array3<T> {
+    positive + inverted
 }
 
-pub fn envelope(waveform: &Array1<f64>) -> Array1<f64> {
-    let nfft = 6340; // length of data
-    let env = analytic(&waveform, nfft).mapv(|x| x.abs());
-    let env = env.slice(s![..waveform.len()]).to_owned();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray_linalg::c64;
+
+    fn tone(freq: f64, n: usize) -> Array1<f64> {
+        let sample_rate = SAMPLE_RATE * UPSAMP_FACT as f64;
+        Array1::from_shape_fn(n, |i| {
+            (2.0 * std::f64::consts::PI * freq * i as f64 / sample_rate).sin()
+        })
+    }
+
+    fn rms(x: &Array1<f64>) -> f64 {
+        // ignore the filter transients at both ends
+        let inner = x.slice(s![HARMONIC_FILTER_TAPS..x.len() - HARMONIC_FILTER_TAPS]);
+        inner.mapv(|v| v * v).mean().unwrap().sqrt()
+    }
+
+    #[test]
+    fn harmonic_filter_rejects_fundamental() {
+        let n = 4096;
+        let rf = ndarray::stack![
+            ndarray::Axis(0),
+            tone(TRANSMIT_FREQ, n),
+            tone(2.0 * TRANSMIT_FREQ, n)
+        ];
+        let filtered = harmonic_filter(&rf);
+
+        let fundamental = rms(&filtered.slice(s![0, ..]).into_owned());
+        let harmonic = rms(&filtered.slice(s![1, ..]).into_owned());
+        assert!((harmonic - 1.0 / 2f64.sqrt()).abs() < 0.05);
+        assert!(20.0 * (fundamental / harmonic).log10() < -40.0);
+    }
+
+    #[test]
+    fn pulse_inversion_keeps_only_even_harmonics() {
+        // weakly nonlinear propagation: y = x + a * x^2
+        let n = 4096;
+        let x = tone(TRANSMIT_FREQ, n);
+        let a = 0.1;
+        let positive = x.mapv(|v| v + a * v * v).into_shape((1, 1, n)).unwrap();
+        let inverted = x.mapv(|v| -v + a * v * v).into_shape((1, 1, n)).unwrap();
+
+        let summed = pulse_inversion(&positive, &inverted);
+        let expected = x.mapv(|v| 2.0 * a * v * v);
+        assert!(
+            summed
+                .iter()
+                .zip(expected.iter())
+                .all(|(s, e)| (s - e).abs() < 1e-12)
+        );
+
+        let rf = summed.into_shape((1, n)).unwrap();
+        let harmonic = rms(&harmonic_filter(&rf).slice(s![0, ..]).into_owned());
+        // x^2 = (1 - cos(2 w t)) / 2, so 2 a x^2 has a 2 f0 component of amplitude a
+        assert!((harmonic - a / 2f64.sqrt()).abs() < 0.01);
+    }
 
-    env
+    #[test]
+    fn analytic_signal_of_any_length() {
+        // cos -> exp(i w n) exactly when the tone is periodic in the frame
+        for n in [63, 64, 1001, 6340] {
+            let w = 2.0 * std::f64::consts::PI * 5.0 / n as f64;
+            let x = Array1::from_shape_fn(n, |i| (w * i as f64).cos());
+            let a = analytic(&x, n);
+
+            assert_eq!(a.len(), n);
+            for (i, z) in a.iter().enumerate() {
+                let expected = c64::new(0.0, w * i as f64).exp();
+                assert!((z - expected).norm() < 1e-9, "n = {n}, i = {i}");
+            }
+        }
+    }
+
+    #[test]
+    fn envelope_detectors_recover_gaussian_pulse() {
+        // Gaussian-modulated tone: envelope is the Gaussian
+        let sample_rate = SAMPLE_RATE * UPSAMP_FACT as f64;
+        let n = 3001;
+        let sigma = 3.0 * sample_rate / TRANSMIT_FREQ;
+        let gauss = Array1::from_shape_fn(n, |i| {
+            (-((i as f64 - n as f64 / 2.0) / sigma).powi(2) / 2.0).exp()
+        });
+        let rf = &gauss * &tone(TRANSMIT_FREQ, n);
+
+        for detection in [
+            EnvelopeDetection::Hilbert {
+                pad_to_power_of_two: false,
+            },
+            EnvelopeDetection::Hilbert {
+                pad_to_power_of_two: true,
+            },
+            EnvelopeDetection::IqMagnitude,
+        ] {
+            let env = detect_line_envelope(&rf, detection, TRANSMIT_FREQ, sample_rate);
+            assert_eq!(env.len(), n);
+            let error = (&env - &gauss)
+                .mapv(f64::abs)
+                .fold(0.0, |a: f64, &b| a.max(b));
+            assert!(error < 0.02, "{detection:?}: max error {error}");
+        }
+    }
 }
@@ -0,0 +1,367 @@
+use std::borrow::Cow;
+use std::sync::RwLock;
+
+use ndarray::{Array1, Array2, Array3, s};
+
+use crate::constants::N_PROBE_CHANNELS;
+use crate::real::{Precision, Real};
+use crate::uniffi_helper::{Array3Shape, Samples};
+use crate::{IQData, ImageError};
+
+/// (beams, channels, samples) frame, stored in the precision it was produced in.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Frame {
+    Single(Array3<f32>),
+    Double(Array3<f64>),
+}
+
+impl Frame {
+    fn zeros(shape: (usize, usize, usize), precision: Precision) -> Self {
+        match precision {
+            Precision::Single => Frame::Single(Array3::zeros(shape)),
+            Precision::Double => Frame::Double(Array3::zeros(shape)),
+        }
+    }
+
+    pub fn dim(&self) -> (usize, usize, usize) {
+        match self {
+            Frame::Single(data) => data.dim(),
+            Frame::Double(data) => data.dim(),
+        }
+    }
+
+    pub fn precision(&self) -> Precision {
+        match self {
+            Frame::Single(_) => Precision::Single,
+            Frame::Double(_) => Precision::Double,
+        }
+    }
+
+    /// The frame in `f32`, narrowed only if it is stored in `f64`.
+    pub fn single(&self) -> Cow<'_, Array3<f32>> {
+        match self {
+            Frame::Single(data) => Cow::Borrowed(data),
+            Frame::Double(data) => Cow::Owned(data.mapv(|x| x as f32)),
+        }
+    }
+
+    /// The frame in `f64`, widened only if it is stored in `f32`.
+    pub fn double(&self) -> Cow<'_, Array3<f64>> {
+        match self {
+            Frame::Single(data) => Cow::Owned(data.mapv(f64::from)),
+            Frame::Double(data) => Cow::Borrowed(data),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Traces {
+    frame: Frame,
+    /// Which (beam, channel) traces have been written.
+    written: Array2<bool>,
+}
+
+/// Preprocessed (beams, channels, samples) channel data owned on the Rust
+/// side. Clients hold it as an opaque handle and fill or read it in chunks of
+/// whole channel traces, so frames never cross the FFI boundary in one piece.
+#[derive(Debug, uniffi::Object)]
+pub struct IqBuffer {
+    traces: RwLock<Traces>,
+    t_interp: Array1<f64>,
+    xd: Array1<f64>,
+}
+
+#[uniffi::export]
+impl IqBuffer {
+    /// Empty buffer for `shape` (beams, channels, samples) traces sampled at
+    /// `t_interp` and received by elements at `xd`, stored in `precision`.
+    #[uniffi::constructor]
+    pub fn new(
+        shape: Array3Shape,
+        precision: Precision,
+        t_interp: Vec<f64>,
+        xd: Vec<f64>,
+    ) -> Result<Self, ImageError> {
+        let [beams, channels, samples] = shape.stride();
+        // the beamformer's delays are tabulated for the probe's channels
+        if channels != N_PROBE_CHANNELS as usize || samples == 0 {
+            return Err(ImageError::InvalidData(format!(
+                "Buffer needs {N_PROBE_CHANNELS} channels of at least one sample, got {channels} x {samples}"
+            )));
+        }
+        if t_interp.len() != samples || xd.len() != channels {
+            return Err(ImageError::InvalidData(format!(
+                "Buffer of {channels} channels x {samples} samples needs matching xd ({}) and t_interp ({})",
+                xd.len(),
+                t_interp.len()
+            )));
+        }
+        Ok(Self {
+            traces: RwLock::new(Traces {
+                frame: Frame::zeros((beams, channels, samples), precision),
+                written: Array2::from_elem((beams, channels), false),
+            }),
+            t_interp: Array1::from(t_interp),
+            xd: Array1::from(xd),
+        })
+    }
+
+    pub fn shape(&self) -> Array3Shape {
+        Array3Shape::from_usize(self.traces.read().unwrap().frame.dim())
+    }
+
+    pub fn precision(&self) -> Precision {
+        self.traces.read().unwrap().frame.precision()
+    }
+
+    pub fn t_interp(&self) -> Vec<f64> {
+        self.t_interp.to_vec()
+    }
+
+    pub fn xd(&self) -> Vec<f64> {
+        self.xd.to_vec()
+    }
+
+    /// Store consecutive channel traces of `beam`, starting at `first_channel`.
+    /// `samples` holds one or more whole traces back to back, and is converted
+    /// to the buffer precision if it differs.
+    pub fn write_channels(
+        &self,
+        beam: u32,
+        first_channel: u32,
+        samples: Samples,
+    ) -> Result<(), ImageError> {
+        let mut traces = self.traces.write().unwrap();
+        let (beam, first) = (beam as usize, first_channel as usize);
+        let count = self.chunk_channels(&traces, beam, first, samples.len())?;
+
+        let shape = (count, self.t_interp.len());
+        match &mut traces.frame {
+            Frame::Single(data) => assign_chunk(data, beam, first, shape, samples)?,
+            Frame::Double(data) => assign_chunk(data, beam, first, shape, samples)?,
+        }
+        traces
+            .written
+            .slice_mut(s![beam, first..first + count])
+            .fill(true);
+        Ok(())
+    }
+
+    /// Copy `count` consecutive channel traces of `beam`, starting at
+    /// `first_channel`, in the buffer precision.
+    pub fn read_channels(
+        &self,
+        beam: u32,
+        first_channel: u32,
+        count: u32,
+    ) -> Result<Samples, ImageError> {
+        let traces = self.traces.read().unwrap();
+        let (beam, first) = (beam as usize, first_channel as usize);
+        let count =
+            self.chunk_channels(&traces, beam, first, count as usize * self.t_interp.len())?;
+        Ok(match &traces.frame {
+            Frame::Single(data) => read_chunk(data, beam, first, count),
+            Frame::Double(data) => read_chunk(data, beam, first, count),
+        })
+    }
+
+    /// Number of (beam, channel) traces that have not been written yet.
+    pub fn missing_traces(&self) -> u32 {
+        let traces = self.traces.read().unwrap();
+        traces.written.iter().filter(|&&w| !w).count() as u32
+    }
+}
+
+impl IqBuffer {
+    /// Take ownership of an IQ frame, in its own precision, without copying its samples.
+    pub fn from_iq_data(data: IQData) -> Result<Self, ImageError> {
+        let shape = data.preproc.shape.stride();
+        let frame = match data.preproc.data {
+            Samples::Single { values } => Array3::from_shape_vec(shape, values).map(Frame::Single),
+            Samples::Double { values } => Array3::from_shape_vec(shape, values).map(Frame::Double),
+        }
+        .map_err(|e| ImageError::InvalidData(e.to_string()))?;
+        Self::from_frame(frame, data.t_interp, data.xd)
+    }
+
+    pub fn from_frame(frame: Frame, t_interp: Vec<f64>, xd: Vec<f64>) -> Result<Self, ImageError> {
+        let shape = Array3Shape::from_usize(frame.dim());
+        let buffer = Self::new(shape, frame.precision(), t_interp, xd)?;
+        {
+            let mut traces = buffer.traces.write().unwrap();
+            traces.frame = frame;
+            traces.written.fill(true);
+        }
+        Ok(buffer)
+    }
+
+    pub fn time(&self) -> &Array1<f64> {
+        &self.t_interp
+    }
+
+    pub fn element_positions(&self) -> &Array1<f64> {
+        &self.xd
+    }
+
+    /// Run `f` on the complete frame, without copying it.
+    pub fn with_frame<R>(&self, f: impl FnOnce(&Frame) -> R) -> Result<R, ImageError> {
+        let missing = self.missing_traces();
+        if missing > 0 {
+            return Err(ImageError::InvalidData(format!(
+                "IQ buffer is missing {missing} channel traces"
+            )));
+        }
+        Ok(f(&self.traces.read().unwrap().frame))
+    }
+
+    /// Number of whole traces in a chunk of `len` samples, checked against the buffer bounds.
+    fn chunk_channels(
+        &self,
+        traces: &Traces,
+        beam: usize,
+        first: usize,
+        len: usize,
+    ) -> Result<usize, ImageError> {
+        let (beams, channels, samples) = traces.frame.dim();
+        if samples == 0 || !len.is_multiple_of(samples) {
+            return Err(ImageError::InvalidData(format!(
+                "Chunk of {len} samples is not a whole number of {samples}-sample traces"
+            )));
+        }
+        let count = len / samples;
+        if beam >= beams || first + count > channels {
+            return Err(ImageError::InvalidData(format!(
+                "Channels {first}..{} of beam {beam} are outside the {beams} x {channels} buffer",
+                first + count
+            )));
+        }
+        Ok(count)
+    }
+}
+
+/// Store a checked chunk of `shape` (channels, samples) traces at `beam`, `first`.
+fn assign_chunk<T: Real>(
+    data: &mut Array3<T>,
+    beam: usize,
+    first: usize,
+    shape: (usize, usize),
+    samples: Samples,
+) -> Result<(), ImageError> {
+    let chunk = Array2::from_shape_vec(shape, T::from_samples(samples))
+        .map_err(|e| ImageError::InvalidData(e.to_string()))?;
+    data.slice_mut(s![beam, first..first + shape.0, ..])
+        .assign(&chunk);
+    Ok(())
+}
+
+fn read_chunk<T: Real>(data: &Array3<T>, beam: usize, first: usize, count: usize) -> Samples {
+    let traces = data.slice(s![beam, first..first + count, ..]);
+    T::into_samples(traces.iter().copied().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHANNELS: usize = N_PROBE_CHANNELS as usize;
+
+    fn frame() -> Array3<f32> {
+        Array3::from_shape_fn((2, CHANNELS, 8), |(b, c, i)| (b * 100 + c * 10 + i) as f32)
+    }
+
+    fn empty() -> IqBuffer {
+        IqBuffer::new(
+            Array3Shape::from_usize((2, CHANNELS, 8)),
+            Precision::Single,
+            (0..8).map(|i| i as f64).collect(),
+            vec![0.0; CHANNELS],
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn chunked_writes_assemble_frame() {
+        let expected = frame();
+        let buffer = empty();
+        assert_eq!(buffer.missing_traces(), 2 * CHANNELS as u32);
+        assert!(buffer.with_frame(|_| ()).is_err());
+
+        // half of the channels at a time
+        let half = CHANNELS / 2;
+        for beam in 0..2 {
+            for first in [0, half] {
+                let chunk = read_chunk(&expected, beam, first, half);
+                buffer
+                    .write_channels(beam as u32, first as u32, chunk)
+                    .unwrap();
+            }
+        }
+
+        assert_eq!(buffer.missing_traces(), 0);
+        assert!(
+            buffer
+                .with_frame(|f| *f == Frame::Single(expected.clone()))
+                .unwrap()
+        );
+        assert_eq!(
+            buffer.read_channels(1, 3, 1).unwrap(),
+            Samples::Single {
+                values: expected.slice(s![1, 3, ..]).to_vec()
+            }
+        );
+        assert_eq!(
+            buffer.read_channels(0, 1, 2).unwrap(),
+            read_chunk(&expected, 0, 1, 2)
+        );
+    }
+
+    #[test]
+    fn chunks_are_bounds_checked() {
+        let buffer = empty();
+        // partial trace, past the last channel, unknown beam
+        let zeros = |len| Samples::Single {
+            values: vec![0.0; len],
+        };
+        let last = CHANNELS as u32 - 1;
+        assert!(buffer.write_channels(0, 0, zeros(5)).is_err());
+        assert!(buffer.write_channels(0, last, zeros(16)).is_err());
+        assert!(buffer.write_channels(2, 0, zeros(8)).is_err());
+        assert!(buffer.read_channels(0, last - 1, 3).is_err());
+        assert_eq!(buffer.missing_traces(), 2 * CHANNELS as u32);
+
+        // mismatched grids, another channel count than the probe's, no samples
+        let new = |channels: usize, samples: usize, t_len: usize| {
+            let shape = Array3Shape::from_usize((2, channels, samples));
+            IqBuffer::new(
+                shape,
+                Precision::Single,
+                vec![0.0; t_len],
+                vec![0.0; channels],
+            )
+        };
+        assert!(new(CHANNELS, 8, 8).is_ok());
+        assert!(new(CHANNELS, 8, 7).is_err());
+        assert!(new(4, 8, 8).is_err());
+        assert!(new(CHANNELS, 0, 0).is_err());
+    }
+
+    #[test]
+    fn chunks_follow_buffer_precision() {
+        let buffer = IqBuffer::new(
+            Array3Shape::from_usize((1, CHANNELS, 4)),
+            Precision::Double,
+            vec![0.0; 4],
+            vec![0.0; CHANNELS],
+        )
+        .unwrap();
+        let trace = Samples::Single {
+            values: vec![0.1, 0.2, 0.3, 0.4],
+        };
+        buffer.write_channels(0, 1, trace).unwrap();
+
+        let Samples::Double { values } = buffer.read_channels(0, 1, 1).unwrap() else {
+            panic!("double-precision buffer returned single-precision samples");
+        };
+        assert_eq!(values, [0.1f32, 0.2, 0.3, 0.4].map(f64::from));
+    }
+}
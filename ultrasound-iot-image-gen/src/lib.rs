@@ -1,6 +1,19 @@
+pub mod annotation;
+pub mod beamform;
+pub mod colormap;
+pub mod compounding;
 pub mod constants;
+pub mod display;
+pub mod elastography;
 pub mod iq2img;
+pub mod iq_buffer;
+pub mod measurement;
+pub mod processing;
+pub mod real;
+pub mod resample;
+pub mod stream;
 pub mod uniffi_helper;
+pub mod volume;
 
 #[cfg(feature = "rf2iq")]
 pub mod rf2iq;
@@ -8,16 +21,28 @@ pub mod rf2iq;
 #[cfg(feature = "rf2iq")]
 use rf2iq::*;
 
-use std::{fmt::Display, io::Cursor, path::Path, time::Instant};
+use std::{
+    fmt::Display,
+    path::Path,
+    sync::{Arc, RwLock},
+    time::Instant,
+};
 use tracing::info;
 
-use image::{DynamicImage, GenericImageView, ImageOutputFormat};
-
-use ndarray::{Array, Array1, Array2, Array3, ArrayBase, Dim, OwnedRepr, s};
+use ndarray::{Array, Array1, Array2, Array3, ArrayBase, Axis, Dim, OwnedRepr, s};
 use ndarray_stats::QuantileExt;
 
+use beamform::*;
+use colormap::{Colormap, PixelFormat};
+use compounding::*;
 use constants::*;
+use display::{AutoOptimize, DisplayParameters, auto_optimize, ramp_tgc};
+use elastography::*;
+use iq_buffer::{Frame, IqBuffer};
 use iq2img::*;
+use measurement::Calibration;
+use processing::*;
+use real::{Precision, Real};
 use uniffi_helper::Array3Data;
 
 uniffi::setup_scaffolding!();
@@ -27,6 +52,12 @@ pub struct UltrasoundImage {
     pub data: Vec<u8>,
     pub width: u32,
     pub height: u32,
+    /// Whether `data` decodes to 8-bit grayscale or RGBA.
+    pub format: PixelFormat,
+    /// Physical pixel scale, when the acquisition geometry is known.
+    pub calibration: Option<Calibration>,
+    /// Gain, dynamic range and TGC the image was rendered with.
+    pub display: DisplayParameters,
 }
 
 #[derive(Debug, uniffi::Error)]
@@ -51,55 +82,408 @@ pub struct IQData {
     pub xd: Vec<f64>,
 }
 
+#[derive(Debug, uniffi::Record)]
+pub struct SteeredIQData {
+    pub iq: IQData,
+    /// Steering angle of the acquisition in radians from the array normal.
+    pub angle: f64,
+}
+
+/// (lines, depth) image with its lateral and depth grids.
+type LineImage<T> = (Array2<T>, Array1<f64>, Array1<f64>);
+
 #[derive(Debug, uniffi::Object)]
 #[uniffi::export(Debug)]
 pub struct ImageProcessor {
     pub path: String,
+    beamformer: RwLock<BeamformerKind>,
+    frequency_compounding: RwLock<Option<FrequencyCompounding>>,
+    imaging_mode: RwLock<ImagingMode>,
+    envelope_detection: RwLock<EnvelopeDetection>,
+    colormap: RwLock<Colormap>,
+    auto_optimize: RwLock<Option<AutoOptimize>>,
+    precision: RwLock<Precision>,
 }
 
 #[uniffi::export]
 impl ImageProcessor {
     #[uniffi::constructor]
     pub fn new(path: String) -> Self {
-        Self { path }
+        Self {
+            path,
+            beamformer: RwLock::new(BeamformerKind::default()),
+            frequency_compounding: RwLock::new(None),
+            imaging_mode: RwLock::new(ImagingMode::default()),
+            envelope_detection: RwLock::new(EnvelopeDetection::default()),
+            colormap: RwLock::new(Colormap::default()),
+            auto_optimize: RwLock::new(None),
+            precision: RwLock::new(Precision::default()),
+        }
+    }
+
+    pub fn precision(&self) -> Precision {
+        *self.precision.read().unwrap()
+    }
+
+    /// Floating point precision of beamforming, filtering and envelope detection.
+    pub fn set_precision(&self, precision: Precision) {
+        *self.precision.write().unwrap() = precision;
+    }
+
+    pub fn beamformer(&self) -> BeamformerKind {
+        *self.beamformer.read().unwrap()
+    }
+
+    pub fn set_beamformer(&self, kind: BeamformerKind) {
+        *self.beamformer.write().unwrap() = kind;
+    }
+
+    pub fn frequency_compounding(&self) -> Option<FrequencyCompounding> {
+        *self.frequency_compounding.read().unwrap()
+    }
+
+    pub fn set_frequency_compounding(&self, options: Option<FrequencyCompounding>) {
+        *self.frequency_compounding.write().unwrap() = options;
+    }
+
+    pub fn imaging_mode(&self) -> ImagingMode {
+        *self.imaging_mode.read().unwrap()
+    }
+
+    pub fn set_imaging_mode(&self, mode: ImagingMode) {
+        *self.imaging_mode.write().unwrap() = mode;
+    }
+
+    pub fn envelope_detection(&self) -> EnvelopeDetection {
+        *self.envelope_detection.read().unwrap()
+    }
+
+    pub fn set_envelope_detection(&self, detection: EnvelopeDetection) {
+        *self.envelope_detection.write().unwrap() = detection;
+    }
+
+    pub fn colormap(&self) -> Colormap {
+        self.colormap.read().unwrap().clone()
+    }
+
+    pub fn set_colormap(&self, colormap: Colormap) -> Result<(), ImageError> {
+        colormap.validate()?;
+        *self.colormap.write().unwrap() = colormap;
+        Ok(())
+    }
+
+    pub fn auto_optimize(&self) -> Option<AutoOptimize> {
+        *self.auto_optimize.read().unwrap()
+    }
+
+    /// Enable histogram-based gain and dynamic range selection, or `None` for the fixed mapping.
+    pub fn set_auto_optimize(&self, options: Option<AutoOptimize>) {
+        *self.auto_optimize.write().unwrap() = options;
     }
 
     pub fn process_iq(&self, data: IQData) -> Result<UltrasoundImage, ImageError> {
+        self.process_iq_buffer(Arc::new(IqBuffer::from_iq_data(data)?))
+    }
+
+    /// Render a frame held in an [`IqBuffer`], without copying it across FFI.
+    pub fn process_iq_buffer(&self, buffer: Arc<IqBuffer>) -> Result<UltrasoundImage, ImageError> {
+        let before = Instant::now();
+
+        let (img, xd2, zd) = self.envelope_image(&buffer, 0.0)?;
+
+        self.render_envelope(&img, &xd2, &zd, before)
+    }
+
+    /// Combine a pulse-inversion pair (echoes of a pulse and of its inverted copy)
+    /// and render it in the current imaging mode.
+    pub fn process_iq_pulse_inversion(
+        &self,
+        positive: IQData,
+        inverted: IQData,
+    ) -> Result<UltrasoundImage, ImageError> {
+        let before = Instant::now();
+
+        if positive.preproc.shape.stride() != inverted.preproc.shape.stride() {
+            return Err(ImageError::InvalidData(
+                "Pulse-inversion acquisitions differ in shape".to_owned(),
+            ));
+        }
+        let (positive_data, inverted_data) = (positive.preproc, inverted.preproc);
+        let summed = match self.precision() {
+            Precision::Single => Frame::Single(pulse_inversion(
+                &positive_data.into_array::<f32>()?,
+                &inverted_data.into_array::<f32>()?,
+            )),
+            Precision::Double => Frame::Double(pulse_inversion(
+                &positive_data.into_array::<f64>()?,
+                &inverted_data.into_array::<f64>()?,
+            )),
+        };
+        let buffer = IqBuffer::from_frame(summed, positive.t_interp, positive.xd)?;
+
+        let (img, xd2, zd) = self.envelope_image(&buffer, 0.0)?;
+
+        self.render_envelope(&img, &xd2, &zd, before)
+    }
+
+    /// Spatially compound frames acquired at several steering angles onto the unsteered grid.
+    pub fn process_iq_compound(
+        &self,
+        frames: Vec<SteeredIQData>,
+    ) -> Result<UltrasoundImage, ImageError> {
+        let before = Instant::now();
+
+        if frames.is_empty() {
+            return Err(ImageError::InvalidData(
+                "Spatial compounding needs at least one frame".to_owned(),
+            ));
+        }
+
+        let mut envelopes = Vec::with_capacity(frames.len());
+        let mut grid = None;
+        for frame in frames {
+            let buffer = IqBuffer::from_iq_data(frame.iq)?;
+            let (img, xd2, zd) = self.envelope_image(&buffer, frame.angle)?;
+            envelopes.push((img, frame.angle));
+            grid = Some((xd2, zd));
+        }
+        let (xd2, zd) = grid.unwrap();
+
+        let img = spatial_compound(&envelopes, &xd2, &zd);
+        info!(
+            "Compounded {} frames, shape = {:?}",
+            envelopes.len(),
+            img.shape()
+        );
+
+        self.render_envelope(&img, &xd2, &zd, before)
+    }
+
+    /// Estimate axial strain between a frame before (`pre`) and after (`post`)
+    /// compression and render it over the B-mode image of `pre`.
+    pub fn process_iq_strain(
+        &self,
+        pre: IQData,
+        post: IQData,
+        options: StrainOptions,
+    ) -> Result<UltrasoundImage, ImageError> {
+        let before = Instant::now();
+
+        options.validate()?;
+        if pre.preproc.shape.stride() != post.preproc.shape.stride() {
+            return Err(ImageError::InvalidData(
+                "Strain frames differ in shape".to_owned(),
+            ));
+        }
+
+        let (rf_pre, xd2, zd) = self.beamformed_rf_f64(&IqBuffer::from_iq_data(pre)?, 0.0)?;
+        let (rf_post, _, _) = self.beamformed_rf_f64(&IqBuffer::from_iq_data(post)?, 0.0)?;
+
+        // displacement and strain
+        let center_frequency = self.imaging_mode().center_frequency();
+        let sample_rate = SAMPLE_RATE * UPSAMP_FACT as f64;
+        let (disp, centres) =
+            displacement(&rf_pre, &rf_post, center_frequency, sample_rate, &options);
+        let strain = least_squares_strain(&disp, &centres, options.kernel as usize);
+        info!("Strain estimated, shape = {:?}", strain.shape());
+
+        // B-mode background and strain overlay on the same scan-converted grid
+        let env = self.detect_envelope(&rf_pre);
+        let (bmode, calibration, display) = self.bmode(&env, &xd2, &zd)?;
+
+        let (strain, mask) = strain_to_samples(&strain, &centres, rf_pre.len_of(Axis(1)));
+        let (strain_sc, mask_sc) = scan_convert_strain(&strain, &mask, &xd2, &zd);
+        let overlay = strain_overlay(&transpose(strain_sc), &transpose(mask_sc), &options);
+
+        let base = self.colormap().apply(bmode).to_rgba8();
+        let img = colormap::composite(&base, &overlay);
+
+        info!("Elapsed time: {:.2?} s", before.elapsed());
+
+        encode_png(
+            image::DynamicImage::ImageRgba8(img),
+            Some(calibration),
+            display,
+        )
+    }
+
+    /// Render a frame that was already beamformed into (depth, beams, 2) complex samples.
+    pub fn process_beamformed_iq(&self, data: Array3Data) -> Result<UltrasoundImage, ImageError> {
         let before = Instant::now();
 
-        let preproc_data = data.preproc.into_array();
-        let t_interp = Array1::from_iter(data.t_interp.into_iter());
-        let xd = Array1::from_iter(data.xd.into_iter());
+        if data.shape.d2 != 2 {
+            return Err(ImageError::InvalidData(format!(
+                "Expected (depth, beams, 2) frame, got last dimension {}",
+                data.shape.d2
+            )));
+        }
+        let frame = data.into_array::<f64>()?;
+        let complex_data = convert_to_complex(&frame)?;
+
+        // envelope detection
+        let env = compute_envelope(&complex_data);
+        info!("Envelope detected Data shape = {:?}", env.shape());
+
+        // time gain compensation and log compression
+        let (img, display) = match self.auto_optimize() {
+            Some(options) if options.tgc_zones > 0 => auto_optimize(&env, Axis(0), options),
+            Some(options) => {
+                let env = apply_tgc(&env, TGC_GAIN);
+                let (img, mut display) = auto_optimize(&env, Axis(0), options);
+                display.tgc_db = ramp_tgc(TGC_GAIN);
+                (img, display)
+            }
+            None => (
+                log_compress(&apply_tgc(&env, TGC_GAIN), DYNAMIC_RANGE),
+                DisplayParameters::fixed(ramp_tgc(TGC_GAIN)),
+            ),
+        };
+        let img = quantize(&img);
+
+        info!("Elapsed time: {:.2?} s", before.elapsed());
+
+        encode_png(self.colormap().apply(create_image(&img)), None, display)
+    }
+}
 
-        let zd = &t_interp * SPEED_SOUND / 2.;
+impl ImageProcessor {
+    /// Beamform one acquisition along lines steered by `angle`.
+    /// Returns the (lines, depth) RF image with the lateral and depth grids.
+    fn beamformed_rf<T: Real>(
+        &self,
+        preproc_data: &Array3<T>,
+        t_interp: &Array1<f64>,
+        xd: &Array1<f64>,
+        angle: f64,
+    ) -> (Array2<T>, Array1<f64>, Array1<f64>) {
+        let zd = t_interp * SPEED_SOUND / 2.;
 
         // beamforming
-        let data_beamformed = beamform_df(&preproc_data, &t_interp, &xd);
+        let data_beamformed = if angle == 0.0 {
+            beamform(preproc_data, t_interp, xd, self.beamformer())
+        } else {
+            beamform_steered(preproc_data, t_interp, xd, self.beamformer(), angle)
+        };
         info!("Beamformed Data shape = {:?}", data_beamformed.shape());
+
+        // second harmonic band-pass
+        let data_beamformed = match self.imaging_mode() {
+            ImagingMode::Fundamental => data_beamformed,
+            ImagingMode::Harmonic => harmonic_filter(&data_beamformed),
+        };
         let m = data_beamformed.slice(s![0, ..]).sum();
         info!("Beamformed Data sum = {:?}", m);
 
         // lateral locations of beamformed a-lines
-        let xd2 = Array1::<f64>::range(0., N_TRANSMIT_BEAMS as f64, 1.) * ARRAY_PITCH;
+        let n_lines = data_beamformed.shape()[0];
+        let xd2 = Array1::<f64>::range(0., n_lines as f64, 1.) * ARRAY_PITCH;
         let xd2_max = *xd.max().unwrap();
         let xd2 = xd2 - xd2_max / 2.;
 
+        (data_beamformed, xd2, zd)
+    }
+
+    /// Beamform and envelope-detect one acquisition along lines steered by `angle`.
+    /// Returns the (lines, depth) envelope with the lateral and depth grids.
+    fn envelope_image(&self, buffer: &IqBuffer, angle: f64) -> Result<LineImage<f64>, ImageError> {
+        let (t_interp, xd) = (buffer.time(), buffer.element_positions());
+        // beamform in place when the buffer is already in the selected precision
+        buffer.with_frame(|frame| match self.precision() {
+            Precision::Single => {
+                let (data_beamformed, xd2, zd) =
+                    self.beamformed_rf(&frame.single(), t_interp, xd, angle);
+                (self.detect_envelope(&data_beamformed), xd2, zd)
+            }
+            Precision::Double => {
+                let (data_beamformed, xd2, zd) =
+                    self.beamformed_rf(&frame.double(), t_interp, xd, angle);
+                (self.detect_envelope(&data_beamformed), xd2, zd)
+            }
+        })
+    }
+
+    /// Beamform in the selected precision and return the RF image as `f64`.
+    fn beamformed_rf_f64(
+        &self,
+        buffer: &IqBuffer,
+        angle: f64,
+    ) -> Result<LineImage<f64>, ImageError> {
+        let (t_interp, xd) = (buffer.time(), buffer.element_positions());
+        buffer.with_frame(|frame| match self.precision() {
+            Precision::Single => {
+                let (data_beamformed, xd2, zd) =
+                    self.beamformed_rf(&frame.single(), t_interp, xd, angle);
+                (data_beamformed.mapv(Real::as_f64), xd2, zd)
+            }
+            Precision::Double => self.beamformed_rf(&frame.double(), t_interp, xd, angle),
+        })
+    }
+
+    /// Envelope of a (lines, depth) RF image, frequency compounded if enabled.
+    fn detect_envelope<T: Real>(&self, data_beamformed: &Array2<T>) -> Array2<f64> {
         // envelope detection
-        let mut img = Array2::<f64>::zeros(data_beamformed.raw_dim());
-        for n in 0..N_TRANSMIT_BEAMS {
-            let a_line = data_beamformed.slice(s![n as usize, ..]).into_owned();
-            let env = envelope(&a_line);
-            let mut img_slice = img.slice_mut(s![n as usize, ..]);
-            img_slice.assign(&env);
-        }
+        let detection = self.envelope_detection();
+        let center_frequency = self.imaging_mode().center_frequency();
+        let sample_rate = SAMPLE_RATE * UPSAMP_FACT as f64;
+        let img = match self.frequency_compounding() {
+            Some(options) => frequency_compound(
+                &data_beamformed.mapv(Real::as_f64),
+                sample_rate,
+                center_frequency,
+                detection,
+                options,
+            ),
+            None => {
+                let mut img = Array2::<f64>::zeros(data_beamformed.raw_dim());
+                for n in 0..data_beamformed.shape()[0] {
+                    let a_line = data_beamformed.slice(s![n as usize, ..]).into_owned();
+                    let env =
+                        detect_line_envelope(&a_line, detection, center_frequency, sample_rate)
+                            .mapv(Real::as_f64);
+                    let mut img_slice = img.slice_mut(s![n as usize, ..]);
+                    img_slice.assign(&env);
+                }
+                img
+            }
+        };
         info!("Envelope detected Data shape = {:?}", img.shape());
 
+        img
+    }
+
+    /// Log compress, scan convert and encode a (lines, depth) envelope image.
+    fn render_envelope(
+        &self,
+        img: &Array2<f64>,
+        xd2: &Array1<f64>,
+        zd: &Array1<f64>,
+        before: Instant,
+    ) -> Result<UltrasoundImage, ImageError> {
+        let (imgbuf, calibration, display) = self.bmode(img, xd2, zd)?;
+
+        info!("Elapsed time: {:.2?} s", before.elapsed());
+
+        encode_png(self.colormap().apply(imgbuf), Some(calibration), display)
+    }
+
+    /// Log compress and scan convert a (lines, depth) envelope image to 8-bit grayscale.
+    fn bmode(
+        &self,
+        img: &Array2<f64>,
+        xd2: &Array1<f64>,
+        zd: &Array1<f64>,
+    ) -> Result<(image::GrayImage, Calibration, DisplayParameters), ImageError> {
         // log compression
-        let dr = 35.0;
-        let img_log = log_compress(&img, dr);
+        let (img_log, display) = match self.auto_optimize() {
+            Some(options) => auto_optimize(img, Axis(1), options),
+            None => (
+                log_compress(img, DYNAMIC_RANGE),
+                DisplayParameters::fixed(Vec::new()),
+            ),
+        };
 
         // scan conversion
-        let (img_sc, x_sc, z_sc) = scan_convert(&img_log, &xd2, &zd);
+        let (img_sc, x_sc, z_sc) = scan_convert(&img_log, xd2, zd);
         info!("Length of z vector after scan conversion {:?}", z_sc.len());
         info!("Length of x vector after scan conversion {:?}", x_sc.len());
         info!("Scan converted imape shape = {:?}", img_sc.shape());
@@ -114,29 +498,54 @@ impl ImageProcessor {
         // let img_save_path = Path::new("./result.png");
         // imgbuf.clone().unwrap().save(img_save_path).unwrap();
 
-        info!("Elapsed time: {:.2?} s", before.elapsed());
-        let dyn_img = DynamicImage::ImageLuma8(imgbuf.unwrap());
-
-        let mut buffer = Vec::new();
-        dyn_img
-            .write_to(&mut Cursor::new(&mut buffer), ImageOutputFormat::Png)
-            .map_err(|e| ImageError::GenerationError(e.to_string()))?;
+        Ok((
+            imgbuf.unwrap(),
+            Calibration::from_grids(&x_sc, &z_sc)?,
+            display,
+        ))
+    }
+}
 
-        let (width, height) = dyn_img.dimensions();
+#[uniffi::export]
+#[cfg(feature = "rf2iq")]
+impl ImageProcessor {
+    pub fn process_rf(&self) -> Result<IQData, ImageError> {
+        let (preproc_data, t_interp, xd) = self.preprocessed_rf();
+        let preproc = match self.precision() {
+            Precision::Single => Array3Data::from_array(preproc_data.mapv(|x| x as f32)),
+            Precision::Double => Array3Data::from_array(preproc_data),
+        };
 
-        Ok(UltrasoundImage {
-            data: buffer,
-            width,
-            height,
+        Ok(IQData {
+            preproc,
+            t_interp: t_interp.into_raw_vec(),
+            xd: xd.into_raw_vec(),
         })
     }
+
+    /// Preprocess the recording into a buffer that stays on the Rust side;
+    /// pass it to `process_iq_buffer` or read it back in chunks.
+    pub fn process_rf_buffer(&self) -> Result<Arc<IqBuffer>, ImageError> {
+        let (preproc_data, t_interp, xd) = self.preprocessed_rf();
+        let frame = match self.precision() {
+            Precision::Single => Frame::Single(preproc_data.mapv(|x| x as f32)),
+            Precision::Double => Frame::Double(preproc_data),
+        };
+
+        Ok(Arc::new(IqBuffer::from_frame(
+            frame,
+            t_interp.into_raw_vec(),
+            xd.into_raw_vec(),
+        )?))
+    }
 }
 
-#[uniffi::export]
 #[cfg(feature = "rf2iq")]
 impl ImageProcessor {
-    pub fn process_rf(&self) -> Result<IQData, ImageError> {
-        tracing_subscriber::fmt().init();
+    /// Load and preprocess the recording at `path`.
+    /// Returns the (beams, channels, samples) data with its time and element grids.
+    fn preprocessed_rf(&self) -> (Array3<f64>, Array1<f64>, Array1<f64>) {
+        let _ = tracing_subscriber::fmt().try_init();
 
         let before = Instant::now();
 
@@ -161,17 +570,14 @@ impl ImageProcessor {
             before.elapsed()
         );
 
-        Ok(IQData {
-            preproc: Array3Data::from_array(preproc_data),
-            t_interp: t_interp.into_raw_vec(),
-            xd: xd.into_raw_vec(),
-        })
+        (preproc_data, t_interp, xd)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use uniffi_helper::{Array3Shape, Samples};
 
     #[test]
     #[cfg(feature = "rf2iq")]
@@ -182,4 +588,166 @@ mod tests {
 
         assert!(img.is_ok());
     }
+
+    #[test]
+    fn beamformed_iq_test() {
+        let (depth, beams) = (64, 16);
+        let frame = Array3::from_shape_fn((depth, beams, 2), |(i, j, k)| {
+            let phase = (i + j) as f64 * 0.3;
+            if k == 0 { phase.cos() } else { phase.sin() }
+        });
+        let proc = ImageProcessor::new(String::new());
+        let img = proc
+            .process_beamformed_iq(Array3Data::from_array(frame))
+            .unwrap();
+
+        assert_eq!((img.width, img.height), (beams as u32, depth as u32));
+
+        // a length that does not match the shape is an error, not a panic
+        let short = Array3Data {
+            shape: Array3Shape::from_usize((depth, beams, 2)),
+            data: Samples::Double {
+                values: vec![0.0; 3],
+            },
+        };
+        assert!(proc.process_beamformed_iq(short).is_err());
+    }
+
+    /// Channel data of a few point scatterers, recorded from t = 0.
+    fn point_targets<T: Real>(beams: usize) -> IQData {
+        let sample_rate = SAMPLE_RATE * UPSAMP_FACT as f64;
+        let n_samples = (2.0 * 30e-3 / SPEED_SOUND * sample_rate) as usize;
+        let channels = N_PROBE_CHANNELS as usize;
+        let xd = Array1::from_shape_fn(channels, |m| {
+            (m as f64 - (channels - 1) as f64 / 2.0) * ARRAY_PITCH
+        });
+        let targets = [(-1e-3, 10e-3), (0.0, 18e-3), (1.5e-3, 25e-3)];
+
+        let sigma = 1.0 / TRANSMIT_FREQ;
+        let data = Array3::from_shape_fn((beams, channels, n_samples), |(n, m, i)| {
+            let t = i as f64 / sample_rate;
+            let beam_x = (n as f64 - (beams / 2) as f64) * ARRAY_PITCH;
+            targets
+                .iter()
+                .map(|&(x, z)| {
+                    let dx = x - beam_x;
+                    let tau = t
+                        - ((dx.powi(2) + z * z).sqrt() + ((xd[m] - dx).powi(2) + z * z).sqrt())
+                            / SPEED_SOUND;
+                    (-(tau / sigma).powi(2)).exp()
+                        * (2.0 * std::f64::consts::PI * TRANSMIT_FREQ * tau).cos()
+                })
+                .sum::<f64>()
+        });
+
+        IQData {
+            preproc: Array3Data::from_array(data.mapv(T::cast)),
+            t_interp: (0..n_samples).map(|i| i as f64 / sample_rate).collect(),
+            xd: xd.into_raw_vec(),
+        }
+    }
+
+    #[test]
+    fn chunked_buffer_matches_iq_data() {
+        let proc = ImageProcessor::new(String::new());
+        let data = point_targets::<f32>(8);
+        let [beams, channels, samples] = data.preproc.shape.stride();
+        let Samples::Single { values } = data.preproc.data.clone() else {
+            unreachable!()
+        };
+
+        // upload one beam at a time, in two chunks of channels
+        let buffer = IqBuffer::new(
+            Array3Shape::from_usize((beams, channels, samples)),
+            proc.precision(),
+            data.t_interp.clone(),
+            data.xd.clone(),
+        )
+        .unwrap();
+        for (beam, traces) in values.chunks(channels * samples).enumerate() {
+            let (first, second) = traces.split_at(channels / 2 * samples);
+            buffer
+                .write_channels(
+                    beam as u32,
+                    0,
+                    Samples::Single {
+                        values: first.to_vec(),
+                    },
+                )
+                .unwrap();
+            buffer
+                .write_channels(
+                    beam as u32,
+                    (channels / 2) as u32,
+                    Samples::Single {
+                        values: second.to_vec(),
+                    },
+                )
+                .unwrap();
+        }
+
+        let chunked = proc.process_iq_buffer(Arc::new(buffer)).unwrap();
+        let whole = proc.process_iq(data).unwrap();
+        assert_eq!(chunked.data, whole.data);
+    }
+
+    #[test]
+    fn lateral_grid_follows_beam_count() {
+        let proc = ImageProcessor::new(String::new());
+        let buffer = IqBuffer::from_iq_data(point_targets::<f32>(8)).unwrap();
+        let (rf, xd2, zd) = proc.beamformed_rf_f64(&buffer, 0.0).unwrap();
+
+        assert_eq!(rf.shape(), [xd2.len(), zd.len()]);
+        assert!((xd2[1] - xd2[0] - ARRAY_PITCH).abs() < 1e-12);
+    }
+
+    #[test]
+    fn harmonic_mode_compounds_around_the_harmonic() {
+        let proc = ImageProcessor::new(String::new());
+        proc.set_imaging_mode(ImagingMode::Harmonic);
+        let sample_rate = SAMPLE_RATE * UPSAMP_FACT as f64;
+        let omega = 2.0 * std::f64::consts::PI * ImagingMode::Harmonic.center_frequency();
+        let rf = Array2::from_shape_fn((4, 2048), |(_, i)| (omega * i as f64 / sample_rate).cos());
+        let compounding = FrequencyCompounding {
+            bands: 1,
+            fractional_bandwidth: 0.5,
+        };
+        let centre = s![.., 512..1536];
+
+        for detection in [EnvelopeDetection::default(), EnvelopeDetection::IqMagnitude] {
+            proc.set_envelope_detection(detection);
+            proc.set_frequency_compounding(None);
+            let plain = proc.detect_envelope(&rf).slice(centre).mean().unwrap();
+            proc.set_frequency_compounding(Some(compounding));
+            let compounded = proc.detect_envelope(&rf).slice(centre).mean().unwrap();
+
+            // the harmonic echo passes the sub-bands instead of being filtered out
+            assert!((compounded / plain - 1.0).abs() < 0.1, "{detection:?}");
+        }
+    }
+
+    #[test]
+    fn single_precision_matches_double() {
+        let proc = ImageProcessor::new(String::new());
+        assert_eq!(proc.precision(), Precision::Single);
+
+        // f32 data through the f32 pipeline against an f64 reference on f64 data
+        let single = proc.process_iq(point_targets::<f32>(32)).unwrap();
+        proc.set_precision(Precision::Double);
+        let double = proc.process_iq(point_targets::<f64>(32)).unwrap();
+
+        let decode = |img: &UltrasoundImage| image::load_from_memory(&img.data).unwrap().to_luma8();
+        let (single, double) = (decode(&single), decode(&double));
+        assert_eq!(single.dimensions(), double.dimensions());
+
+        // differences only where a value sits on a quantization boundary
+        let diffs: Vec<u8> = single
+            .pixels()
+            .zip(double.pixels())
+            .map(|(a, b)| a[0].abs_diff(b[0]))
+            .collect();
+        let differing = diffs.iter().filter(|&&d| d > 0).count();
+        assert!(diffs.iter().all(|&d| d <= 2));
+        assert!((differing as f64) < 0.01 * diffs.len() as f64);
+    }
 }
@@ -0,0 +1,151 @@
+use ndarray::Array1;
+
+use crate::ImageError;
+
+/// Physical scale of an image: the position of the top-left pixel centre and
+/// the pixel spacing, in millimetres. Depth grows downwards along rows.
+#[derive(Debug, Clone, Copy, PartialEq, uniffi::Record)]
+pub struct Calibration {
+    pub x_origin_mm: f64,
+    pub z_origin_mm: f64,
+    pub pixel_width_mm: f64,
+    pub pixel_height_mm: f64,
+}
+
+/// Location in image pixel coordinates (column, row); may be fractional.
+#[derive(Debug, Clone, Copy, PartialEq, uniffi::Record)]
+pub struct PixelPoint {
+    pub x: f64,
+    pub y: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, uniffi::Record)]
+pub struct EllipseMeasurement {
+    pub area_mm2: f64,
+    pub circumference_mm: f64,
+}
+
+impl Calibration {
+    /// Build from the lateral and depth grids (metres) returned by `scan_convert`.
+    /// Each grid needs at least two points to define a spacing.
+    pub fn from_grids(x_sc: &Array1<f64>, z_sc: &Array1<f64>) -> Result<Self, ImageError> {
+        if x_sc.len() < 2 || z_sc.len() < 2 {
+            return Err(ImageError::InvalidData(format!(
+                "Cannot calibrate a {} x {} pixel image",
+                x_sc.len(),
+                z_sc.len()
+            )));
+        }
+        Ok(Self {
+            x_origin_mm: x_sc[0] * 1e3,
+            z_origin_mm: z_sc[0] * 1e3,
+            pixel_width_mm: (x_sc[x_sc.len() - 1] - x_sc[0]) / (x_sc.len() - 1) as f64 * 1e3,
+            pixel_height_mm: (z_sc[z_sc.len() - 1] - z_sc[0]) / (z_sc.len() - 1) as f64 * 1e3,
+        })
+    }
+
+    /// Physical (lateral, depth) position of a pixel in millimetres.
+    pub fn to_mm(&self, p: PixelPoint) -> (f64, f64) {
+        (
+            self.x_origin_mm + p.x * self.pixel_width_mm,
+            self.z_origin_mm + p.y * self.pixel_height_mm,
+        )
+    }
+}
+
+/// Distance between two pixels in millimetres.
+#[uniffi::export]
+pub fn distance_mm(calibration: Calibration, a: PixelPoint, b: PixelPoint) -> f64 {
+    let (ax, az) = calibration.to_mm(a);
+    let (bx, bz) = calibration.to_mm(b);
+    (ax - bx).hypot(az - bz)
+}
+
+/// Depth of a pixel below the transducer face in millimetres.
+#[uniffi::export]
+pub fn pixel_depth_mm(calibration: Calibration, p: PixelPoint) -> f64 {
+    calibration.to_mm(p).1
+}
+
+/// Area and circumference of an ellipse given by the end points of its two axes.
+/// The circumference uses Ramanujan's second approximation.
+#[uniffi::export]
+pub fn ellipse_mm(
+    calibration: Calibration,
+    axis_a_start: PixelPoint,
+    axis_a_end: PixelPoint,
+    axis_b_start: PixelPoint,
+    axis_b_end: PixelPoint,
+) -> EllipseMeasurement {
+    let a = distance_mm(calibration, axis_a_start, axis_a_end) / 2.0;
+    let b = distance_mm(calibration, axis_b_start, axis_b_end) / 2.0;
+
+    let h = ((a - b) / (a + b)).powi(2);
+    let circumference = if a + b > 0.0 {
+        std::f64::consts::PI * (a + b) * (1.0 + 3.0 * h / (10.0 + (4.0 - 3.0 * h).sqrt()))
+    } else {
+        0.0
+    };
+
+    EllipseMeasurement {
+        area_mm2: std::f64::consts::PI * a * b,
+        circumference_mm: circumference,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn calibration() -> Calibration {
+        // 0.1 mm lateral and 0.2 mm axial pixels, imaging from 5 mm depth
+        let x_sc = Array1::linspace(-10e-3, 10e-3, 201);
+        let z_sc = Array1::linspace(5e-3, 45e-3, 201);
+        Calibration::from_grids(&x_sc, &z_sc).unwrap()
+    }
+
+    #[test]
+    fn single_pixel_grids_are_rejected() {
+        let x_sc = Array1::linspace(-10e-3, 10e-3, 201);
+        let z_sc = Array1::from_elem(1, 5e-3);
+        assert!(Calibration::from_grids(&x_sc, &z_sc).is_err());
+        assert!(Calibration::from_grids(&z_sc, &x_sc).is_err());
+    }
+
+    #[test]
+    fn distance_and_depth() {
+        let cal = calibration();
+        let a = PixelPoint { x: 0.0, y: 0.0 };
+        let b = PixelPoint { x: 30.0, y: 20.0 };
+
+        assert!((distance_mm(cal, a, b) - 5.0).abs() < 1e-9);
+        assert!((pixel_depth_mm(cal, a) - 5.0).abs() < 1e-9);
+        assert!((pixel_depth_mm(cal, b) - 9.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn ellipse_measurements() {
+        let cal = calibration();
+        // circle of 2 mm radius
+        let circle = ellipse_mm(
+            cal,
+            PixelPoint { x: 80.0, y: 50.0 },
+            PixelPoint { x: 120.0, y: 50.0 },
+            PixelPoint { x: 100.0, y: 40.0 },
+            PixelPoint { x: 100.0, y: 60.0 },
+        );
+        assert!((circle.area_mm2 - std::f64::consts::PI * 4.0).abs() < 1e-9);
+        assert!((circle.circumference_mm - std::f64::consts::PI * 4.0).abs() < 1e-9);
+
+        // 3 x 1 mm semi-axes, exact circumference 13.3649
+        let ellipse = ellipse_mm(
+            cal,
+            PixelPoint { x: 70.0, y: 50.0 },
+            PixelPoint { x: 130.0, y: 50.0 },
+            PixelPoint { x: 100.0, y: 45.0 },
+            PixelPoint { x: 100.0, y: 55.0 },
+        );
+        assert!((ellipse.area_mm2 - std::f64::consts::PI * 3.0).abs() < 1e-9);
+        assert!((ellipse.circumference_mm - 13.3649).abs() < 1e-3);
+    }
+}
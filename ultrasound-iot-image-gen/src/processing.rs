@@ -1,37 +1,49 @@
-use image::{GrayImage, Luma};
+use std::io::Cursor;
+
+use image::{DynamicImage, GenericImageView, GrayImage, ImageOutputFormat, Luma};
 use ndarray::{Array2, Array3};
-use num_complex::Complex32;
-use std::f32;
+use ndarray_linalg::c64;
+
+use crate::colormap::PixelFormat;
+use crate::display::DisplayParameters;
+use crate::iq2img::log_compress;
+use crate::measurement::Calibration;
+use crate::{ImageError, UltrasoundImage};
 
-pub fn convert_to_complex(frame: Array3<f32>) -> Array2<Complex32> {
+/// Convert a (depth, beams, 2) frame of interleaved real/imag values to complex samples.
+pub fn convert_to_complex(frame: &Array3<f64>) -> Result<Array2<c64>, ImageError> {
     let (height, width, channels) = frame.dim();
-    assert!(
-        channels == 2,
-        "Expected last dimension to be 2 (real, imag)"
-    );
-    let mut complex_array = Array2::<Complex32>::zeros((height, width));
+    if channels != 2 {
+        return Err(ImageError::InvalidData(format!(
+            "Expected last dimension to be 2 (real, imag), got {}",
+            channels
+        )));
+    }
+    let mut complex_array = Array2::<c64>::zeros((height, width));
     for i in 0..height {
         for j in 0..width {
             let real = frame[[i, j, 0]];
             let imag = frame[[i, j, 1]];
-            complex_array[[i, j]] = Complex32::new(real, imag);
+            complex_array[[i, j]] = c64::new(real, imag);
         }
     }
-    complex_array
+    Ok(complex_array)
 }
 
-/// Compute the envelope (magnitude) of complex RF data.
-pub fn compute_envelope(complex_data: &Array2<Complex32>) -> Array2<f32> {
+/// Compute the envelope (magnitude) of complex IQ data.
+pub fn compute_envelope(complex_data: &Array2<c64>) -> Array2<f64> {
     complex_data.mapv(|c| c.norm())
 }
 
 /// Apply Time Gain Compensation (TGC) to envelope data.
-pub fn apply_tgc(envelope: &Array2<f32>) -> Array2<f32> {
+///
+/// The gain rises linearly in dB from 0 at the first sample to `gain_db` at the last one.
+pub fn apply_tgc(envelope: &Array2<f64>, gain_db: f64) -> Array2<f64> {
     let (num_samples, num_beams) = envelope.dim();
     let mut tgc = envelope.clone();
     for i in 0..num_samples {
         // Compute a gain factor based on the depth index.
-        let gain = (1.0 + i as f32 / num_samples as f32).exp();
+        let gain = 10f64.powf(gain_db * i as f64 / num_samples as f64 / 20.0);
         for j in 0..num_beams {
             tgc[[i, j]] *= gain;
         }
@@ -39,18 +51,14 @@ pub fn apply_tgc(envelope: &Array2<f32>) -> Array2<f32> {
     tgc
 }
 
-/// Apply log compression to the TGC data.
-pub fn log_compression(data: &Array2<f32>) -> Array2<u8> {
-    let (num_samples, num_beams) = data.dim();
-    let mut compressed = Array2::<u8>::zeros((num_samples, num_beams));
-    for ((i, j), &value) in data.indexed_iter() {
-        // Use logarithmic compression; add 1.0 to avoid log(0)
-        let log_val = (1.0 + value).ln();
-        // Normalize to 0-255. Adjust divisor if your max log value is different.
-        let normalized = (log_val / 5.0 * 255.0).min(255.0).max(0.0);
-        compressed[[i, j]] = normalized as u8;
-    }
-    compressed
+/// Apply log compression with the given dynamic range (dB) and quantize to 8 bits.
+pub fn log_compression(data: &Array2<f64>, dr: f64) -> Array2<u8> {
+    quantize(&log_compress(data, dr))
+}
+
+/// Quantize a compressed image in [0, 1] to 8 bits.
+pub fn quantize(data: &Array2<f64>) -> Array2<u8> {
+    data.mapv(|x| (x * 255.0).round().clamp(0.0, 255.0) as u8)
 }
 
 /// Create a grayscale image from processed data.
@@ -65,3 +73,35 @@ pub fn create_image(data: &Array2<u8>) -> GrayImage {
     }
     img
 }
+
+/// Encode an 8-bit grayscale or RGBA image as PNG for the FFI boundary.
+pub fn encode_png(
+    dyn_img: DynamicImage,
+    calibration: Option<Calibration>,
+    display: DisplayParameters,
+) -> Result<UltrasoundImage, ImageError> {
+    let format = match dyn_img {
+        DynamicImage::ImageLuma8(_) => PixelFormat::Gray8,
+        _ => PixelFormat::Rgba8,
+    };
+    let dyn_img = match format {
+        PixelFormat::Gray8 => dyn_img,
+        PixelFormat::Rgba8 => DynamicImage::ImageRgba8(dyn_img.to_rgba8()),
+    };
+
+    let mut buffer = Vec::new();
+    dyn_img
+        .write_to(&mut Cursor::new(&mut buffer), ImageOutputFormat::Png)
+        .map_err(|e| ImageError::GenerationError(e.to_string()))?;
+
+    let (width, height) = dyn_img.dimensions();
+
+    Ok(UltrasoundImage {
+        data: buffer,
+        width,
+        height,
+        format,
+        calibration,
+        display,
+    })
+}
@@ -0,0 +1,81 @@
+use std::iter::Sum;
+use std::ops::{AddAssign, DivAssign, MulAssign, SubAssign};
+
+use ndarray::{LinalgScalar, ScalarOperand};
+use rustfft::FftNum;
+use rustfft::num_traits::{Float, FloatConst};
+
+use crate::uniffi_helper::Samples;
+
+/// Floating point type of the acquisition-size stages (beamforming, RF
+/// filtering and envelope detection). Display stages on the envelope image
+/// always run in `f64`.
+pub trait Real:
+    Float
+    + FloatConst
+    + FftNum
+    + LinalgScalar
+    + ScalarOperand
+    + Sum
+    + AddAssign
+    + SubAssign
+    + MulAssign
+    + DivAssign
+    + Default
+{
+    fn cast(x: f64) -> Self;
+    fn as_f64(self) -> f64;
+    fn into_samples(values: Vec<Self>) -> Samples;
+    fn from_samples(samples: Samples) -> Vec<Self>;
+}
+
+impl Real for f32 {
+    fn cast(x: f64) -> Self {
+        x as f32
+    }
+
+    fn as_f64(self) -> f64 {
+        self as f64
+    }
+
+    fn into_samples(values: Vec<Self>) -> Samples {
+        Samples::Single { values }
+    }
+
+    fn from_samples(samples: Samples) -> Vec<Self> {
+        match samples {
+            Samples::Single { values } => values,
+            Samples::Double { values } => values.into_iter().map(|x| x as f32).collect(),
+        }
+    }
+}
+
+impl Real for f64 {
+    fn cast(x: f64) -> Self {
+        x
+    }
+
+    fn as_f64(self) -> f64 {
+        self
+    }
+
+    fn into_samples(values: Vec<Self>) -> Samples {
+        Samples::Double { values }
+    }
+
+    fn from_samples(samples: Samples) -> Vec<Self> {
+        match samples {
+            Samples::Single { values } => values.into_iter().map(f64::from).collect(),
+            Samples::Double { values } => values,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, uniffi::Enum)]
+pub enum Precision {
+    /// `f32`: half the memory and bandwidth, the default for mobile targets.
+    #[default]
+    Single,
+    /// `f64` reference processing.
+    Double,
+}
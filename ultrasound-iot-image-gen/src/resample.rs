@@ -0,0 +1,182 @@
+use ndarray::{Array, Array1, ArrayView1, Axis, Dimension, Zip};
+
+/// Half length of the anti-imaging/anti-aliasing filter in units of the larger
+/// of the up and down factors, and the Kaiser window shape (as scipy's resample_poly).
+const HALF_LENGTH: usize = 10;
+const KAISER_BETA: f64 = 5.0;
+
+fn gcd(a: u32, b: u32) -> u32 {
+    if b == 0 { a } else { gcd(b, a % b) }
+}
+
+/// Zeroth-order modified Bessel function of the first kind.
+fn bessel_i0(x: f64) -> f64 {
+    let mut sum = 1.0;
+    let mut term = 1.0;
+    let mut k = 1.0;
+    while term > 1e-12 * sum {
+        term *= (x / (2.0 * k)).powi(2);
+        sum += term;
+        k += 1.0;
+    }
+    sum
+}
+
+fn kaiser(i: usize, taps: usize, beta: f64) -> f64 {
+    let r = 2.0 * i as f64 / (taps - 1) as f64 - 1.0;
+    bessel_i0(beta * (1.0 - r * r).max(0.0).sqrt()) / bessel_i0(beta)
+}
+
+/// Polyphase FIR resampler by the rational factor `up / down`.
+///
+/// The output is aligned with the input: output sample `m` lies at input time
+/// `m * down / up`, and there are `ceil(n * up / down)` output samples.
+#[derive(Debug, Clone)]
+pub struct Resampler {
+    up: usize,
+    down: usize,
+    /// Filter coefficients split into `up` phases.
+    phases: Vec<Vec<f64>>,
+    /// Group delay of the filter at the upsampled rate.
+    delay: usize,
+}
+
+impl Resampler {
+    pub fn new(up: u32, down: u32) -> Self {
+        assert!(up > 0 && down > 0, "resampling factors must be positive");
+        let g = gcd(up, down);
+        let (up, down) = ((up / g) as usize, (down / g) as usize);
+
+        // low-pass at the lower of the two Nyquist frequencies, gain `up` to
+        // make up for the zeros inserted between samples
+        let max_rate = up.max(down);
+        let half = HALF_LENGTH * max_rate;
+        let taps = 2 * half + 1;
+        let fc = 0.5 / max_rate as f64;
+        let coeffs: Vec<f64> = (0..taps)
+            .map(|i| {
+                let n = i as f64 - half as f64;
+                let sinc = if n == 0.0 {
+                    1.0
+                } else {
+                    (2.0 * std::f64::consts::PI * fc * n).sin()
+                        / (2.0 * std::f64::consts::PI * fc * n)
+                };
+                up as f64 * 2.0 * fc * sinc * kaiser(i, taps, KAISER_BETA)
+            })
+            .collect();
+
+        let phases = (0..up)
+            .map(|p| coeffs.iter().skip(p).step_by(up).copied().collect())
+            .collect();
+
+        Self {
+            up,
+            down,
+            phases,
+            delay: half,
+        }
+    }
+
+    pub fn output_len(&self, len: usize) -> usize {
+        (len * self.up).div_ceil(self.down)
+    }
+
+    /// Resample one signal.
+    pub fn apply(&self, x: ArrayView1<f64>) -> Array1<f64> {
+        let len = x.len() as isize;
+        Array1::from_shape_fn(self.output_len(x.len()), |m| {
+            // position in the zero-stuffed signal, shifted by the filter delay
+            let j = m * self.down + self.delay;
+            let (base, phase) = ((j / self.up) as isize, j % self.up);
+            self.phases[phase]
+                .iter()
+                .enumerate()
+                .map(|(q, h)| {
+                    let i = base - q as isize;
+                    if i >= 0 && i < len {
+                        h * x[i as usize]
+                    } else {
+                        0.0
+                    }
+                })
+                .sum()
+        })
+    }
+
+    /// Resample every lane of `data` along `axis`, e.g. all (beam, channel) traces at once.
+    pub fn apply_axis<D: Dimension>(&self, data: &Array<f64, D>, axis: Axis) -> Array<f64, D> {
+        let mut dim = data.raw_dim();
+        dim[axis.index()] = self.output_len(data.len_of(axis));
+        let mut out = Array::zeros(dim);
+        Zip::from(out.lanes_mut(axis))
+            .and(data.lanes(axis))
+            .for_each(|mut resampled, x| resampled.assign(&self.apply(x)));
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::{Array3, s};
+
+    fn tone(cycles_per_sample: f64, n: usize, step: f64) -> Array1<f64> {
+        Array1::from_shape_fn(n, |i| {
+            (2.0 * std::f64::consts::PI * cycles_per_sample * i as f64 * step + 0.3).sin()
+        })
+    }
+
+    fn max_error(a: &Array1<f64>, b: &Array1<f64>, margin: usize) -> f64 {
+        let n = a.len().min(b.len());
+        (&a.slice(s![margin..n - margin]) - &b.slice(s![margin..n - margin]))
+            .mapv(f64::abs)
+            .fold(0.0, |m: f64, &e| m.max(e))
+    }
+
+    #[test]
+    fn rational_resampling_matches_band_limited_tone() {
+        let f = 0.05;
+        let x = tone(f, 1000, 1.0);
+        for (up, down) in [(4, 1), (3, 2), (2, 3)] {
+            let resampler = Resampler::new(up, down);
+            let y = resampler.apply(x.view());
+            assert_eq!(y.len(), resampler.output_len(1000));
+            // tone sampled at the new rate
+            let expected = tone(f, y.len(), down as f64 / up as f64);
+            let error = max_error(&y, &expected, 40);
+            assert!(error < 1e-2, "{up}/{down}: {error}");
+        }
+    }
+
+    #[test]
+    fn decimation_rejects_aliases() {
+        let resampler = Resampler::new(1, 8);
+        let rms = |x: &Array1<f64>| {
+            x.slice(s![10..x.len() - 10])
+                .mapv(|v| v * v)
+                .mean()
+                .unwrap()
+                .sqrt()
+        };
+
+        // below the new Nyquist frequency (1/16) the tone passes, above it is removed
+        let pass = resampler.apply(tone(0.02, 4000, 1.0).view());
+        let stop = resampler.apply(tone(0.1, 4000, 1.0).view());
+        assert!((rms(&pass) - 1.0 / 2f64.sqrt()).abs() < 0.01);
+        assert!(20.0 * (rms(&stop) / rms(&pass)).log10() < -40.0);
+    }
+
+    #[test]
+    fn lanes_are_resampled_independently() {
+        let data = Array3::from_shape_fn((2, 3, 100), |(b, c, i)| {
+            ((b * 3 + c) as f64 + 1.0) * (i as f64 * 0.1).sin()
+        });
+        let resampler = Resampler::new(4, 1);
+        let out = resampler.apply_axis(&data, Axis(2));
+
+        assert_eq!(out.dim(), (2, 3, 400));
+        let lane = resampler.apply(data.slice(s![1, 2, ..]));
+        assert_eq!(out.slice(s![1, 2, ..]), lane);
+    }
+}
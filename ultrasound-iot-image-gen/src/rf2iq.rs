@@ -1,14 +1,11 @@
-extern crate basic_dsp;
 extern crate blas_src;
 
 use std::path::Path;
 
-use ndarray::{Array, Array1, Array3, s};
-
-use basic_dsp::conv_types::*;
-use basic_dsp::*;
+use ndarray::{Array, Array1, Array3, Axis, s};
 
 use crate::constants::*;
+use crate::resample::Resampler;
 
 pub fn get_data(data_path: &Path) -> Array3<f64> {
     let file = hdf5::File::open(data_path).unwrap();
@@ -23,40 +20,11 @@ pub fn preproc(
     xd: &Array1<f64>,
 ) -> (Array3<f64>, Array1<f64>) {
     // Preprocessing. right now this only does upsampling/interpolation.
-    // TODO: filtering, apodization, replace interpolation b/c
-    //       it's slow
-    let filt_ord = 201;
-    let lc = 0.5e6;
-    let uc = 2.5e6;
-    let lc = lc / (SAMPLE_RATE / 2.0);
-    let uc = uc / (SAMPLE_RATE / 2.0);
+    // TODO: filtering, apodization
 
+    // interpolate every (beam, channel) trace
     let rec_len_interp = REC_LEN * UPSAMP_FACT;
-    let mut data_interp = Array3::<f64>::zeros((
-        N_TRANSMIT_BEAMS as usize,
-        N_PROBE_CHANNELS as usize,
-        rec_len_interp as usize,
-    ));
-    let mut buffer = SingleBuffer::new();
-    for n in 0..N_TRANSMIT_BEAMS {
-        for m in 0..N_PROBE_CHANNELS {
-            // get waveform and convert to DspVec<f64>
-            let waveform = data.slice(s![n as usize, m as usize, ..]);
-            let mut dsp_vec = waveform.to_owned().into_raw_vec().to_real_time_vec();
-
-            // interpolate - currently a bug(ish) requiring truncation. See https://github.com/liebharc/basic_dsp/issues/46
-            dsp_vec
-                .interpolatei(&mut buffer, &RaisedCosineFunction::new(0.1), UPSAMP_FACT)
-                .unwrap();
-            let (mut dsp_vec_data, points) = dsp_vec.get();
-            dsp_vec_data.truncate(points);
-            // let vec: Vec<f64> = dsp_vec.into(); // This also works but, what if you still need to operate on dsp_vec?
-
-            // plug into new array
-            let mut waveform_interp = data_interp.slice_mut(s![n as usize, m as usize, ..]);
-            waveform_interp.assign(&Array1::from(dsp_vec_data));
-        }
-    }
+    let data_interp = Resampler::new(UPSAMP_FACT, 1).apply_axis(data, Axis(2));
     let sample_rate = SAMPLE_RATE * UPSAMP_FACT as f64;
     let t_interp = Array::range(0.0, rec_len_interp as f64, 1.0) / sample_rate + t[0];
 
@@ -0,0 +1,242 @@
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use ndarray::{Array2, s};
+use tracing::info;
+
+use crate::constants::*;
+use crate::iq_buffer::IqBuffer;
+use crate::{IQData, ImageError, ImageProcessor, UltrasoundImage};
+
+#[derive(Debug, Clone, Copy, PartialEq, uniffi::Record)]
+pub struct PersistenceOptions {
+    /// Weight of the previous output in the IIR average, in [0, 1). 0 disables persistence.
+    pub persistence: f64,
+    /// Register the running average to each new frame before blending.
+    pub motion_compensation: bool,
+    /// Largest shift searched by motion compensation, in lines laterally and
+    /// in DECIM_FACT samples axially.
+    pub max_shift: u32,
+}
+
+impl Default for PersistenceOptions {
+    fn default() -> Self {
+        Self {
+            persistence: 0.5,
+            motion_compensation: false,
+            max_shift: 3,
+        }
+    }
+}
+
+/// Running temporal average of (lines, depth) envelope frames.
+#[derive(Debug, Default)]
+pub struct Persistence {
+    pub options: PersistenceOptions,
+    previous: Option<Array2<f64>>,
+    average: Option<Array2<f64>>,
+}
+
+impl Persistence {
+    pub fn new(options: PersistenceOptions) -> Self {
+        Self {
+            options,
+            previous: None,
+            average: None,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.previous = None;
+        self.average = None;
+    }
+
+    /// Blend a new envelope frame into the running average and return it.
+    pub fn update(&mut self, frame: Array2<f64>) -> Array2<f64> {
+        let alpha = self.options.persistence.clamp(0.0, 0.99);
+
+        let average = match (self.average.take(), &self.previous) {
+            (Some(average), Some(previous)) if average.dim() == frame.dim() => {
+                let average = if self.options.motion_compensation {
+                    let (dl, dz) =
+                        estimate_shift(previous, &frame, self.options.max_shift as isize);
+                    shift_frame(&average, &frame, dl, dz)
+                } else {
+                    average
+                };
+                average * alpha + &frame * (1.0 - alpha)
+            }
+            // first frame, or the geometry changed
+            _ => frame.clone(),
+        };
+
+        self.previous = Some(frame);
+        self.average = Some(average.clone());
+        average
+    }
+}
+
+/// Global (lateral, axial) shift in samples that best maps `previous` onto
+/// `current`, i.e. current[i, j] ~ previous[i - dl, j - dz], found by minimizing
+/// the mean absolute difference on a grid decimated axially by DECIM_FACT.
+pub fn estimate_shift(
+    previous: &Array2<f64>,
+    current: &Array2<f64>,
+    max_shift: isize,
+) -> (isize, isize) {
+    let step = DECIM_FACT as usize;
+    let prev = previous.slice(s![.., ..;step]);
+    let curr = current.slice(s![.., ..;step]);
+    let prev = &prev / prev.mean().unwrap_or(1.0).max(f64::MIN_POSITIVE);
+    let curr = &curr / curr.mean().unwrap_or(1.0).max(f64::MIN_POSITIVE);
+    let (lines, depth) = curr.dim();
+
+    let mut best = (0, 0);
+    let mut best_cost = f64::MAX;
+    for dl in -max_shift..=max_shift {
+        for dz in -max_shift..=max_shift {
+            let mut cost = 0.0;
+            let mut count = 0usize;
+            for i in 0..lines as isize {
+                let pi = i - dl;
+                if pi < 0 || pi >= lines as isize {
+                    continue;
+                }
+                for j in 0..depth as isize {
+                    let pj = j - dz;
+                    if pj < 0 || pj >= depth as isize {
+                        continue;
+                    }
+                    cost +=
+                        (curr[[i as usize, j as usize]] - prev[[pi as usize, pj as usize]]).abs();
+                    count += 1;
+                }
+            }
+            if count > 0 && cost / (count as f64) < best_cost {
+                best_cost = cost / count as f64;
+                best = (dl, dz);
+            }
+        }
+    }
+    (best.0, best.1 * step as isize)
+}
+
+/// Shift `frame` by (dl, dz) samples; pixels shifted in from outside are taken from `fill`.
+pub fn shift_frame(frame: &Array2<f64>, fill: &Array2<f64>, dl: isize, dz: isize) -> Array2<f64> {
+    let (lines, depth) = frame.dim();
+    Array2::from_shape_fn((lines, depth), |(i, j)| {
+        let (pi, pj) = (i as isize - dl, j as isize - dz);
+        if pi >= 0 && pj >= 0 && (pi as usize) < lines && (pj as usize) < depth {
+            frame[[pi as usize, pj as usize]]
+        } else {
+            fill[[i, j]]
+        }
+    })
+}
+
+/// Stateful live-view pipeline: frames pushed through it are temporally
+/// averaged before being rendered by the wrapped processor.
+#[derive(Debug, uniffi::Object)]
+pub struct FrameStream {
+    processor: Arc<ImageProcessor>,
+    persistence: Mutex<Persistence>,
+}
+
+#[uniffi::export]
+impl FrameStream {
+    #[uniffi::constructor]
+    pub fn new(processor: Arc<ImageProcessor>, options: PersistenceOptions) -> Self {
+        Self {
+            processor,
+            persistence: Mutex::new(Persistence::new(options)),
+        }
+    }
+
+    pub fn options(&self) -> PersistenceOptions {
+        self.persistence.lock().unwrap().options
+    }
+
+    pub fn set_options(&self, options: PersistenceOptions) {
+        self.persistence.lock().unwrap().options = options;
+    }
+
+    /// Drop the averaged history, e.g. when the probe is lifted or moved to a new view.
+    pub fn reset(&self) {
+        self.persistence.lock().unwrap().reset();
+    }
+
+    pub fn push_iq(&self, data: IQData) -> Result<UltrasoundImage, ImageError> {
+        self.push_iq_buffer(Arc::new(IqBuffer::from_iq_data(data)?))
+    }
+
+    /// As [`FrameStream::push_iq`], for a frame held in an [`IqBuffer`].
+    pub fn push_iq_buffer(&self, buffer: Arc<IqBuffer>) -> Result<UltrasoundImage, ImageError> {
+        let before = Instant::now();
+
+        let (img, xd2, zd) = self.processor.envelope_image(&buffer, 0.0)?;
+        let img = self.persistence.lock().unwrap().update(img);
+        info!("Persistence applied, shape = {:?}", img.shape());
+
+        self.processor.render_envelope(&img, &xd2, &zd, before)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    fn noise(rng: &mut StdRng, lines: usize, depth: usize) -> Array2<f64> {
+        Array2::from_shape_fn((lines, depth), |_| rng.r#gen::<f64>())
+    }
+
+    fn variance(x: &Array2<f64>) -> f64 {
+        let mean = x.mean().unwrap();
+        x.mapv(|v| (v - mean).powi(2)).mean().unwrap()
+    }
+
+    #[test]
+    fn persistence_averages_noise() {
+        let options = PersistenceOptions {
+            persistence: 0.8,
+            ..Default::default()
+        };
+        let mut persistence = Persistence::new(options);
+        let mut rng = StdRng::seed_from_u64(33);
+        let mut out = Array2::zeros((1, 1));
+        for _ in 0..50 {
+            out = persistence.update(noise(&mut rng, 32, 256));
+        }
+        // steady-state IIR variance ratio (1 - a) / (1 + a) = 0.11
+        let ratio = variance(&out) / variance(&noise(&mut rng, 32, 256));
+        assert!(ratio < 0.2);
+
+        persistence.reset();
+        let frame = noise(&mut rng, 32, 256);
+        assert_eq!(persistence.update(frame.clone()), frame);
+    }
+
+    #[test]
+    fn motion_compensation_keeps_moving_target_sharp() {
+        let base = noise(&mut StdRng::seed_from_u64(34), 48, 512);
+        let frames: Vec<Array2<f64>> = (0..6).map(|k| shift_frame(&base, &base, k, 0)).collect();
+
+        let run = |motion_compensation| {
+            let mut persistence = Persistence::new(PersistenceOptions {
+                persistence: 0.7,
+                motion_compensation,
+                max_shift: 2,
+            });
+            let mut out = Array2::zeros((1, 1));
+            for frame in &frames {
+                out = persistence.update(frame.clone());
+            }
+            let last = frames.last().unwrap();
+            (&out - last).mapv(f64::abs).mean().unwrap()
+        };
+
+        assert_eq!(estimate_shift(&frames[0], &frames[1], 2), (1, 0));
+        assert!(run(true) < 0.5 * run(false));
+    }
+}
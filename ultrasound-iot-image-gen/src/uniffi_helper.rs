@@ -1,5 +1,8 @@
 use ndarray::Array3;
 
+use crate::ImageError;
+use crate::real::Real;
+
 #[derive(Debug, uniffi::Record)]
 pub struct Array3Shape {
     pub d0: u32,
@@ -29,24 +32,45 @@ impl Array3Shape {
     }
 }
 
+/// Array samples in the precision the client chose.
+#[derive(Debug, Clone, PartialEq, uniffi::Enum)]
+pub enum Samples {
+    Single { values: Vec<f32> },
+    Double { values: Vec<f64> },
+}
+
+impl Samples {
+    pub fn len(&self) -> usize {
+        match self {
+            Samples::Single { values } => values.len(),
+            Samples::Double { values } => values.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Row-major 3-d array, carried over FFI in single or double precision.
 #[derive(Debug, uniffi::Record)]
 pub struct Array3Data {
     pub shape: Array3Shape,
-    pub data: Vec<f64>,
+    pub data: Samples,
 }
 
 impl Array3Data {
-    pub fn from_array(arr: Array3<f64>) -> Self {
+    pub fn from_array<T: Real>(arr: Array3<T>) -> Self {
         let shape = arr.dim();
-        let data = arr.into_raw_vec();
         Self {
             shape: Array3Shape::from_usize(shape),
-            data,
+            data: T::into_samples(arr.into_raw_vec()),
         }
     }
 
-    pub fn into_array(self) -> Array3<f64> {
-        Array3::from_shape_vec(self.shape.stride(), self.data)
-            .expect("Data length does not match the provided shape")
+    /// Convert to `T`, without copying when the samples are already in that precision.
+    pub fn into_array<T: Real>(self) -> Result<Array3<T>, ImageError> {
+        Array3::from_shape_vec(self.shape.stride(), T::from_samples(self.data))
+            .map_err(|e| ImageError::InvalidData(e.to_string()))
     }
 }
@@ -0,0 +1,441 @@
+use image::{DynamicImage, GrayImage, Luma};
+use ndarray::{Array3, ArrayView2, Axis, s};
+
+use crate::display::DisplayParameters;
+use crate::measurement::{Calibration, PixelPoint};
+use crate::processing::encode_png;
+use crate::uniffi_helper::Array3Shape;
+use crate::{ImageError, UltrasoundImage};
+
+/// Largest working memory `Volume::reconstruct` allocates, in bytes.
+const MAX_VOLUME_BYTES: usize = 1 << 30;
+
+/// Bytes per voxel held at once while reconstructing: the binning sum and
+/// count, the binned mean and the quantized output.
+const BYTES_PER_VOXEL: usize = 2 * size_of::<u32>() + size_of::<Option<f64>>() + size_of::<u8>();
+
+/// Position of the transducer centre (millimetres) and orientation of the image
+/// plane (radians). With all angles zero the image columns run along x and the
+/// rows (depth) along z; the rotation is applied as yaw about z, then pitch about
+/// y, then roll about x.
+#[derive(Debug, Clone, Copy, PartialEq, Default, uniffi::Record)]
+pub struct Pose {
+    pub x_mm: f64,
+    pub y_mm: f64,
+    pub z_mm: f64,
+    pub roll: f64,
+    pub pitch: f64,
+    pub yaw: f64,
+}
+
+impl Pose {
+    /// Volume coordinates of the image-plane point (`lateral`, `depth`).
+    fn transform(&self, lateral: f64, depth: f64) -> [f64; 3] {
+        let (sr, cr) = self.roll.sin_cos();
+        let (sp, cp) = self.pitch.sin_cos();
+        let (sy, cy) = self.yaw.sin_cos();
+        // first and third columns of Rz(yaw) Ry(pitch) Rx(roll)
+        let u = [cy * cp, sy * cp, -sp];
+        let w = [cy * sp * cr + sy * sr, sy * sp * cr - cy * sr, cp * cr];
+        let t = [self.x_mm, self.y_mm, self.z_mm];
+        [0, 1, 2].map(|i| t[i] + u[i] * lateral + w[i] * depth)
+    }
+
+    fn is_finite(&self) -> bool {
+        [
+            self.x_mm, self.y_mm, self.z_mm, self.roll, self.pitch, self.yaw,
+        ]
+        .iter()
+        .all(|v| v.is_finite())
+    }
+}
+
+/// A calibrated B-mode frame and where it was acquired.
+#[derive(Debug, uniffi::Record)]
+pub struct PosedFrame {
+    pub image: UltrasoundImage,
+    pub pose: Pose,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, uniffi::Record)]
+pub struct VolumeOptions {
+    /// Edge length of the cubic voxels in millimetres.
+    pub voxel_size_mm: f64,
+    /// Largest gap, in voxels, bridged by hole filling. 0 leaves holes empty.
+    pub fill_radius: u32,
+}
+
+impl Default for VolumeOptions {
+    fn default() -> Self {
+        Self {
+            voxel_size_mm: 0.5,
+            fill_radius: 2,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, uniffi::Enum)]
+pub enum SliceAxis {
+    /// Lateral: slices show (y, z).
+    X,
+    /// Elevation, the usual sweep direction: slices show (x, z) like a B-mode frame.
+    Y,
+    /// Depth: slices are C-planes showing (x, y).
+    Z,
+}
+
+impl SliceAxis {
+    fn index(self) -> usize {
+        match self {
+            SliceAxis::X => 0,
+            SliceAxis::Y => 1,
+            SliceAxis::Z => 2,
+        }
+    }
+}
+
+/// Gray-level voxel volume indexed (x, y, z). Voxels no frame reached are 0.
+#[derive(Debug, uniffi::Object)]
+pub struct Volume {
+    voxels: Array3<u8>,
+    origin_mm: [f64; 3],
+    voxel_size_mm: f64,
+    display: DisplayParameters,
+}
+
+#[uniffi::export]
+impl Volume {
+    /// Pixel-nearest-neighbour reconstruction: every pixel is binned into the
+    /// nearest voxel, voxels hit several times keep the mean, and empty voxels
+    /// take the mean of filled voxels in the smallest neighbourhood (up to
+    /// `fill_radius`) that contains any.
+    #[uniffi::constructor]
+    pub fn reconstruct(
+        frames: Vec<PosedFrame>,
+        options: VolumeOptions,
+    ) -> Result<Self, ImageError> {
+        if !options.voxel_size_mm.is_finite() || options.voxel_size_mm <= 0.0 {
+            return Err(ImageError::InvalidData(
+                "Voxel size must be positive".to_owned(),
+            ));
+        }
+        if frames.is_empty() {
+            return Err(ImageError::InvalidData(
+                "Volume reconstruction needs at least one frame".to_owned(),
+            ));
+        }
+
+        let mut planes = Vec::with_capacity(frames.len());
+        for frame in &frames {
+            let calibration = frame.image.calibration.ok_or_else(|| {
+                ImageError::InvalidData("Volume reconstruction needs calibrated frames".to_owned())
+            })?;
+            let Calibration {
+                x_origin_mm,
+                z_origin_mm,
+                pixel_width_mm,
+                pixel_height_mm,
+            } = calibration;
+            let calibrated = [x_origin_mm, z_origin_mm, pixel_width_mm, pixel_height_mm];
+            if !frame.pose.is_finite() || !calibrated.iter().all(|v| v.is_finite()) {
+                return Err(ImageError::InvalidData(
+                    "Frame pose and calibration must be finite".to_owned(),
+                ));
+            }
+            let pixels = image::load_from_memory(&frame.image.data)
+                .map_err(|e| ImageError::InvalidData(e.to_string()))?
+                .to_luma8();
+            planes.push((pixels, calibration, frame.pose));
+        }
+
+        // bounding box of the frame corners; the frames are planar so these suffice
+        let mut lo = [f64::INFINITY; 3];
+        let mut hi = [f64::NEG_INFINITY; 3];
+        for (pixels, calibration, pose) in &planes {
+            let (w, h) = pixels.dimensions();
+            for (c, r) in [(0, 0), (w - 1, 0), (0, h - 1), (w - 1, h - 1)] {
+                let (lateral, depth) = pixel_mm(calibration, c, r);
+                let p = pose.transform(lateral, depth);
+                for i in 0..3 {
+                    lo[i] = lo[i].min(p[i]);
+                    hi[i] = hi[i].max(p[i]);
+                }
+            }
+        }
+        let voxel = options.voxel_size_mm;
+        // sized in f64 first, where a huge extent cannot overflow the cast
+        let extent = [0, 1, 2].map(|i| ((hi[i] - lo[i]) / voxel).round() + 1.0);
+        let bytes = extent.iter().product::<f64>() * BYTES_PER_VOXEL as f64;
+        if !bytes.is_finite() || bytes > MAX_VOLUME_BYTES as f64 {
+            return Err(ImageError::InvalidData(format!(
+                "Volume of {extent:?} voxels is too large, increase the voxel size"
+            )));
+        }
+        let dim = extent.map(|n| n as usize);
+
+        // pixel-nearest-neighbour binning
+        let mut sum = Array3::<u32>::zeros(dim);
+        let mut count = Array3::<u32>::zeros(dim);
+        for (pixels, calibration, pose) in &planes {
+            for (c, r, value) in pixels.enumerate_pixels() {
+                let (lateral, depth) = pixel_mm(calibration, c, r);
+                let p = pose.transform(lateral, depth);
+                let index = [0, 1, 2].map(|i| ((p[i] - lo[i]) / voxel).round() as usize);
+                sum[index] += value[0] as u32;
+                count[index] += 1;
+            }
+        }
+        let binned = Array3::from_shape_fn(dim, |index| {
+            (count[index] > 0).then(|| sum[index] as f64 / count[index] as f64)
+        });
+
+        let voxels = fill_holes(&binned, options.fill_radius as usize);
+
+        Ok(Self {
+            voxels,
+            origin_mm: lo,
+            voxel_size_mm: voxel,
+            display: frames[0].image.display.clone(),
+        })
+    }
+
+    /// Number of voxels along x, y and z.
+    pub fn dimensions(&self) -> Array3Shape {
+        Array3Shape::from_usize(self.voxels.dim())
+    }
+
+    /// Centre of voxel (0, 0, 0) in millimetres.
+    pub fn origin_mm(&self) -> Vec<f64> {
+        self.origin_mm.to_vec()
+    }
+
+    pub fn voxel_size_mm(&self) -> f64 {
+        self.voxel_size_mm
+    }
+
+    /// Plane `index` perpendicular to `axis`.
+    pub fn slice(&self, axis: SliceAxis, index: u32) -> Result<UltrasoundImage, ImageError> {
+        let len = self.voxels.len_of(Axis(axis.index()));
+        if index as usize >= len {
+            return Err(ImageError::InvalidData(format!(
+                "Slice {index} is outside the {len} planes along {axis:?}"
+            )));
+        }
+        let plane = self.voxels.index_axis(Axis(axis.index()), index as usize);
+        self.plane_image(axis, plane)
+    }
+
+    /// Maximum-intensity projection along `axis`.
+    pub fn maximum_intensity_projection(
+        &self,
+        axis: SliceAxis,
+    ) -> Result<UltrasoundImage, ImageError> {
+        let mip = self
+            .voxels
+            .fold_axis(Axis(axis.index()), 0, |&max, &v| max.max(v));
+        self.plane_image(axis, mip.view())
+    }
+}
+
+impl Volume {
+    /// Encode a plane whose first axis runs across and second axis down the image.
+    fn plane_image(
+        &self,
+        axis: SliceAxis,
+        plane: ArrayView2<u8>,
+    ) -> Result<UltrasoundImage, ImageError> {
+        let (w, h) = plane.dim();
+        let img = GrayImage::from_fn(w as u32, h as u32, |c, r| {
+            Luma([plane[[c as usize, r as usize]]])
+        });
+
+        // the two remaining axes, in order, are the image columns and rows
+        let [across, down] = match axis {
+            SliceAxis::X => [1, 2],
+            SliceAxis::Y => [0, 2],
+            SliceAxis::Z => [0, 1],
+        };
+        let calibration = Calibration {
+            x_origin_mm: self.origin_mm[across],
+            z_origin_mm: self.origin_mm[down],
+            pixel_width_mm: self.voxel_size_mm,
+            pixel_height_mm: self.voxel_size_mm,
+        };
+
+        encode_png(
+            DynamicImage::ImageLuma8(img),
+            Some(calibration),
+            self.display.clone(),
+        )
+    }
+}
+
+fn pixel_mm(calibration: &Calibration, column: u32, row: u32) -> (f64, f64) {
+    calibration.to_mm(PixelPoint {
+        x: column as f64,
+        y: row as f64,
+    })
+}
+
+/// Quantize binned voxels, filling each empty one with the mean of the filled
+/// voxels within the smallest cube of half width 1..=`radius` containing any.
+fn fill_holes(binned: &Array3<Option<f64>>, radius: usize) -> Array3<u8> {
+    let (nx, ny, nz) = binned.dim();
+    Array3::from_shape_fn(binned.raw_dim(), |(x, y, z)| {
+        let value = binned[[x, y, z]].or_else(|| {
+            (1..=radius).find_map(|r| {
+                let (mut sum, mut n) = (0.0, 0);
+                let window = binned.slice(s![
+                    x.saturating_sub(r)..(x + r + 1).min(nx),
+                    y.saturating_sub(r)..(y + r + 1).min(ny),
+                    z.saturating_sub(r)..(z + r + 1).min(nz)
+                ]);
+                for v in window.iter().flatten() {
+                    sum += v;
+                    n += 1;
+                }
+                (n > 0).then(|| sum / n as f64)
+            })
+        });
+        value.unwrap_or(0.0).round() as u8
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Frame of `w` x `h` pixels of 0.5 mm, centred laterally.
+    fn frame(w: u32, h: u32, pose: Pose, f: impl Fn(u32, u32) -> u8) -> PosedFrame {
+        let calibration = Calibration {
+            x_origin_mm: -0.25 * (w - 1) as f64,
+            z_origin_mm: 0.0,
+            pixel_width_mm: 0.5,
+            pixel_height_mm: 0.5,
+        };
+        let img = GrayImage::from_fn(w, h, |c, r| Luma([f(c, r)]));
+        let image = encode_png(
+            DynamicImage::ImageLuma8(img),
+            Some(calibration),
+            DisplayParameters::fixed(Vec::new()),
+        )
+        .unwrap();
+        PosedFrame { image, pose }
+    }
+
+    fn decode(image: &UltrasoundImage) -> GrayImage {
+        image::load_from_memory(&image.data).unwrap().to_luma8()
+    }
+
+    #[test]
+    fn linear_sweep_fills_gaps_between_frames() {
+        // frames 1 mm apart, voxels of 0.5 mm: every other y plane is a hole
+        let pattern = |c: u32, r: u32| (10 * c + 5 * r) as u8;
+        let frames = (0..5)
+            .map(|k| {
+                let pose = Pose {
+                    y_mm: k as f64,
+                    ..Default::default()
+                };
+                frame(9, 12, pose, pattern)
+            })
+            .collect();
+        let volume = Volume::reconstruct(frames, VolumeOptions::default()).unwrap();
+
+        let dim = volume.dimensions();
+        assert_eq!((dim.d0, dim.d1, dim.d2), (9, 9, 12));
+
+        for index in [0, 4, 8] {
+            let plane = decode(&volume.slice(SliceAxis::Y, index).unwrap());
+            assert_eq!(plane.dimensions(), (9, 12));
+            assert!(
+                plane
+                    .enumerate_pixels()
+                    .all(|(c, r, v)| v[0] == pattern(c, r))
+            );
+        }
+        // a hole plane is the mean of its neighbourhood, exact away from the edges
+        let hole = decode(&volume.slice(SliceAxis::Y, 3).unwrap());
+        assert!(
+            hole.enumerate_pixels()
+                .filter(|&(c, r, _)| (1..8).contains(&c) && (1..11).contains(&r))
+                .all(|(c, r, v)| v[0] == pattern(c, r))
+        );
+        assert!(volume.slice(SliceAxis::Y, 9).is_err());
+
+        let calibration = volume.slice(SliceAxis::X, 0).unwrap().calibration.unwrap();
+        assert_eq!(calibration.pixel_width_mm, 0.5);
+        assert_eq!(calibration.x_origin_mm, 0.0);
+    }
+
+    #[test]
+    fn oversized_volume_is_rejected_before_allocating() {
+        // 9 x 2e6 x 12 voxels: few enough voxels for a u8 volume, but gigabytes to bin
+        let frames = [0.0, 1e6]
+            .map(|y_mm| {
+                frame(
+                    9,
+                    12,
+                    Pose {
+                        y_mm,
+                        ..Default::default()
+                    },
+                    |_, _| 0,
+                )
+            })
+            .into();
+        assert!(Volume::reconstruct(frames, VolumeOptions::default()).is_err());
+    }
+
+    #[test]
+    fn non_finite_poses_are_rejected() {
+        for pose in [
+            Pose {
+                x_mm: f64::INFINITY,
+                ..Default::default()
+            },
+            Pose {
+                yaw: f64::NAN,
+                ..Default::default()
+            },
+            Pose {
+                y_mm: 1e300,
+                ..Default::default()
+            },
+        ] {
+            let frames = vec![
+                frame(9, 12, Pose::default(), |_, _| 0),
+                frame(9, 12, pose, |_, _| 0),
+            ];
+            assert!(Volume::reconstruct(frames, VolumeOptions::default()).is_err());
+        }
+    }
+
+    #[test]
+    fn rotated_frame_projects_onto_elevation() {
+        // turned by 90° about z, the image columns run along y
+        let pose = Pose {
+            yaw: std::f64::consts::FRAC_PI_2,
+            ..Default::default()
+        };
+        let bright = |c: u32, r: u32| if (c, r) == (6, 3) { 255 } else { 20 };
+        let options = VolumeOptions {
+            fill_radius: 0,
+            ..Default::default()
+        };
+        let volume = Volume::reconstruct(vec![frame(9, 8, pose, bright)], options).unwrap();
+
+        let dim = volume.dimensions();
+        assert_eq!((dim.d0, dim.d1, dim.d2), (1, 9, 8));
+
+        let mip = decode(&volume.maximum_intensity_projection(SliceAxis::X).unwrap());
+        assert_eq!(mip.dimensions(), (9, 8));
+        assert_eq!(mip.get_pixel(6, 3)[0], 255);
+        assert_eq!(mip.get_pixel(2, 3)[0], 20);
+
+        // depth projection: the brightest sample of each column
+        let c_plane = decode(&volume.maximum_intensity_projection(SliceAxis::Z).unwrap());
+        assert_eq!(c_plane.dimensions(), (1, 9));
+        assert_eq!(c_plane.get_pixel(0, 6)[0], 255);
+    }
+}
@@ -0,0 +1,73 @@
+use actix::Message;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum CommandKind {
+    StartSession { session: Option<String> },
+    StopSession,
+    SetSamplingInterval { interval_ms: u32 },
+    Reboot,
+}
+
+/// Command for a device, relayed to it by the broker.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Command {
+    pub id: Uuid,
+    pub device: Uuid,
+    #[serde(flatten)]
+    pub kind: CommandKind,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum CommandStatus {
+    /// Waiting for the broker to pick it up.
+    Queued,
+    /// Handed out to the broker.
+    Dispatched,
+    Sent {
+        attempts: u32,
+    },
+    Acknowledged,
+    Rejected {
+        reason: String,
+    },
+    Expired,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CommandRecord {
+    #[serde(flatten)]
+    pub command: Command,
+    #[serde(flatten)]
+    pub status: CommandStatus,
+}
+
+#[derive(Message)]
+#[rtype(result = "Command")]
+pub struct Enqueue {
+    pub device: Uuid,
+    pub kind: CommandKind,
+}
+
+/// Commands not yet handed out to the broker.
+#[derive(Message)]
+#[rtype(result = "Vec<Command>")]
+pub struct TakePending;
+
+/// Status reported by the broker; the result is false for an unknown command.
+#[derive(Message, Deserialize)]
+#[rtype(result = "bool")]
+pub struct UpdateStatus {
+    pub id: Uuid,
+    #[serde(flatten)]
+    pub status: CommandStatus,
+}
+
+#[derive(Message)]
+#[rtype(result = "Option<CommandRecord>")]
+pub struct GetCommand {
+    pub id: Uuid,
+}
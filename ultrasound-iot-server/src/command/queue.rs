@@ -0,0 +1,77 @@
+use std::collections::{HashMap, VecDeque};
+
+use actix::{Actor, Context, Handler, MessageResult};
+use uuid::Uuid;
+
+use super::message::*;
+
+/// Store commands for devices until the broker picks them up, and their status
+#[derive(Default)]
+pub struct CommandQueue {
+    commands: HashMap<Uuid, CommandRecord>,
+    pending: VecDeque<Uuid>,
+}
+
+impl Actor for CommandQueue {
+    type Context = Context<Self>;
+}
+
+impl Handler<Enqueue> for CommandQueue {
+    type Result = MessageResult<Enqueue>;
+
+    fn handle(&mut self, msg: Enqueue, _ctx: &mut Context<Self>) -> Self::Result {
+        let command = Command {
+            id: Uuid::new_v4(),
+            device: msg.device,
+            kind: msg.kind,
+        };
+        println!("Queued command {:?}", command);
+        self.pending.push_back(command.id);
+        self.commands.insert(
+            command.id,
+            CommandRecord {
+                command: command.clone(),
+                status: CommandStatus::Queued,
+            },
+        );
+        MessageResult(command)
+    }
+}
+
+impl Handler<TakePending> for CommandQueue {
+    type Result = MessageResult<TakePending>;
+
+    fn handle(&mut self, _msg: TakePending, _ctx: &mut Context<Self>) -> Self::Result {
+        let mut commands = Vec::new();
+        for id in self.pending.drain(..) {
+            if let Some(record) = self.commands.get_mut(&id) {
+                record.status = CommandStatus::Dispatched;
+                commands.push(record.command.clone());
+            }
+        }
+        MessageResult(commands)
+    }
+}
+
+impl Handler<UpdateStatus> for CommandQueue {
+    type Result = bool;
+
+    fn handle(&mut self, msg: UpdateStatus, _ctx: &mut Context<Self>) -> bool {
+        match self.commands.get_mut(&msg.id) {
+            Some(record) => {
+                println!("Command {}: {:?}", msg.id, msg.status);
+                record.status = msg.status;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl Handler<GetCommand> for CommandQueue {
+    type Result = Option<CommandRecord>;
+
+    fn handle(&mut self, msg: GetCommand, _ctx: &mut Context<Self>) -> Self::Result {
+        self.commands.get(&msg.id).cloned()
+    }
+}
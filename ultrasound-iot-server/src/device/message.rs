@@ -0,0 +1,33 @@
+use actix::Message;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeviceState {
+    Online,
+    Offline,
+}
+
+/// Presence and health of a device, as reported by the broker.
+#[derive(Message, Debug, Clone, Deserialize, Serialize)]
+#[rtype(result = "()")]
+pub struct DevicePresence {
+    pub device: Uuid,
+    pub state: DeviceState,
+    pub last_seen_ms: u64,
+    pub battery: Option<f32>,
+    pub rssi: Option<i32>,
+    pub firmware: Option<String>,
+    pub uptime_secs: Option<u64>,
+}
+
+#[derive(Message)]
+#[rtype(result = "Vec<DevicePresence>")]
+pub struct ListDevices;
+
+#[derive(Message)]
+#[rtype(result = "Option<DevicePresence>")]
+pub struct GetDevice {
+    pub device: Uuid,
+}
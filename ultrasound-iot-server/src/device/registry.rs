@@ -0,0 +1,41 @@
+use std::collections::HashMap;
+
+use actix::{Actor, Context, Handler, MessageResult};
+use uuid::Uuid;
+
+use super::message::*;
+
+/// Store the latest presence of each device reported by the broker
+#[derive(Default)]
+pub struct DeviceRegistry {
+    devices: HashMap<Uuid, DevicePresence>,
+}
+
+impl Actor for DeviceRegistry {
+    type Context = Context<Self>;
+}
+
+impl Handler<DevicePresence> for DeviceRegistry {
+    type Result = ();
+
+    fn handle(&mut self, msg: DevicePresence, _ctx: &mut Context<Self>) {
+        println!("Device {} is {:?}", msg.device, msg.state);
+        self.devices.insert(msg.device, msg);
+    }
+}
+
+impl Handler<ListDevices> for DeviceRegistry {
+    type Result = MessageResult<ListDevices>;
+
+    fn handle(&mut self, _msg: ListDevices, _ctx: &mut Context<Self>) -> Self::Result {
+        MessageResult(self.devices.values().cloned().collect())
+    }
+}
+
+impl Handler<GetDevice> for DeviceRegistry {
+    type Result = Option<DevicePresence>;
+
+    fn handle(&mut self, msg: GetDevice, _ctx: &mut Context<Self>) -> Self::Result {
+        self.devices.get(&msg.device).cloned()
+    }
+}
@@ -0,0 +1,135 @@
+use actix_web::dev::Payload;
+use actix_web::http::header::CONTENT_TYPE;
+use actix_web::web::BytesMut;
+use actix_web::{Error, FromRequest, HttpRequest, error};
+use futures::future::LocalBoxFuture;
+use futures::{FutureExt, StreamExt};
+use serde::de::DeserializeOwned;
+
+/// Body size limit of the [`Encoded`] extractor, registered with `App::app_data`.
+#[derive(Debug, Clone, Copy)]
+pub struct EncodedConfig {
+    pub limit: usize,
+}
+
+impl Default for EncodedConfig {
+    /// The 2 MB limit of the `Json` extractor `Encoded` replaced.
+    fn default() -> Self {
+        Self {
+            limit: 2 * 1024 * 1024,
+        }
+    }
+}
+
+/// Request body decoded according to its Content-Type: JSON (also when no
+/// Content-Type is given), CBOR or MessagePack, as posted by the broker.
+pub struct Encoded<T>(pub T);
+
+impl<T> Encoded<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+fn decode<T: DeserializeOwned>(content_type: &str, body: &[u8]) -> Result<T, Error> {
+    let media_type = content_type.split(';').next().unwrap_or_default().trim();
+    match media_type.to_ascii_lowercase().as_str() {
+        "" | "application/json" => serde_json::from_slice(body).map_err(error::ErrorBadRequest),
+        "application/cbor" => ciborium::from_reader(body).map_err(error::ErrorBadRequest),
+        "application/msgpack" | "application/x-msgpack" | "application/vnd.msgpack" => {
+            rmp_serde::from_slice(body).map_err(error::ErrorBadRequest)
+        }
+        _ => Err(error::ErrorUnsupportedMediaType(format!(
+            "Unsupported content type {:?}",
+            content_type
+        ))),
+    }
+}
+
+impl<T: DeserializeOwned + 'static> FromRequest for Encoded<T> {
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let content_type = req
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default()
+            .to_owned();
+        let limit = req
+            .app_data::<EncodedConfig>()
+            .copied()
+            .unwrap_or_default()
+            .limit;
+        let mut payload = payload.take();
+        async move {
+            let mut body = BytesMut::new();
+            while let Some(chunk) = payload.next().await {
+                let chunk = chunk?;
+                if body.len() + chunk.len() > limit {
+                    return Err(error::ErrorPayloadTooLarge(format!(
+                        "Request body is larger than {limit} bytes"
+                    )));
+                }
+                body.extend_from_slice(&chunk);
+            }
+            decode(&content_type, &body).map(Encoded)
+        }
+        .boxed_local()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::StatusCode;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Scan {
+        session: String,
+        values: Vec<f32>,
+    }
+
+    fn scan() -> Scan {
+        Scan {
+            session: "s-1".to_owned(),
+            values: vec![0.5, 1.5],
+        }
+    }
+
+    #[test]
+    fn decodes_each_content_type() {
+        let json = serde_json::to_vec(&scan()).unwrap();
+        let mut cbor = Vec::new();
+        ciborium::into_writer(&scan(), &mut cbor).unwrap();
+        let msgpack = rmp_serde::to_vec_named(&scan()).unwrap();
+
+        assert_eq!(decode::<Scan>("", &json).unwrap(), scan());
+        assert_eq!(
+            decode::<Scan>("application/json; charset=utf-8", &json).unwrap(),
+            scan()
+        );
+        assert_eq!(decode::<Scan>("application/cbor", &cbor).unwrap(), scan());
+        assert_eq!(
+            decode::<Scan>("application/msgpack", &msgpack).unwrap(),
+            scan()
+        );
+        assert_eq!(
+            decode::<Scan>("Application/X-MsgPack", &msgpack).unwrap(),
+            scan()
+        );
+
+        let status =
+            |result: Result<Scan, Error>| result.unwrap_err().as_response_error().status_code();
+        assert_eq!(
+            status(decode("application/cbor", &json)),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            status(decode("text/plain", &json)),
+            StatusCode::UNSUPPORTED_MEDIA_TYPE
+        );
+    }
+}
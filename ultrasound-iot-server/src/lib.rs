@@ -2,5 +2,8 @@ pub mod app_state;
 pub mod settings;
 pub mod utils;
 
+pub mod command;
+pub mod device;
+pub mod encoded;
 pub mod routes;
 pub mod session;
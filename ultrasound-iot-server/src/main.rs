@@ -9,6 +9,8 @@ use services::sea_orm::Database;
 
 use tracing_actix_web::TracingLogger;
 use ultrasound_iot_server::app_state::AppState;
+use ultrasound_iot_server::command::queue::CommandQueue;
+use ultrasound_iot_server::device::registry::DeviceRegistry;
 use ultrasound_iot_server::routes;
 use ultrasound_iot_server::session::lobby::Lobby;
 use ultrasound_iot_server::settings::Settings;
@@ -29,6 +31,9 @@ async fn main() -> std::io::Result<()> {
     let app_state = AppState { conn };
 
     let lobby_addr = Lobby::default().start();
+    let command_queue_addr = CommandQueue::default().start();
+    let device_registry_addr = DeviceRegistry::default().start();
+    let encoded_config = settings.server.encoded_config();
 
     HttpServer::new(move || {
         App::new()
@@ -36,6 +41,9 @@ async fn main() -> std::io::Result<()> {
             .wrap(TracingLogger::default())
             .app_data(Data::new(app_state.clone()))
             .app_data(Data::new(lobby_addr.clone()))
+            .app_data(Data::new(command_queue_addr.clone()))
+            .app_data(Data::new(device_registry_addr.clone()))
+            .app_data(encoded_config)
     })
     .bind(("127.0.0.1", 8080))?
     .run()
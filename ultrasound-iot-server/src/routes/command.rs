@@ -0,0 +1,73 @@
+use tracing::debug;
+use uuid::Uuid;
+
+use actix::Addr;
+use actix_web::Error;
+use actix_web::web::{Data, Json, Path};
+use actix_web::{HttpResponse, Responder, get, post};
+
+use crate::command::message::{CommandKind, Enqueue, GetCommand, TakePending, UpdateStatus};
+use crate::command::queue::CommandQueue;
+
+#[post("/device/{device}")]
+pub async fn enqueue(
+    queue: Data<Addr<CommandQueue>>,
+    device: Path<Uuid>,
+    payload: Json<CommandKind>,
+) -> Result<impl Responder, Error> {
+    let device = device.into_inner();
+    debug!("Queueing command for device {}", device);
+
+    let command = queue
+        .send(Enqueue {
+            device,
+            kind: payload.into_inner(),
+        })
+        .await
+        .map_err(|e| crate::utils::to_internal_error("Command queue", e))?;
+
+    Ok(HttpResponse::Created().json(command))
+}
+
+/// Polled by the broker, which relays the commands to the devices.
+#[get("/pending")]
+pub async fn pending(queue: Data<Addr<CommandQueue>>) -> Result<impl Responder, Error> {
+    let commands = queue
+        .send(TakePending)
+        .await
+        .map_err(|e| crate::utils::to_internal_error("Command queue", e))?;
+
+    Ok(HttpResponse::Ok().json(commands))
+}
+
+#[post("/status")]
+pub async fn update_status(
+    queue: Data<Addr<CommandQueue>>,
+    payload: Json<UpdateStatus>,
+) -> Result<impl Responder, Error> {
+    let known = queue
+        .send(payload.into_inner())
+        .await
+        .map_err(|e| crate::utils::to_internal_error("Command queue", e))?;
+
+    if known {
+        Ok(HttpResponse::Ok().body("Status updated"))
+    } else {
+        Ok(HttpResponse::NotFound().body("Unknown command"))
+    }
+}
+
+#[get("/{id}")]
+pub async fn get(queue: Data<Addr<CommandQueue>>, id: Path<Uuid>) -> Result<impl Responder, Error> {
+    let record = queue
+        .send(GetCommand {
+            id: id.into_inner(),
+        })
+        .await
+        .map_err(|e| crate::utils::to_internal_error("Command queue", e))?;
+
+    match record {
+        Some(record) => Ok(HttpResponse::Ok().json(record)),
+        None => Ok(HttpResponse::NotFound().body("Unknown command")),
+    }
+}
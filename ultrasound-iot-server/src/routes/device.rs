@@ -0,0 +1,51 @@
+use uuid::Uuid;
+
+use actix::Addr;
+use actix_web::Error;
+use actix_web::web::{Data, Json, Path};
+use actix_web::{HttpResponse, Responder, get, post};
+
+use crate::device::message::{DevicePresence, GetDevice, ListDevices};
+use crate::device::registry::DeviceRegistry;
+
+/// Reported by the broker whenever a device goes online or offline.
+#[post("/presence")]
+pub async fn presence(
+    registry: Data<Addr<DeviceRegistry>>,
+    payload: Json<DevicePresence>,
+) -> Result<impl Responder, Error> {
+    registry
+        .send(payload.into_inner())
+        .await
+        .map_err(|e| crate::utils::to_internal_error("Device registry", e))?;
+
+    Ok(HttpResponse::Ok().body("Presence updated"))
+}
+
+#[get("")]
+pub async fn list(registry: Data<Addr<DeviceRegistry>>) -> Result<impl Responder, Error> {
+    let devices = registry
+        .send(ListDevices)
+        .await
+        .map_err(|e| crate::utils::to_internal_error("Device registry", e))?;
+
+    Ok(HttpResponse::Ok().json(devices))
+}
+
+#[get("/{device}")]
+pub async fn get(
+    registry: Data<Addr<DeviceRegistry>>,
+    device: Path<Uuid>,
+) -> Result<impl Responder, Error> {
+    let device = registry
+        .send(GetDevice {
+            device: device.into_inner(),
+        })
+        .await
+        .map_err(|e| crate::utils::to_internal_error("Device registry", e))?;
+
+    match device {
+        Some(device) => Ok(HttpResponse::Ok().json(device)),
+        None => Ok(HttpResponse::NotFound().body("Unknown device")),
+    }
+}
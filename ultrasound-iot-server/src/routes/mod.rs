@@ -1,3 +1,5 @@
+pub mod command;
+pub mod device;
 pub mod scan;
 pub mod session;
 
@@ -10,5 +12,18 @@ pub fn configure(cfg: &mut web::ServiceConfig) {
             .service(scan::receive)
             .service(scan::assign_patient),
     )
-    .service(web::scope("/session").service(session::ws_index));
+    .service(web::scope("/session").service(session::ws_index))
+    .service(
+        web::scope("/command")
+            .service(command::enqueue)
+            .service(command::pending)
+            .service(command::update_status)
+            .service(command::get),
+    )
+    .service(
+        web::scope("/device")
+            .service(device::presence)
+            .service(device::list)
+            .service(device::get),
+    );
 }
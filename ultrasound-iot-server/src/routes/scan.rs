@@ -12,6 +12,7 @@ use services::scans::{self, ScanId};
 use services::utils;
 
 use crate::app_state::AppState;
+use crate::encoded::Encoded;
 use crate::session::lobby::Lobby;
 use crate::session::message::ScanData;
 
@@ -58,7 +59,7 @@ pub async fn get(
 pub async fn receive(
     data: Data<AppState>,
     lobby: Data<Addr<Lobby>>,
-    payload: Json<ScanPayload>,
+    payload: Encoded<ScanPayload>,
 ) -> Result<impl Responder, Error> {
     let ScanPayload {
         device,
@@ -2,11 +2,15 @@ use config::{Config, ConfigError, File};
 use serde::Deserialize;
 
 pub mod db;
+pub mod server;
 use db::Database;
+use server::Server;
 
 #[derive(Debug, Deserialize)]
 pub struct Settings {
     pub database: Database,
+    #[serde(default)]
+    pub server: Server,
 }
 
 impl Settings {
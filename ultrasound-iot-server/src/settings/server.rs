@@ -0,0 +1,18 @@
+use serde::Deserialize;
+
+use crate::encoded::EncodedConfig;
+
+#[derive(Debug, Default, Deserialize)]
+pub struct Server {
+    /// Largest scan request body accepted, in bytes.
+    pub body_limit: Option<usize>,
+}
+
+impl Server {
+    pub fn encoded_config(&self) -> EncodedConfig {
+        match self.body_limit {
+            Some(limit) => EncodedConfig { limit },
+            None => EncodedConfig::default(),
+        }
+    }
+}